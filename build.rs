@@ -2,22 +2,45 @@
 //use async_graphql::{EmptyMutation, EmptySubscription, Schema as GraphqlSchema, SimpleObject, Context, Error as GraphqlError, Object};
 //include!("src/error.rs");
 //include!("src/lib.rs");
+#[path = "src/account.rs"]
+mod account;
+#[path = "src/admin.rs"]
+mod admin;
 #[path = "src/error.rs"]
 mod error;
+#[path = "src/events.rs"]
+mod events;
+#[path = "src/lockout.rs"]
+mod lockout;
+#[path = "src/mailer.rs"]
+mod mailer;
+#[path = "src/matchmaking.rs"]
+mod matchmaking;
 #[path = "src/model.rs"]
 mod model;
+#[path = "src/oauth.rs"]
+mod oauth;
+#[path = "src/oidc.rs"]
+mod oidc;
+#[path = "src/permission.rs"]
+mod permission;
+#[path = "src/rating.rs"]
+mod rating;
 #[path = "src/session.rs"]
 mod session;
+#[path = "src/token.rs"]
+mod token;
 #[path = "src/util.rs"]
 mod util;
+#[path = "src/validation.rs"]
+mod validation;
 
-use crate::model::{Mutation, Query, Schema};
-use async_graphql::EmptySubscription;
+use crate::model::{Mutation, Query, Schema, Subscription};
 use std::fs;
 
 fn main() {
     // Tell Cargo that if the given file changes, to rerun this build script.
-    let schema = Schema::build(Query, Mutation, EmptySubscription).finish();
+    let schema = Schema::build(Query, Mutation, Subscription).finish();
     fs::write("./schema.graphql", schema.sdl()).unwrap();
 
     println!("cargo:rerun-if-changed=src/lib.rs");
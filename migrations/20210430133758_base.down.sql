@@ -0,0 +1,3 @@
+DROP TABLE cards;
+DROP TABLE users;
+DROP TYPE userkind;
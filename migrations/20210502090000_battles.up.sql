@@ -0,0 +1,17 @@
+CREATE TYPE battlestate AS ENUM ('pending', 'active', 'finished', 'abandoned');
+
+CREATE TABLE battles (
+  id UUID PRIMARY KEY NOT NULL DEFAULT gen_random_uuid(),
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  state BATTLESTATE NOT NULL DEFAULT 'pending',
+  challenger_id UUID NOT NULL,
+  challenger_card_id UUID NOT NULL,
+  opponent_id UUID,
+  opponent_card_id UUID,
+  winner_card_id UUID,
+  started_at TIMESTAMPTZ,
+  finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX ON battles (challenger_card_id) WHERE state IN ('pending', 'active');
+CREATE INDEX ON battles (opponent_card_id) WHERE state IN ('pending', 'active');
@@ -0,0 +1,5 @@
+ALTER TABLE battles
+  DROP COLUMN challenger_rating_before,
+  DROP COLUMN challenger_rating_after,
+  DROP COLUMN opponent_rating_before,
+  DROP COLUMN opponent_rating_after;
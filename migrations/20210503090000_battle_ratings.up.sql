@@ -0,0 +1,5 @@
+ALTER TABLE battles
+  ADD COLUMN challenger_rating_before DOUBLE PRECISION,
+  ADD COLUMN challenger_rating_after DOUBLE PRECISION,
+  ADD COLUMN opponent_rating_before DOUBLE PRECISION,
+  ADD COLUMN opponent_rating_after DOUBLE PRECISION;
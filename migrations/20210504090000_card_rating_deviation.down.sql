@@ -0,0 +1,3 @@
+ALTER TABLE cards
+  DROP COLUMN rating_deviation,
+  DROP COLUMN rating_volatility;
@@ -0,0 +1,3 @@
+ALTER TABLE cards
+  ADD COLUMN rating_deviation DOUBLE PRECISION,
+  ADD COLUMN rating_volatility DOUBLE PRECISION;
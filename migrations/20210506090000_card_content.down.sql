@@ -0,0 +1,3 @@
+ALTER TABLE cards
+  DROP COLUMN title,
+  DROP COLUMN image_url;
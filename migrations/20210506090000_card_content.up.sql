@@ -0,0 +1,3 @@
+ALTER TABLE cards
+  ADD COLUMN title TEXT NOT NULL DEFAULT '',
+  ADD COLUMN image_url TEXT;
@@ -0,0 +1,3 @@
+DROP TABLE card_ownerships;
+DROP FUNCTION card_ownerships_append_only();
+DROP TYPE ownershipreason;
@@ -0,0 +1,22 @@
+CREATE TYPE ownershipreason AS ENUM ('mint', 'transfer', 'release', 'admin');
+
+CREATE TABLE card_ownerships (
+  id UUID PRIMARY KEY NOT NULL DEFAULT gen_random_uuid(),
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  card_id UUID NOT NULL,
+  previous_owner_id UUID,
+  new_owner_id UUID,
+  reason OWNERSHIPREASON NOT NULL
+);
+
+CREATE INDEX ON card_ownerships (card_id, created_at, id);
+
+CREATE FUNCTION card_ownerships_append_only() RETURNS TRIGGER AS $$
+BEGIN
+  RAISE EXCEPTION 'card_ownerships is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER card_ownerships_append_only
+  BEFORE UPDATE OR DELETE ON card_ownerships
+  FOR EACH ROW EXECUTE FUNCTION card_ownerships_append_only();
@@ -0,0 +1,11 @@
+CREATE TABLE oauth_clients (
+  id UUID PRIMARY KEY NOT NULL DEFAULT gen_random_uuid(),
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  secret TEXT NOT NULL,
+  name TEXT NOT NULL,
+  owner_id UUID NOT NULL,
+  redirect_uris TEXT[] NOT NULL,
+  scopes TEXT[] NOT NULL
+);
+
+CREATE INDEX ON oauth_clients (owner_id);
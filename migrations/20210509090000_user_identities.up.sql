@@ -0,0 +1,11 @@
+CREATE TABLE user_identities (
+  id UUID PRIMARY KEY NOT NULL DEFAULT gen_random_uuid(),
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  user_id UUID NOT NULL,
+  provider TEXT NOT NULL,
+  subject TEXT NOT NULL,
+  email TEXT
+);
+
+CREATE UNIQUE INDEX ON user_identities (provider, subject);
+CREATE INDEX ON user_identities (user_id);
@@ -0,0 +1,5 @@
+DROP TRIGGER user_roles_default ON users;
+DROP FUNCTION user_roles_default();
+DROP TABLE user_roles;
+DROP TABLE role_permissions;
+DROP TABLE roles;
@@ -0,0 +1,52 @@
+CREATE TABLE roles (
+  name TEXT PRIMARY KEY NOT NULL,
+  description TEXT NOT NULL
+);
+
+CREATE TABLE role_permissions (
+  role TEXT NOT NULL,
+  permission TEXT NOT NULL,
+  PRIMARY KEY (role, permission)
+);
+
+CREATE TABLE user_roles (
+  user_id UUID NOT NULL,
+  role TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (user_id, role)
+);
+
+INSERT INTO roles (name, description) VALUES
+  ('admin', 'Everything, including managing roles'),
+  ('moderator', 'Looks after players and battles'),
+  ('player', 'Owns cards and battles with them'),
+  ('banned', 'May log in, but not play');
+
+INSERT INTO role_permissions (role, permission) VALUES
+  ('admin', 'play'),
+  ('admin', 'profile:read-any'),
+  ('admin', 'cards:manage'),
+  ('admin', 'battles:manage'),
+  ('admin', 'lockout:read'),
+  ('admin', 'roles:manage'),
+  ('admin', 'errors:detail'),
+  ('moderator', 'play'),
+  ('moderator', 'profile:read-any'),
+  ('moderator', 'battles:manage'),
+  ('moderator', 'lockout:read'),
+  ('player', 'play');
+
+INSERT INTO user_roles (user_id, role)
+  SELECT id, CASE kind WHEN 'super' THEN 'admin' ELSE 'player' END FROM users;
+
+CREATE FUNCTION user_roles_default() RETURNS TRIGGER AS $$
+BEGIN
+  INSERT INTO user_roles (user_id, role)
+    VALUES (NEW.id, CASE NEW.kind WHEN 'super' THEN 'admin' ELSE 'player' END);
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER user_roles_default
+  AFTER INSERT ON users
+  FOR EACH ROW EXECUTE PROCEDURE user_roles_default();
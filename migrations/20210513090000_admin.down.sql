@@ -0,0 +1,3 @@
+DROP TABLE card_rating_adjustments;
+DROP INDEX users_nickname_trgm_idx;
+DROP INDEX users_email_trgm_idx;
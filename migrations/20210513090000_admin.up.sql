@@ -0,0 +1,14 @@
+CREATE INDEX users_email_trgm_idx ON users USING GIN (email gin_trgm_ops);
+CREATE INDEX users_nickname_trgm_idx ON users USING GIN (nickname gin_trgm_ops);
+
+CREATE TABLE card_rating_adjustments (
+  id UUID PRIMARY KEY NOT NULL DEFAULT gen_random_uuid(),
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  card_id UUID NOT NULL,
+  adjusted_by UUID NOT NULL,
+  rating_before DOUBLE PRECISION NOT NULL,
+  rating_after DOUBLE PRECISION NOT NULL,
+  reason TEXT NOT NULL
+);
+
+CREATE INDEX ON card_rating_adjustments (card_id, created_at);
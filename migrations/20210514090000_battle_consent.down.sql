@@ -0,0 +1,5 @@
+ALTER TABLE battles
+  DROP COLUMN challenger_report,
+  DROP COLUMN opponent_report;
+
+DROP TYPE battleresult;
@@ -0,0 +1,5 @@
+CREATE TYPE battleresult AS ENUM ('challenger', 'opponent', 'draw');
+
+ALTER TABLE battles
+  ADD COLUMN challenger_report BATTLERESULT,
+  ADD COLUMN opponent_report BATTLERESULT;
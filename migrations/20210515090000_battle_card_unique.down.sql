@@ -0,0 +1,4 @@
+DROP INDEX battles_live_challenger_card_idx;
+DROP INDEX battles_live_opponent_card_idx;
+CREATE INDEX ON battles (challenger_card_id) WHERE state IN ('pending', 'active');
+CREATE INDEX ON battles (opponent_card_id) WHERE state IN ('pending', 'active');
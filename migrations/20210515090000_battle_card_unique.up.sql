@@ -0,0 +1,5 @@
+-- a card takes part in at most one live battle on each side
+DROP INDEX battles_challenger_card_id_idx;
+DROP INDEX battles_opponent_card_id_idx;
+CREATE UNIQUE INDEX battles_live_challenger_card_idx ON battles (challenger_card_id) WHERE state IN ('pending', 'active');
+CREATE UNIQUE INDEX battles_live_opponent_card_idx ON battles (opponent_card_id) WHERE state IN ('pending', 'active');
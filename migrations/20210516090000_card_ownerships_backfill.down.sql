@@ -0,0 +1 @@
+-- card_ownerships is append-only, and the backfilled rows are history like any other
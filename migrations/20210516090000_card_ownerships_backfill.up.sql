@@ -0,0 +1,5 @@
+-- cards owned before the history was recorded start it with a mint to their owner
+INSERT INTO card_ownerships (created_at, card_id, new_owner_id, reason)
+SELECT owned_at, id, owner_id, 'mint' FROM cards
+WHERE owner_id IS NOT NULL
+AND NOT EXISTS (SELECT 1 FROM card_ownerships WHERE card_ownerships.card_id = cards.id);
@@ -0,0 +1,122 @@
+use crate::error::Error;
+use crate::mailer::{deliver, Mail, SharedMailer};
+use crate::model::{DbPool, User};
+use crate::util::{hash_password, put_temporary, random_string, take_temporary};
+use deadpool_redis::ConnectionWrapper as RedisConn;
+use uuid::Uuid;
+
+const TOKEN_LENGTH: usize = 40;
+const EMAIL_VERIFICATION_LIFETIME_SECONDS: i64 = 60 * 60 * 24;
+const PASSWORD_RESET_LIFETIME_SECONDS: i64 = 60 * 60;
+
+fn email_verification_key(token: &str) -> String {
+    format!("email-verification/{}", token)
+}
+
+fn password_reset_key(token: &str) -> String {
+    format!("password-reset/{}", token)
+}
+
+/// Mail the user a token that proves they own their email address.
+pub async fn send_verification_email(
+    redis_conn: &mut RedisConn,
+    mailer: &SharedMailer,
+    user: &User,
+) -> Result<(), Error> {
+    let token = random_string(TOKEN_LENGTH);
+    put_temporary(
+        redis_conn,
+        &email_verification_key(&token),
+        &user.id,
+        EMAIL_VERIFICATION_LIFETIME_SECONDS,
+    )
+    .await?;
+    deliver(
+        mailer,
+        Mail {
+            to: user.email.clone(),
+            subject: "Verify your email".to_string(),
+            body: format!(
+                "Hi {},\n\nuse this code to verify your email. It expires in 24 hours.\n\n{}\n",
+                user.nickname, token
+            ),
+        },
+    )
+    .await;
+    Ok(())
+}
+
+/// Mark the email of the token's user as verified. Returns false for unknown or used tokens.
+pub async fn verify_email(dbpool: &DbPool, redis_conn: &mut RedisConn, token: &str) -> Result<bool, Error> {
+    let user_id: Uuid = match take_temporary(redis_conn, &email_verification_key(token)).await? {
+        Some(user_id) => user_id,
+        None => return Ok(false),
+    };
+    sqlx::query("UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW()) WHERE id = $1")
+        .bind(user_id)
+        .execute(dbpool)
+        .await?;
+    Ok(true)
+}
+
+/// Mail a reset token when the address belongs to an account. Says nothing either way, so
+/// the mutation can not be used to find out who is registered.
+pub async fn request_password_reset(
+    dbpool: &DbPool,
+    redis_conn: &mut RedisConn,
+    mailer: &SharedMailer,
+    email: &str,
+) -> Result<(), Error> {
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE lower(email) = lower($1)")
+        .bind(email)
+        .fetch_optional(dbpool)
+        .await?;
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+    let token = random_string(TOKEN_LENGTH);
+    put_temporary(
+        redis_conn,
+        &password_reset_key(&token),
+        &user.id,
+        PASSWORD_RESET_LIFETIME_SECONDS,
+    )
+    .await?;
+    let mail = Mail {
+        to: user.email.clone(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Hi {},\n\nuse this code to set a new password. It expires in an hour. If you did not ask for it, ignore this mail.\n\n{}\n",
+            user.nickname, token
+        ),
+    };
+    // in the background, or the time the mail server takes would tell registered
+    // addresses apart from unknown ones
+    let mailer = mailer.clone();
+    actix_rt::spawn(async move {
+        deliver(&mailer, mail).await;
+    });
+    Ok(())
+}
+
+/// Set a new password with a reset token. Returns the user it was for, or None for unknown
+/// or used tokens. Receiving the mail also proves the address, so it counts as verified.
+pub async fn reset_password(
+    dbpool: &DbPool,
+    redis_conn: &mut RedisConn,
+    token: &str,
+    new_password: String,
+    password_hash_cost: u32,
+) -> Result<Option<Uuid>, Error> {
+    let user_id: Uuid = match take_temporary(redis_conn, &password_reset_key(token)).await? {
+        Some(user_id) => user_id,
+        None => return Ok(None),
+    };
+    sqlx::query("UPDATE users SET password = $2, email_verified_at = COALESCE(email_verified_at, NOW()) WHERE id = $1")
+        .bind(user_id)
+        .bind(hash_password(new_password, password_hash_cost)?)
+        .execute(dbpool)
+        .await?;
+    Ok(Some(user_id))
+}
@@ -0,0 +1,234 @@
+use crate::error::Error;
+use crate::model::{Card, DbPool, User, UserKind};
+use crate::util::hash_password;
+use crate::validation::{self, FieldError, Validator};
+use async_graphql::SimpleObject;
+use chrono::Utc;
+use uuid::Uuid;
+
+type DateTime = chrono::DateTime<Utc>;
+
+/// A rating set by hand instead of by a battle.
+#[derive(sqlx::FromRow, Clone, Debug, PartialEq, SimpleObject)]
+pub struct RatingAdjustment {
+    pub id: Uuid,
+    pub card_id: Uuid,
+    /// The user who made the change.
+    pub adjusted_by: Uuid,
+    pub rating_before: f64,
+    pub rating_after: f64,
+    pub reason: String,
+    pub created_at: DateTime,
+}
+
+/// Escape `LIKE` wildcards so the query only matches literally.
+fn like_pattern(query: &str) -> String {
+    let escaped = query.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+/// Users whose email or nickname contains the query or resembles it, best match first.
+pub async fn search_users(dbpool: &DbPool, query: &str, limit: i64) -> Result<Vec<User>, Error> {
+    let query = query.trim();
+    Ok(sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE email ILIKE $1 OR nickname ILIKE $1 OR email % $2 OR nickname % $2 ORDER BY GREATEST(similarity(email, $2), similarity(nickname, $2)) DESC, id LIMIT $3",
+    )
+    .bind(like_pattern(query))
+    .bind(query)
+    .bind(limit)
+    .fetch_all(dbpool)
+    .await?)
+}
+
+/// Swap the player role for the banned one, or back. Returns false when there was nothing
+/// to change.
+pub async fn set_banned(dbpool: &DbPool, user_id: Uuid, banned: bool) -> Result<bool, Error> {
+    let (add, remove) = if banned { ("banned", "player") } else { ("player", "banned") };
+    let mut tx = dbpool.begin().await?;
+    let user_exists = sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM users WHERE id = $1)")
+        .bind(user_id)
+        .fetch_one(&mut tx)
+        .await?;
+    if !user_exists {
+        return Err(Error::BadRequest("banUser", "no such user"));
+    }
+    let added = sqlx::query("INSERT INTO user_roles (user_id, role) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+        .bind(user_id)
+        .bind(add)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    let removed = sqlx::query("DELETE FROM user_roles WHERE user_id = $1 AND role = $2")
+        .bind(user_id)
+        .bind(remove)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    tx.commit().await?;
+    Ok(added + removed > 0)
+}
+
+/// Change the kind of the user. Super users get the admin role with it. Normal users lose
+/// it, and become players unless banned.
+pub async fn set_kind(dbpool: &DbPool, user_id: Uuid, kind: UserKind) -> Result<User, Error> {
+    let mut tx = dbpool.begin().await?;
+    let user = sqlx::query_as::<_, User>("UPDATE users SET kind = $2 WHERE id = $1 RETURNING *")
+        .bind(user_id)
+        .bind(kind)
+        .fetch_one(&mut tx)
+        .await?;
+    let queries: &[&str] = match kind {
+        UserKind::Super => &["INSERT INTO user_roles (user_id, role) VALUES ($1, 'admin') ON CONFLICT DO NOTHING"],
+        UserKind::Normal => &[
+            "DELETE FROM user_roles WHERE user_id = $1 AND role = 'admin'",
+            "INSERT INTO user_roles (user_id, role) SELECT $1, 'player' WHERE NOT EXISTS (SELECT 1 FROM user_roles WHERE user_id = $1 AND role = 'banned') ON CONFLICT DO NOTHING",
+        ],
+    };
+    for query in queries {
+        sqlx::query(query).bind(user_id).execute(&mut tx).await?;
+    }
+    tx.commit().await?;
+    Ok(user)
+}
+
+/// Set the rating of the card and record who did it and why.
+pub async fn adjust_rating(
+    dbpool: &DbPool,
+    adjusted_by: Uuid,
+    card_id: Uuid,
+    rating: f64,
+    reason: &str,
+) -> Result<Card, Error> {
+    let mut tx = dbpool.begin().await?;
+    let rating_before = sqlx::query_scalar::<_, f64>("SELECT rating FROM cards WHERE id = $1 FOR UPDATE")
+        .bind(card_id)
+        .fetch_one(&mut tx)
+        .await?;
+    let card = sqlx::query_as::<_, Card>("UPDATE cards SET rating = $2 WHERE id = $1 RETURNING *")
+        .bind(card_id)
+        .bind(rating)
+        .fetch_one(&mut tx)
+        .await?;
+    sqlx::query("INSERT INTO card_rating_adjustments (card_id, adjusted_by, rating_before, rating_after, reason) VALUES ($1, $2, $3, $4, $5)")
+        .bind(card_id)
+        .bind(adjusted_by)
+        .bind(rating_before)
+        .bind(rating)
+        .bind(reason.trim())
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(card)
+}
+
+/// Manual rating changes of the card, most recent first.
+pub async fn rating_adjustments(dbpool: &DbPool, card_id: Uuid) -> Result<Vec<RatingAdjustment>, Error> {
+    Ok(sqlx::query_as::<_, RatingAdjustment>(
+        "SELECT * FROM card_rating_adjustments WHERE card_id = $1 ORDER BY created_at DESC, id",
+    )
+    .bind(card_id)
+    .fetch_all(dbpool)
+    .await?)
+}
+
+/// Delete the card. Its ownership history and rating adjustments are kept.
+pub async fn delete_card(dbpool: &DbPool, card_id: Uuid) -> Result<Card, Error> {
+    Ok(sqlx::query_as::<_, Card>("DELETE FROM cards WHERE id = $1 RETURNING *")
+        .bind(card_id)
+        .fetch_one(dbpool)
+        .await?)
+}
+
+/// Sign up a super user. The email counts as verified, since an operator vouches for it.
+pub async fn create_super_user(
+    dbpool: &DbPool,
+    email: &str,
+    nickname: &str,
+    password: String,
+    password_hash_cost: u32,
+) -> Result<User, Error> {
+    let email = email.trim();
+    Validator::default()
+        .check("email", validation::email(email))
+        .check("password", validation::password(&password))
+        .check("nickname", validation::nickname(nickname))
+        .finish()?;
+    sqlx::query_as::<_, User>(
+        "INSERT INTO users (kind, email, password, nickname, email_verified_at) VALUES ('super', $1, $2, $3, NOW()) ON CONFLICT DO NOTHING RETURNING *",
+    )
+    .bind(email)
+    .bind(hash_password(password, password_hash_cost)?)
+    .bind(nickname)
+    .fetch_optional(dbpool)
+    .await?
+    .ok_or_else(|| Error::Validation(vec![FieldError::new("email", "is already registered")]))
+}
+
+/// Make the user with the email super, or normal again.
+pub async fn set_kind_by_email(dbpool: &DbPool, email: &str, kind: UserKind) -> Result<User, Error> {
+    let user_id = sqlx::query_scalar::<_, Uuid>("SELECT id FROM users WHERE lower(email) = lower($1)")
+        .bind(email.trim())
+        .fetch_one(dbpool)
+        .await?;
+    set_kind(dbpool, user_id, kind).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::permission::{load_permissions, Permission};
+    use crate::test_util::*;
+
+    #[test]
+    fn test_like_pattern() {
+        assert_eq!(like_pattern("carol"), "%carol%");
+        assert_eq!(like_pattern("100%_\\"), "%100\\%\\_\\\\%");
+    }
+
+    #[actix_rt::test]
+    async fn test_create_super_user() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let dbpool = &db.pgpool;
+
+        let user = create_super_user(dbpool, " root@example.com ", "root", "hunter22".to_string(), 4).await.unwrap();
+        assert_eq!((user.email.as_str(), user.kind), ("root@example.com", UserKind::Super));
+        assert!(user.email_verified_at.is_some());
+        assert_eq!(load_permissions(dbpool, user.id).await.unwrap().len(), Permission::ALL.len());
+        let res = create_super_user(dbpool, "ROOT@example.com", "root2", "hunter22".to_string(), 4).await;
+        assert!(matches!(res, Err(Error::Validation(_))));
+        let res = create_super_user(dbpool, "other@example.com", "r", "short".to_string(), 4).await;
+        assert!(matches!(res, Err(Error::Validation(fields)) if fields.len() == 2));
+
+        let user = set_kind_by_email(dbpool, "ROOT@example.com", UserKind::Normal).await.unwrap();
+        assert_eq!(user.kind, UserKind::Normal);
+        assert_eq!(load_permissions(dbpool, user.id).await.unwrap(), vec![Permission::Play]);
+        let user = set_kind_by_email(dbpool, "root@example.com", UserKind::Super).await.unwrap();
+        assert_eq!(user.kind, UserKind::Super);
+        assert!(matches!(
+            set_kind_by_email(dbpool, "nobody@example.com", UserKind::Super).await,
+            Err(Error::Database(sqlx::Error::RowNotFound))
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn test_ban_moderator() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let dbpool = &db.pgpool;
+
+        let user = create_super_user(dbpool, "mod@example.com", "mod", "hunter22".to_string(), 4).await.unwrap();
+        let user = set_kind_by_email(dbpool, &user.email, UserKind::Normal).await.unwrap();
+        crate::permission::grant_role(dbpool, user.id, "moderator").await.unwrap();
+        assert!(load_permissions(dbpool, user.id).await.unwrap().contains(&Permission::Play));
+
+        // the moderator role grants play too, but a ban wins
+        assert!(set_banned(dbpool, user.id, true).await.unwrap());
+        let permissions = load_permissions(dbpool, user.id).await.unwrap();
+        assert!(!permissions.contains(&Permission::Play));
+        assert!(permissions.contains(&Permission::BattlesManage));
+
+        assert!(set_banned(dbpool, user.id, false).await.unwrap());
+        assert!(load_permissions(dbpool, user.id).await.unwrap().contains(&Permission::Play));
+    }
+}
@@ -1,5 +1,12 @@
-use actix_web::ResponseError;
-use async_graphql::{Error as GraphqlError, ErrorExtensions};
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
+use crate::permission::Permission;
+use crate::session::Session;
+use crate::validation::FieldError;
+use async_graphql::{Context, Error as GraphqlError, ErrorExtensions};
+use std::future::Future;
 use thiserror::Error as thisError;
 
 #[derive(thisError, Debug)]
@@ -21,12 +28,19 @@ pub enum Error {
     JwtError(#[from] jsonwebtoken::errors::Error),
     #[error("not authorized to do such request")]
     NotAuthorized,
-    #[error("wrong password")]
+    /// Same for unknown emails, so logins do not reveal who is registered.
+    #[error("wrong email or password")]
     WrongPassword,
+    #[error("too many failed logins. try again in {0} seconds")]
+    LockedOut(i64),
+    #[error("rate limit exceeded. retry in {0} seconds")]
+    RateLimited(i64),
     #[error("bincode error: {0:?}")]
     BincodeError(#[from] bincode::Error),
     #[error("serde_json error: {0:?}")]
     SerdeJsonError(#[from] serde_json::Error),
+    #[error("uuid error: {0:?}")]
+    UuidError(#[from] uuid::Error),
     #[error("base64 error: {0:?}")]
     Base64Error(#[from] base64::DecodeError),
     #[error("io error: {0:?}")]
@@ -35,18 +49,131 @@ pub enum Error {
     RedisPoolError(#[from] deadpool_redis::PoolError),
     #[error("redis error: {0:?}")]
     RedisError(#[from] redis::RedisError),
+    #[error("http client error: {0}")]
+    HttpClientError(String),
+    #[error("mail error: {0}")]
+    MailError(String),
+    #[error("invalid config: {0}")]
+    Config(String),
+    /// Error code from RFC 6749, such as `invalid_grant`.
+    #[error("oauth error: {0}")]
+    Oauth(&'static str),
+    #[error("invalid input: {}", describe_fields(.0))]
+    Validation(Vec<FieldError>),
+    /// Error async-graphql raised itself, such as missing context data.
+    #[error("graphql error: {}", .0.message)]
+    Graphql(GraphqlError),
 }
 
-impl ResponseError for Error {}
+fn describe_fields(errors: &[FieldError]) -> String {
+    errors
+        .iter()
+        .map(|error| format!("{} {}", error.field, error.message))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Error {
+    /// Stable code clients can branch on. Sent in the `code` error extension.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::NotAuthorized | Error::JwtError(_) | Error::Oauth("invalid_client") => "NOT_AUTHORIZED",
+            Error::WrongPassword => "WRONG_PASSWORD",
+            Error::LockedOut(_) => "LOCKED_OUT",
+            Error::RateLimited(_) => "RATE_LIMITED",
+            Error::Database(sqlx::Error::RowNotFound) => "NOT_FOUND",
+            Error::BadRequest(..)
+            | Error::Validation(_)
+            | Error::Oauth(_)
+            | Error::Base64Error(_)
+            | Error::UuidError(_) => "BAD_REQUEST",
+            _ => "INTERNAL",
+        }
+    }
+    /// What clients are told. Details of internal errors stay in the server log.
+    pub fn public_message(&self) -> String {
+        if let Error::JwtError(_) = self {
+            // why a token was refused helps whoever forges one
+            return "invalid token".to_string();
+        }
+        match self.code() {
+            "INTERNAL" => "internal error".to_string(),
+            "NOT_FOUND" => "not found".to_string(),
+            _ => self.to_string(),
+        }
+    }
+}
 
-/*impl From<Error> for GraphqlError {
-    fn from(e: Error) -> Self {
-        GraphqlError::new(format!("{}", e))
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self.code() {
+            "NOT_AUTHORIZED" | "WRONG_PASSWORD" => StatusCode::UNAUTHORIZED,
+            "NOT_FOUND" => StatusCode::NOT_FOUND,
+            "LOCKED_OUT" | "RATE_LIMITED" => StatusCode::TOO_MANY_REQUESTS,
+            "BAD_REQUEST" => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            // clients may authenticate with HTTP Basic, so say so when they fail to
+            Error::Oauth("invalid_client") => HttpResponse::build(self.status_code())
+                .append_header((header::WWW_AUTHENTICATE, r#"Basic realm="oauth""#))
+                .json(serde_json::json!({ "error": "invalid_client" })),
+            Error::Oauth(code) => HttpResponse::build(self.status_code())
+                .json(serde_json::json!({ "error": code })),
+            Error::LockedOut(seconds) | Error::RateLimited(seconds) => HttpResponse::build(self.status_code())
+                .append_header((header::RETRY_AFTER, seconds.to_string()))
+                .body(self.public_message()),
+            _ => {
+                if self.code() == "INTERNAL" {
+                    log::error!("internal error: {:?}", self);
+                }
+                HttpResponse::build(self.status_code()).body(self.public_message())
+            }
+        }
     }
-}*/
+}
 
 impl ErrorExtensions for Error {
     fn extend(&self) -> GraphqlError {
-        GraphqlError::new(format!("{}", self))
+        GraphqlError::new(self.public_message()).extend_with(|_, extensions| {
+            extensions.set("code", self.code());
+            if let Error::Validation(fields) = self {
+                extensions.set("fields", async_graphql::to_value(fields).unwrap_or_default());
+            }
+        })
+    }
+}
+
+impl From<GraphqlError> for Error {
+    fn from(error: GraphqlError) -> Self {
+        Error::Graphql(error)
+    }
+}
+
+/// GraphQL error with a `code` extension. Internal errors are logged, and their details only
+/// shown to sessions with `ERROR_DETAILS`.
+pub fn to_graphql_error(ctx: &Context<'_>, err: Error) -> GraphqlError {
+    if err.code() != "INTERNAL" {
+        return err.extend();
+    }
+    log::error!("internal error: {:?}", err);
+    let show_detail = ctx
+        .data_opt::<Session>()
+        .map_or(false, |session| session.has(Permission::ErrorDetails));
+    if show_detail {
+        let detail = err.to_string();
+        err.extend().extend_with(|_, extensions| extensions.set("detail", detail))
+    } else {
+        err.extend()
     }
 }
+
+/// Run a resolver body, turning its error with `to_graphql_error`.
+pub async fn resolve<T>(
+    ctx: &Context<'_>,
+    body: impl Future<Output = Result<T, Error>>,
+) -> Result<T, GraphqlError> {
+    body.await.map_err(|err| to_graphql_error(ctx, err))
+}
@@ -0,0 +1,57 @@
+use crate::error::Error;
+use deadpool_redis::{cmd, ConnectionWrapper as RedisConn, Pool as RedisPool};
+use futures::{Stream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use uuid::Uuid;
+
+pub type RedisClient = redis::Client;
+
+pub fn battle_channel(battle_id: Uuid) -> String {
+    format!("events/battle/{}", battle_id)
+}
+
+pub fn match_channel(user_id: Uuid) -> String {
+    format!("events/match/{}", user_id)
+}
+
+pub fn card_rating_channel(card_id: Uuid) -> String {
+    format!("events/card-rating/{}", card_id)
+}
+
+pub async fn publish<T: Serialize>(
+    redis_conn: &mut RedisConn,
+    channel: &str,
+    payload: &T,
+) -> Result<(), Error> {
+    cmd("PUBLISH")
+        .arg(&[channel, &serde_json::to_string(payload)?])
+        .execute_async(redis_conn)
+        .await?;
+    Ok(())
+}
+
+/// Publish without failing the caller. Events go out after the change is committed,
+/// so there is nothing to roll back.
+pub async fn notify<T: Serialize>(redispool: &RedisPool, channel: &str, payload: &T) {
+    let result = match redispool.get().await {
+        Ok(mut redis_conn) => publish(&mut redis_conn, channel, payload).await,
+        Err(err) => Err(Error::from(err)),
+    };
+    if let Err(err) = result {
+        log::warn!("publish to {}: {}", channel, err);
+    }
+}
+
+/// Messages on the channel from every server instance. Payloads that do not parse are dropped.
+pub async fn subscribe<T: DeserializeOwned>(
+    client: &RedisClient,
+    channel: &str,
+) -> Result<impl Stream<Item = T>, Error> {
+    let mut pubsub = client.get_async_connection().await?.into_pubsub();
+    pubsub.subscribe(channel).await?;
+    Ok(pubsub.into_on_message().filter_map(|msg| async move {
+        msg.get_payload::<String>()
+            .ok()
+            .and_then(|payload| serde_json::from_str(&payload).ok())
+    }))
+}
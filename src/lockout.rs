@@ -0,0 +1,131 @@
+use crate::error::Error;
+use async_graphql::SimpleObject;
+use deadpool_redis::{cmd, ConnectionWrapper as RedisConn};
+
+/// Failures are forgotten this long after the last one.
+const FAILURE_WINDOW_SECONDS: i64 = 60 * 60;
+/// Failures allowed before logins are delayed.
+const FREE_ATTEMPTS: i64 = 3;
+const MAX_LOCKOUT_SECONDS: i64 = 60 * 15;
+
+/// Who failed logins are counted against. Counting per ip as well slows down guessing
+/// across many accounts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Subject<'a> {
+    Email(&'a str),
+    Ip(&'a str),
+}
+
+impl Subject<'_> {
+    fn id(&self) -> String {
+        match self {
+            Subject::Email(email) => format!("email/{}", email.to_lowercase()),
+            Subject::Ip(ip) => format!("ip/{}", ip),
+        }
+    }
+    fn failures_key(&self) -> String {
+        format!("login-failures/{}", self.id())
+    }
+    fn lock_key(&self) -> String {
+        format!("login-lock/{}", self.id())
+    }
+}
+
+fn subjects<'a>(email: &'a str, ip: Option<&'a str>) -> Vec<Subject<'a>> {
+    let mut subjects = vec![Subject::Email(email)];
+    subjects.extend(ip.map(Subject::Ip));
+    subjects
+}
+
+/// Seconds logins stay locked after the given number of failures. Doubles with every
+/// failure past the free ones.
+fn lockout_seconds(failures: i64) -> i64 {
+    if failures < FREE_ATTEMPTS {
+        return 0;
+    }
+    let doublings = (failures - FREE_ATTEMPTS).min(16) as u32;
+    (2_i64.pow(doublings)).min(MAX_LOCKOUT_SECONDS)
+}
+
+async fn locked_for(redis_conn: &mut RedisConn, subject: Subject<'_>) -> Result<i64, Error> {
+    // TTL is -2 for missing keys
+    let ttl: i64 = cmd("TTL").arg(&[subject.lock_key()]).query_async(redis_conn).await?;
+    Ok(ttl.max(0))
+}
+
+/// Fails with `Error::LockedOut` while the email or ip has to wait before trying again.
+pub async fn check(redis_conn: &mut RedisConn, email: &str, ip: Option<&str>) -> Result<(), Error> {
+    let mut wait = 0;
+    for subject in subjects(email, ip) {
+        wait = wait.max(locked_for(redis_conn, subject).await?);
+    }
+    if wait > 0 {
+        Err(Error::LockedOut(wait))
+    } else {
+        Ok(())
+    }
+}
+
+pub async fn record_failure(redis_conn: &mut RedisConn, email: &str, ip: Option<&str>) -> Result<(), Error> {
+    for subject in subjects(email, ip) {
+        let failures: i64 = cmd("INCR").arg(&[subject.failures_key()]).query_async(redis_conn).await?;
+        cmd("EXPIRE")
+            .arg(subject.failures_key())
+            .arg(FAILURE_WINDOW_SECONDS)
+            .execute_async(redis_conn)
+            .await?;
+        let lockout = lockout_seconds(failures);
+        if lockout > 0 {
+            cmd("SET")
+                .arg(subject.lock_key())
+                .arg(failures)
+                .arg("EX")
+                .arg(lockout)
+                .execute_async(redis_conn)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Forget the failures of an email after a successful login. Failures of the ip are kept.
+pub async fn clear(redis_conn: &mut RedisConn, email: &str) -> Result<(), Error> {
+    let subject = Subject::Email(email);
+    cmd("DEL")
+        .arg(&[subject.failures_key(), subject.lock_key()])
+        .execute_async(redis_conn)
+        .await?;
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, SimpleObject)]
+pub struct LockoutStatus {
+    /// Failed logins within the last hour.
+    pub failed_attempts: i32,
+    /// Seconds until the next login may be tried. 0 when not locked.
+    pub locked_for_seconds: i32,
+}
+
+pub async fn status(redis_conn: &mut RedisConn, email: &str) -> Result<LockoutStatus, Error> {
+    let subject = Subject::Email(email);
+    let failures: Option<i64> = cmd("GET").arg(&[subject.failures_key()]).query_async(redis_conn).await?;
+    Ok(LockoutStatus {
+        failed_attempts: failures.unwrap_or(0) as i32,
+        locked_for_seconds: locked_for(redis_conn, subject).await? as i32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockout_seconds() {
+        assert_eq!(lockout_seconds(0), 0);
+        assert_eq!(lockout_seconds(2), 0);
+        assert_eq!(lockout_seconds(3), 1);
+        assert_eq!(lockout_seconds(4), 2);
+        assert_eq!(lockout_seconds(8), 32);
+        assert_eq!(lockout_seconds(1000), MAX_LOCKOUT_SECONDS);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::error::Error;
+use async_trait::async_trait;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers mail to players. Swappable so tests never talk to a real server.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: Mail) -> Result<(), Error>;
+}
+
+pub type SharedMailer = Arc<dyn Mailer>;
+
+/// Send without failing the request. The player can ask for the mail again.
+pub async fn deliver(mailer: &SharedMailer, mail: Mail) {
+    if let Err(err) = mailer.send(mail).await {
+        log::warn!("failed to send mail: {}", err);
+    }
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(host: &str, username: &str, password: &str, from: &str) -> Result<Self, Error> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::relay(host)
+            .map_err(|err| Error::MailError(err.to_string()))?
+            .credentials(Credentials::new(username.to_string(), password.to_string()))
+            .build();
+        Ok(SmtpMailer {
+            transport,
+            from: from
+                .parse()
+                .map_err(|_| Error::MailError(format!("invalid sender address: {}", from)))?,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, mail: Mail) -> Result<(), Error> {
+        let to: Mailbox = mail
+            .to
+            .parse()
+            .map_err(|_| Error::MailError(format!("invalid recipient address: {}", mail.to)))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(mail.subject)
+            .body(mail.body)
+            .map_err(|err| Error::MailError(err.to_string()))?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(|err| Error::MailError(err.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Logs who would have got mail instead of sending it, for servers without SMTP. The body
+/// holds one-time codes, so it is left out.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, mail: Mail) -> Result<(), Error> {
+        log::warn!("SMTP is not configured. dropped mail to {}: {}", mail.to, mail.subject);
+        Ok(())
+    }
+}
+
+/// Keeps mail instead of sending it, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<Mail>>,
+}
+
+#[cfg(test)]
+impl MemoryMailer {
+    pub fn sent(&self) -> Vec<Mail> {
+        self.sent.lock().unwrap().clone()
+    }
+    /// Mail sent so far, once there is at least `count` of it or a few seconds have passed.
+    /// For mail that goes out in the background.
+    pub async fn wait_for(&self, count: usize) -> Vec<Mail> {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        loop {
+            let sent = self.sent();
+            if sent.len() >= count || std::time::Instant::now() >= deadline {
+                return sent;
+            }
+            actix_rt::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, mail: Mail) -> Result<(), Error> {
+        self.sent.lock().unwrap().push(mail);
+        Ok(())
+    }
+}
@@ -1,42 +1,233 @@
 use actix_web::{App, HttpServer};
-use serde::Deserialize;
+use model::UserKind;
+use mailer::{LogMailer, SharedMailer, SmtpMailer};
+use settings::Config;
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::StructOpt;
+use oidc::OidcProviders;
+use session::TrustedProxies;
+use token::{JwtAlgorithm, JwtKeys};
+mod account;
+mod admin;
 mod error;
+mod events;
+mod lockout;
+mod mailer;
+mod matchmaking;
+mod migration;
 mod model;
+mod oauth;
+mod oidc;
+mod permission;
+mod rate_limit;
+mod rating;
 mod routes;
 mod session;
+mod settings;
 #[cfg(test)]
 mod test_util;
+mod token;
 mod util;
+mod validation;
 
-#[derive(Deserialize, Debug)]
-struct Config {
-    database_url: String,
-    redis_url: String,
+#[derive(StructOpt, Debug)]
+#[structopt(name = "server", about = "Card battle server. Configured through a TOML file and environment variables.")]
+struct Opt {
+    /// TOML file of settings. Environment variables override what it sets.
+    #[structopt(long, env = "SERVER_CONFIG_FILE", parse(from_os_str))]
+    config: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Serve the API on `listen_addr`. Runs when no command is given.
+    Serve,
+    /// Apply the migrations that have not run yet, or revert some.
+    Migrate {
+        #[structopt(subcommand)]
+        command: Option<MigrateCommand>,
+    },
+    /// Create a super user. The password is read from the first line of stdin.
+    CreateAdmin {
+        #[structopt(long)]
+        email: String,
+        #[structopt(long)]
+        nickname: String,
+    },
+    /// Make an existing user super. Their sessions end, so they log in with the new roles.
+    Promote { email: String },
+    /// Make a super user normal again. Their sessions and tokens end right away.
+    Demote { email: String },
+}
+
+#[derive(StructOpt, Debug)]
+enum MigrateCommand {
+    /// Apply the migrations that have not run yet. Runs when no command is given.
+    Run,
+    /// Undo the latest applied migrations with their `.down.sql` files.
+    Revert {
+        /// How many migrations to undo.
+        #[structopt(long, default_value = "1")]
+        steps: usize,
+    },
+}
+
+/// First line of stdin, so the password stays out of the shell history and process list.
+fn read_password() -> Result<String, error::Error> {
+    eprint!("password: ");
+    let mut password = String::new();
+    std::io::stdin().lock().read_line(&mut password)?;
+    Ok(password.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+async fn connect_database(config: &Config) -> Result<model::DbPool, error::Error> {
+    Ok(model::DbPoolOptions::new()
+        .max_connections(config.db_max_connections)
+        .min_connections(config.db_min_connections)
+        .connect(&config.database_url)
+        .await?)
+}
+
+/// Change the kind of the user and end their sessions, so the old roles stop working now.
+async fn set_user_kind(config: &Config, email: &str, kind: UserKind) -> Result<model::User, error::Error> {
+    let user = admin::set_kind_by_email(&connect_database(config).await?, email, kind).await?;
+    let redispool = model::create_redispool(&config.redis_url, 1)?;
+    session::end_user_sessions(&mut redispool.get().await?, user.id).await?;
+    Ok(user)
 }
 
 #[actix_rt::main]
-async fn main() -> Result<(), error::Error> {
-    let config = match envy::from_env::<Config>() {
-        Ok(config) => config,
-        Err(err) => panic!("{:#?}", err),
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let opt = Opt::from_args();
+    let result = match settings::load(opt.config.as_deref()) {
+        Ok(config) => run(opt.command.unwrap_or(Command::Serve), config).await,
+        Err(err) => Err(err),
     };
+    if let Err(err) = result {
+        log::error!("{}", err);
+        std::process::exit(1);
+    }
+}
 
-    let dbpool = model::DbPoolOptions::new()
-        .connect(&config.database_url)
-        .await?;
-    let redispool = model::create_redispool(&config.redis_url)?;
+async fn run(command: Command, config: Config) -> Result<(), error::Error> {
+    match command {
+        Command::Serve => serve(config).await?,
+        Command::Migrate { command } => {
+            let dbpool = connect_database(&config).await?;
+            match command.unwrap_or(MigrateCommand::Run) {
+                MigrateCommand::Run => {
+                    migration::run(&dbpool).await?;
+                    println!("migrations are up to date");
+                }
+                MigrateCommand::Revert { steps } => {
+                    let reverted = migration::revert(&dbpool, steps).await?;
+                    if reverted.is_empty() {
+                        println!("no migrations to revert");
+                    }
+                    for migration in reverted {
+                        println!("reverted {} {}", migration.version, migration.description);
+                    }
+                }
+            }
+        }
+        Command::CreateAdmin { email, nickname } => {
+            let dbpool = connect_database(&config).await?;
+            let user =
+                admin::create_super_user(&dbpool, &email, &nickname, read_password()?, config.password_hash_cost)
+                    .await?;
+            println!("created super user {} ({})", user.email, user.id);
+        }
+        Command::Promote { email } => {
+            let user = set_user_kind(&config, &email, UserKind::Super).await?;
+            println!("{} is now a super user", user.email);
+        }
+        Command::Demote { email } => {
+            let user = set_user_kind(&config, &email, UserKind::Normal).await?;
+            println!("{} is now a normal user", user.email);
+        }
+    }
+    Ok(())
+}
+
+async fn serve(config: Config) -> Result<(), error::Error> {
+    let dbpool = connect_database(&config).await?;
+    if config.run_migrations {
+        migration::run(&dbpool).await?;
+    }
+    let redispool = model::create_redispool(&config.redis_url, config.redis_pool_size)?;
+    let redis_client = model::create_redis_client(&config.redis_url)?;
+
+    let jwt_keys = match config.jwt_algorithm {
+        JwtAlgorithm::Hs256 => match &config.jwt_secret {
+            Some(secret) => JwtKeys::hs256(secret.as_bytes()),
+            None => return Err(error::Error::Config("jwt_secret is required for hs256".to_string())),
+        },
+        JwtAlgorithm::Rs256 => match (&config.jwt_private_key, &config.jwt_public_key) {
+            (Some(private_key), Some(public_key)) => {
+                JwtKeys::rs256(&std::fs::read(private_key)?, &std::fs::read(public_key)?)?
+            }
+            _ => {
+                return Err(error::Error::Config(
+                    "jwt_private_key and jwt_public_key are required for rs256".to_string(),
+                ))
+            }
+        },
+    };
+
+    let oidc_providers = OidcProviders(config.oidc_providers.clone());
 
-    let schema = model::build_schema(dbpool.clone(), redispool.clone()).await?;
+    let mailer: SharedMailer = match (
+        &config.smtp_host,
+        &config.smtp_username,
+        &config.smtp_password,
+        &config.mail_from,
+    ) {
+        (Some(host), Some(username), Some(password), Some(from)) => {
+            Arc::new(SmtpMailer::new(host, username, password, from)?)
+        }
+        _ => {
+            log::warn!("SMTP is not configured. account mail will only be logged");
+            Arc::new(LogMailer)
+        }
+    };
+
+    let schema = model::build_schema(
+        dbpool.clone(),
+        redispool.clone(),
+        redis_client,
+        config.rating_system(),
+        jwt_keys.clone(),
+        mailer,
+        config.session_policy(),
+        config.tuning(),
+    )
+    .await?;
+    let session_policy = config.session_policy();
+    let trusted_proxies = TrustedProxies(config.trusted_proxies.clone());
+    let tls = config.tls()?;
 
-    HttpServer::new(move || {
+    actix_rt::spawn(matchmaking::run_matchmaker(dbpool.clone(), redispool.clone()));
+
+    let server = HttpServer::new(move || {
         App::new()
             .data(schema.clone())
             .data(dbpool.clone())
             .data(redispool.clone())
+            .data(jwt_keys.clone())
+            .data(oidc_providers.clone())
+            .data(session_policy)
+            .data(trusted_proxies.clone())
             .configure(routes::routes)
-    })
-    .bind("0.0.0.0:8000")?
-    .run()
-    .await?;
+    });
+    let server = match tls {
+        Some(tls) => server.bind_rustls(&config.listen_addr, tls)?,
+        None => server.bind(&config.listen_addr)?,
+    };
+    server.run().await?;
     Ok(())
 }
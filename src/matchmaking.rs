@@ -0,0 +1,352 @@
+use crate::error::Error;
+use crate::events;
+use crate::model::{Battle, DbPool, RedisPool};
+use chrono::Utc;
+use deadpool_redis::{cmd, ConnectionWrapper as RedisConn};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// card id -> rating
+const QUEUE_KEY: &str = "matchmaking/queue";
+/// card id -> joined at, in unix seconds
+const JOINED_KEY: &str = "matchmaking/joined";
+const RATING_WINDOW_BASE: f64 = 50.0;
+const RATING_WINDOW_GROWTH_PER_SECOND: f64 = 5.0;
+const MAX_RATING_WINDOW: f64 = 500.0;
+const REMATCH_COOLDOWN_SECONDS: i64 = 60 * 10;
+const SWEEP_INTERVAL_SECONDS: u64 = 2;
+
+/// Removes both cards from the queue only if both are still there,
+/// so two servers can not hand out the same card twice.
+const CLAIM_SCRIPT: &str = r#"
+if redis.call('ZSCORE', KEYS[1], ARGV[1]) and redis.call('ZSCORE', KEYS[1], ARGV[2]) then
+  redis.call('ZREM', KEYS[1], ARGV[1], ARGV[2])
+  redis.call('ZREM', KEYS[2], ARGV[1], ARGV[2])
+  return 1
+end
+return 0
+"#;
+
+fn owner_key(card_id: Uuid) -> String {
+    format!("matchmaking/owner/{}", card_id)
+}
+
+/// Last opponent of the card. Expires after the rematch cooldown.
+fn recent_key(card_id: Uuid) -> String {
+    format!("matchmaking/recent/{}", card_id)
+}
+
+fn now() -> f64 {
+    Utc::now().timestamp_millis() as f64 / 1000.0
+}
+
+/// Acceptable rating difference after waiting `waited` seconds.
+pub fn rating_window(waited: f64) -> f64 {
+    (RATING_WINDOW_BASE + RATING_WINDOW_GROWTH_PER_SECOND * waited.max(0.0)).min(MAX_RATING_WINDOW)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pairing {
+    pub challenger_id: Uuid,
+    pub challenger_card_id: Uuid,
+    pub opponent_id: Uuid,
+    pub opponent_card_id: Uuid,
+}
+
+struct Entry {
+    rating: f64,
+    joined_at: f64,
+    owner_id: Uuid,
+}
+
+async fn entry(redis_conn: &mut RedisConn, card_id: Uuid) -> Result<Option<Entry>, Error> {
+    let rating: Option<f64> = cmd("ZSCORE")
+        .arg(&[QUEUE_KEY, &card_id.to_string()])
+        .query_async(redis_conn)
+        .await?;
+    let joined_at: Option<f64> = cmd("ZSCORE")
+        .arg(&[JOINED_KEY, &card_id.to_string()])
+        .query_async(redis_conn)
+        .await?;
+    let owner_id: Option<String> = cmd("GET")
+        .arg(&[owner_key(card_id)])
+        .query_async(redis_conn)
+        .await?;
+    Ok(match (rating, joined_at, owner_id) {
+        (Some(rating), Some(joined_at), Some(owner_id)) => Some(Entry {
+            rating,
+            joined_at,
+            owner_id: Uuid::parse_str(&owner_id)?,
+        }),
+        _ => None,
+    })
+}
+
+async fn recent_opponent(redis_conn: &mut RedisConn, card_id: Uuid) -> Result<Option<Uuid>, Error> {
+    let opponent: Option<String> = cmd("GET")
+        .arg(&[recent_key(card_id)])
+        .query_async(redis_conn)
+        .await?;
+    Ok(opponent.and_then(|id| Uuid::parse_str(&id).ok()))
+}
+
+/// Put a claimed card back with its original join time, so it keeps its widened window.
+async fn requeue(redis_conn: &mut RedisConn, card_id: Uuid, entry: &Entry) -> Result<(), Error> {
+    cmd("SET")
+        .arg(&[owner_key(card_id), entry.owner_id.to_string()])
+        .execute_async(redis_conn)
+        .await?;
+    let card_id = card_id.to_string();
+    cmd("ZADD")
+        .arg(&[QUEUE_KEY, &entry.rating.to_string(), &card_id])
+        .execute_async(redis_conn)
+        .await?;
+    cmd("ZADD")
+        .arg(&[JOINED_KEY, "NX", &entry.joined_at.to_string(), &card_id])
+        .execute_async(redis_conn)
+        .await?;
+    Ok(())
+}
+
+/// Put the card in the queue. Joining again only updates the rating.
+pub async fn join(
+    redis_conn: &mut RedisConn,
+    card_id: Uuid,
+    owner_id: Uuid,
+    rating: f64,
+) -> Result<(), Error> {
+    cmd("SET")
+        .arg(&[owner_key(card_id), owner_id.to_string()])
+        .execute_async(redis_conn)
+        .await?;
+    let card_id = card_id.to_string();
+    cmd("ZADD")
+        .arg(&[QUEUE_KEY, &rating.to_string(), &card_id])
+        .execute_async(redis_conn)
+        .await?;
+    cmd("ZADD")
+        .arg(&[JOINED_KEY, "NX", &now().to_string(), &card_id])
+        .execute_async(redis_conn)
+        .await?;
+    Ok(())
+}
+
+/// Returns false if the card was not in the queue.
+pub async fn leave(redis_conn: &mut RedisConn, card_id: Uuid) -> Result<bool, Error> {
+    let removed: i64 = cmd("ZREM")
+        .arg(&[QUEUE_KEY, &card_id.to_string()])
+        .query_async(redis_conn)
+        .await?;
+    cmd("ZREM")
+        .arg(&[JOINED_KEY, &card_id.to_string()])
+        .execute_async(redis_conn)
+        .await?;
+    cmd("DEL")
+        .arg(&[owner_key(card_id)])
+        .execute_async(redis_conn)
+        .await?;
+    Ok(removed > 0)
+}
+
+/// Place in the queue by join time. 0 is the longest waiting card.
+pub async fn position(redis_conn: &mut RedisConn, card_id: Uuid) -> Result<Option<i64>, Error> {
+    Ok(cmd("ZRANK")
+        .arg(&[JOINED_KEY, &card_id.to_string()])
+        .query_async(redis_conn)
+        .await?)
+}
+
+/// Closest rated card that is acceptable for both sides.
+/// Cards of the same owner and the last opponent are skipped.
+pub async fn find_opponent(
+    redis_conn: &mut RedisConn,
+    card_id: Uuid,
+) -> Result<Option<Pairing>, Error> {
+    let entry = match entry(redis_conn, card_id).await? {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    let now = now();
+    let last_opponent = recent_opponent(redis_conn, card_id).await?;
+    let candidates: Vec<(String, f64)> = cmd("ZRANGEBYSCORE")
+        .arg(&[
+            QUEUE_KEY,
+            &(entry.rating - MAX_RATING_WINDOW).to_string(),
+            &(entry.rating + MAX_RATING_WINDOW).to_string(),
+            "WITHSCORES",
+        ])
+        .query_async(redis_conn)
+        .await?;
+    let mut candidates = candidates
+        .into_iter()
+        .filter_map(|(id, rating)| Uuid::parse_str(&id).ok().map(|id| (id, rating)))
+        .filter(|(id, _)| *id != card_id && Some(*id) != last_opponent)
+        .collect::<Vec<_>>();
+    candidates.sort_by(|(_, a), (_, b)| {
+        (a - entry.rating)
+            .abs()
+            .partial_cmp(&(b - entry.rating).abs())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    for (candidate_id, candidate_rating) in candidates {
+        let candidate = match self::entry(redis_conn, candidate_id).await? {
+            Some(candidate) => candidate,
+            None => continue,
+        };
+        if candidate.owner_id == entry.owner_id {
+            continue;
+        }
+        if recent_opponent(redis_conn, candidate_id).await? == Some(card_id) {
+            continue;
+        }
+        let window = rating_window(now - entry.joined_at).max(rating_window(now - candidate.joined_at));
+        if (candidate_rating - entry.rating).abs() > window {
+            continue;
+        }
+        return Ok(Some(Pairing {
+            challenger_id: entry.owner_id,
+            challenger_card_id: card_id,
+            opponent_id: candidate.owner_id,
+            opponent_card_id: candidate_id,
+        }));
+    }
+    Ok(None)
+}
+
+async fn claim(redis_conn: &mut RedisConn, pairing: &Pairing) -> Result<bool, Error> {
+    let a = pairing.challenger_card_id;
+    let b = pairing.opponent_card_id;
+    let claimed: i64 = cmd("EVAL")
+        .arg(&[CLAIM_SCRIPT, "2", QUEUE_KEY, JOINED_KEY, &a.to_string(), &b.to_string()])
+        .query_async(redis_conn)
+        .await?;
+    if claimed == 0 {
+        return Ok(false);
+    }
+    cmd("DEL")
+        .arg(&[owner_key(a), owner_key(b)])
+        .execute_async(redis_conn)
+        .await?;
+    for (card_id, opponent_card_id) in [(a, b), (b, a)].iter() {
+        cmd("SET")
+            .arg(&[
+                recent_key(*card_id),
+                opponent_card_id.to_string(),
+                "EX".to_string(),
+                REMATCH_COOLDOWN_SECONDS.to_string(),
+            ])
+            .execute_async(redis_conn)
+            .await?;
+    }
+    Ok(true)
+}
+
+/// Pair the card with an opponent from the queue and start an active battle.
+pub async fn try_match(
+    dbpool: &DbPool,
+    redis_conn: &mut RedisConn,
+    card_id: Uuid,
+) -> Result<Option<Battle>, Error> {
+    let pairing = match find_opponent(redis_conn, card_id).await? {
+        Some(pairing) => pairing,
+        None => return Ok(None),
+    };
+    let mut entries = Vec::new();
+    for card_id in [pairing.challenger_card_id, pairing.opponent_card_id].iter() {
+        if let Some(entry) = entry(redis_conn, *card_id).await? {
+            entries.push((*card_id, entry));
+        }
+    }
+    if !claim(redis_conn, &pairing).await? {
+        return Ok(None);
+    }
+    // Ownership may have changed while the cards were waiting, and either card may have
+    // entered a battle by hand. Both rows stay locked until the battle is written.
+    let mut tx = dbpool.begin().await?;
+    sqlx::query("SELECT id FROM cards WHERE id IN ($1, $2) ORDER BY id FOR UPDATE")
+        .bind(pairing.challenger_card_id)
+        .bind(pairing.opponent_card_id)
+        .execute(&mut tx)
+        .await?;
+    let battle = sqlx::query_as::<_, Battle>(
+        "INSERT INTO battles (state, challenger_id, challenger_card_id, opponent_id, opponent_card_id, started_at)
+        SELECT 'active', $1, $2, $3, $4, NOW()
+        WHERE EXISTS (SELECT 1 FROM cards WHERE id = $2 AND owner_id = $1)
+        AND EXISTS (SELECT 1 FROM cards WHERE id = $4 AND owner_id = $3)
+        AND NOT EXISTS (SELECT 1 FROM battles WHERE state IN ('pending', 'active') AND (challenger_card_id IN ($2, $4) OR opponent_card_id IN ($2, $4)))
+        RETURNING *")
+        .bind(pairing.challenger_id)
+        .bind(pairing.challenger_card_id)
+        .bind(pairing.opponent_id)
+        .bind(pairing.opponent_card_id)
+        .fetch_optional(&mut tx)
+        .await?;
+    if battle.is_some() {
+        tx.commit().await?;
+        return Ok(battle);
+    }
+    // The claim already took both cards out of the queue. Put back the ones that can still
+    // battle and lift the cooldown, since the two never met.
+    let free: Vec<Uuid> = sqlx::query_scalar(
+        "SELECT id FROM cards WHERE ((id = $2 AND owner_id = $1) OR (id = $4 AND owner_id = $3))
+        AND NOT EXISTS (SELECT 1 FROM battles WHERE state IN ('pending', 'active') AND (challenger_card_id = cards.id OR opponent_card_id = cards.id))")
+        .bind(pairing.challenger_id)
+        .bind(pairing.challenger_card_id)
+        .bind(pairing.opponent_id)
+        .bind(pairing.opponent_card_id)
+        .fetch_all(&mut tx)
+        .await?;
+    tx.commit().await?;
+    cmd("DEL")
+        .arg(&[recent_key(pairing.challenger_card_id), recent_key(pairing.opponent_card_id)])
+        .execute_async(redis_conn)
+        .await?;
+    for (card_id, entry) in entries.iter() {
+        if free.contains(card_id) {
+            requeue(redis_conn, *card_id, entry).await?;
+        }
+    }
+    Ok(None)
+}
+
+/// Match every queued card, oldest first. Windows widen while cards wait,
+/// so cards that had no opponent when joining get paired here.
+pub async fn sweep(dbpool: &DbPool, redis_conn: &mut RedisConn) -> Result<Vec<Battle>, Error> {
+    let card_ids: Vec<String> = cmd("ZRANGE")
+        .arg(&[JOINED_KEY, "0", "-1"])
+        .query_async(redis_conn)
+        .await?;
+    let mut battles = Vec::new();
+    for card_id in card_ids {
+        if let Some(battle) = try_match(dbpool, redis_conn, Uuid::parse_str(&card_id)?).await? {
+            battles.push(battle);
+        }
+    }
+    Ok(battles)
+}
+
+/// Tell both players about a battle started by matchmaking.
+pub async fn announce(redispool: &RedisPool, battle: &Battle) {
+    events::notify(redispool, &events::match_channel(battle.challenger_id), battle).await;
+    if let Some(opponent_id) = battle.opponent_id {
+        events::notify(redispool, &events::match_channel(opponent_id), battle).await;
+    }
+}
+
+pub async fn run_matchmaker(dbpool: DbPool, redispool: RedisPool) {
+    loop {
+        actix_rt::time::sleep(Duration::from_secs(SWEEP_INTERVAL_SECONDS)).await;
+        let result = match redispool.get().await {
+            Ok(mut redis_conn) => sweep(&dbpool, &mut redis_conn).await,
+            Err(err) => Err(Error::from(err)),
+        };
+        match result {
+            Ok(battles) => {
+                for battle in battles.iter() {
+                    announce(&redispool, battle).await;
+                }
+            }
+            Err(err) => log::error!("matchmaker: {}", err),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use crate::error::Error;
+use crate::model::DbPool;
+use sqlx::migrate::{Migrate, MigrateError, Migration, Migrator};
+use sqlx::{Connection, Executor, PgConnection};
+
+/// Migrations embedded from `migrations/`. Each one is a `.up.sql` and `.down.sql` pair.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Apply every migration that has not run yet.
+pub async fn run(dbpool: &DbPool) -> Result<(), Error> {
+    Ok(MIGRATOR.run(dbpool).await?)
+}
+
+/// Undo the latest applied migrations, newest first. Returns the ones that were reverted,
+/// which are fewer than `steps` when the database runs out of them.
+pub async fn revert(dbpool: &DbPool, steps: usize) -> Result<Vec<Migration>, Error> {
+    let mut conn = dbpool.acquire().await?;
+    conn.lock().await?;
+    let reverted = revert_locked(&mut conn, steps).await;
+    // unlock even when reverting failed, or every later run waits on the lock
+    let unlocked = conn.unlock().await;
+    let reverted = reverted?;
+    unlocked?;
+    Ok(reverted)
+}
+
+async fn revert_locked(conn: &mut PgConnection, steps: usize) -> Result<Vec<Migration>, Error> {
+    conn.ensure_migrations_table().await?;
+    if let Some(version) = conn.dirty_version().await? {
+        return Err(MigrateError::Dirty(version).into());
+    }
+    let mut applied: Vec<i64> = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| migration.version)
+        .collect();
+    applied.sort_unstable_by(|a, b| b.cmp(a));
+    let mut reverted = Vec::new();
+    for version in applied.into_iter().take(steps) {
+        let down = MIGRATOR
+            .iter()
+            .find(|migration| migration.version == version && migration.migration_type.is_down_migration())
+            .ok_or(MigrateError::VersionMissing(version))?;
+        // a failing down migration leaves both the schema and the record as they were
+        let mut tx = conn.begin().await?;
+        (&mut tx).execute(&*down.sql).await?;
+        sqlx::query("DELETE FROM _sqlx_migrations WHERE version = $1")
+            .bind(version)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        reverted.push(down.clone());
+    }
+    Ok(reverted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    /// Up migrations from `version` on, which reverting that many steps undoes.
+    fn steps_since(version: i64) -> usize {
+        MIGRATOR
+            .iter()
+            .filter(|migration| !migration.migration_type.is_down_migration() && migration.version >= version)
+            .count()
+    }
+
+    const ADMIN_MIGRATION: i64 = 20210513090000;
+
+    #[actix_rt::test]
+    async fn test_revert() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let dbpool = &db.pgpool;
+        let table_exists = || {
+            sqlx::query_scalar::<_, bool>("SELECT to_regclass('card_rating_adjustments') IS NOT NULL")
+                .fetch_one(dbpool)
+        };
+        let latest = MIGRATOR.iter().map(|migration| migration.version).max().unwrap();
+        assert!(table_exists().await.unwrap());
+
+        let reverted = revert(dbpool, 1).await.unwrap();
+        assert_eq!(reverted.iter().map(|migration| migration.version).collect::<Vec<_>>(), vec![latest]);
+        let reverted = revert(dbpool, steps_since(ADMIN_MIGRATION) - 1).await.unwrap();
+        assert_eq!(reverted.last().unwrap().version, ADMIN_MIGRATION);
+        assert!(!table_exists().await.unwrap());
+
+        run(dbpool).await.unwrap();
+        assert!(table_exists().await.unwrap());
+
+        // every down migration works, all the way to an empty database
+        assert_eq!(revert(dbpool, usize::MAX).await.unwrap().len(), steps_since(0));
+        assert!(revert(dbpool, 1).await.unwrap().is_empty());
+        run(dbpool).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_revert_failure() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let dbpool = &db.pgpool;
+
+        // the admin down migration drops its table, then fails on the missing index
+        sqlx::query("DROP INDEX users_email_trgm_idx").execute(dbpool).await.unwrap();
+        assert!(revert(dbpool, steps_since(ADMIN_MIGRATION)).await.is_err());
+        let (table_exists, applied) = sqlx::query_as::<_, (bool, bool)>(
+            "SELECT to_regclass('card_rating_adjustments') IS NOT NULL, EXISTS (SELECT 1 FROM _sqlx_migrations WHERE version = $1)",
+        )
+        .bind(ADMIN_MIGRATION)
+        .fetch_one(dbpool)
+        .await
+        .unwrap();
+        assert!(table_exists && applied);
+
+        // and the lock was let go
+        sqlx::query("CREATE INDEX users_email_trgm_idx ON users USING GIN (email gin_trgm_ops)")
+            .execute(dbpool)
+            .await
+            .unwrap();
+        assert_eq!(revert(dbpool, 1).await.unwrap()[0].version, ADMIN_MIGRATION);
+    }
+
+    /// Databases migrated before the migrations became reversible recorded the base one from
+    /// `20210430133758_base.sql`. sqlx 0.5.2 matches applied migrations by version and by a
+    /// checksum of the SQL alone, so the rename to `.up.sql` keeps it applied.
+    #[actix_rt::test]
+    async fn test_base_migration_rename() {
+        use sha2::{Digest, Sha384};
+
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let dbpool = &db.pgpool;
+        revert(dbpool, usize::MAX).await.unwrap();
+
+        // what running the old `.sql` file left behind
+        let base = include_str!("../migrations/20210430133758_base.up.sql");
+        dbpool.execute(base).await.unwrap();
+        sqlx::query("INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) VALUES ($1, 'base', TRUE, $2, 0)")
+            .bind(20210430133758i64)
+            .bind(Sha384::digest(base.as_bytes()).to_vec())
+            .execute(dbpool)
+            .await
+            .unwrap();
+
+        // running the base migration again would fail on its existing tables
+        run(dbpool).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_ownership_backfill() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let dbpool = &db.pgpool;
+        revert(dbpool, steps_since(20210516090000)).await.unwrap();
+
+        let owner_id = uuid::Uuid::new_v4();
+        let (card_id, owned_at): (uuid::Uuid, chrono::DateTime<chrono::Utc>) =
+            sqlx::query_as("INSERT INTO cards (owner_id, owned_at) VALUES ($1, NOW() - INTERVAL '3 days') RETURNING id, owned_at")
+                .bind(owner_id)
+                .fetch_one(dbpool)
+                .await
+                .unwrap();
+        sqlx::query("INSERT INTO cards (owner_id, owned_at) VALUES (NULL, NOW())")
+            .execute(dbpool)
+            .await
+            .unwrap();
+        run(dbpool).await.unwrap();
+
+        let rows: Vec<(uuid::Uuid, Option<uuid::Uuid>, String, chrono::DateTime<chrono::Utc>)> =
+            sqlx::query_as("SELECT card_id, new_owner_id, reason::TEXT, created_at FROM card_ownerships")
+                .fetch_all(dbpool)
+                .await
+                .unwrap();
+        assert_eq!(rows, vec![(card_id, Some(owner_id), "mint".to_string(), owned_at)]);
+    }
+}
@@ -1,19 +1,33 @@
-use crate::error;
-use crate::session::{create_session, Session};
+use crate::account;
+use crate::admin::{self, RatingAdjustment};
+use crate::error::{self, resolve, to_graphql_error};
+use crate::events::{self, RedisClient};
+use crate::lockout::{self, LockoutStatus};
+use crate::mailer::SharedMailer;
+use crate::matchmaking;
+use crate::oauth::{self, OauthClientCredentials, Scope};
+use crate::oidc::{self, Identity};
+use crate::permission::{self, Permission, PermissionGuard, Role};
+use crate::rating::{Outcome, Rating, SharedRatingSystem};
+use crate::session::{
+    create_session, list_sessions, remove_session, remove_user_sessions, revoke_session, RequestInfo,
+    Session, SessionInfo, SessionPolicy,
+};
+use crate::token::{consume_refresh_token, issue_tokens, JwtKeys, TokenPair};
 use crate::util::{hash_password, verify_password};
-//use crate::util::{hash_password, verify_password, create_jwt_token, create_jwt_token};
+use crate::validation::{self, FieldError, Validator};
 
 use async_graphql::{
     connection::{Connection, CursorType, Edge, EmptyFields},
-    Context, EmptySubscription, Enum, Error as GraphqlError, Object, Schema as GraphqlSchema,
-    SimpleObject,
+    Context, Enum, Error as GraphqlError, Object, Schema as GraphqlSchema, SimpleObject,
+    Subscription,
     validators::IntRange,
 };
-use chrono::{TimeZone, Utc};
+use chrono::Utc;
+use futures::Stream;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use lazy_static::lazy_static;
-pub use deadpool_redis::{Config as RedisConfig, Pool as RedisPool};
+pub use deadpool_redis::{Config as RedisConfig, Pool as RedisPool, PoolConfig as RedisPoolConfig};
 
 pub use error::Error;
 
@@ -21,35 +35,225 @@ type DateTime = chrono::DateTime<Utc>;
 pub type DbPool = sqlx::postgres::PgPool;
 pub type DbPoolOptions = sqlx::postgres::PgPoolOptions;
 
-lazy_static! {
-    static ref MAX_DATETIME: DateTime = Utc.ymd(9999, 1, 1).and_hms(0, 1, 1);
-    static ref MIN_DATETIME: DateTime = Utc.ymd(0, 1, 1).and_hms(0, 1, 1);
+/// Limits and costs that depend on the deployment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tuning {
+    /// Largest page of a connection, and the page size when neither `first` nor `last` is given.
+    pub max_page_size: i32,
+    pub password_hash_cost: u32,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning {
+            max_page_size: 100,
+            password_hash_cost: bcrypt::DEFAULT_COST,
+        }
+    }
 }
-const MAX_RATING: f64 = 999999999.0;
-const MIN_RATING: f64 = -999999999.0;
-const MAX_PAGE_SIZE: i32 = 100;
 
-pub fn create_redispool(url: &str) -> Result<RedisPool, Error> {
+pub fn create_redispool(url: &str, max_size: usize) -> Result<RedisPool, Error> {
     Ok(RedisConfig {
         url: Some(url.to_string()),
-        pool: None,
+        pool: Some(RedisPoolConfig::new(max_size)),
     }
     .create_pool()?)
 }
 
-#[derive(sqlx::FromRow, Clone, Debug, Deserialize, Serialize, PartialEq, SimpleObject)]
+/// Plain client for pub/sub. Pooled connections can not subscribe.
+pub fn create_redis_client(url: &str) -> Result<RedisClient, Error> {
+    Ok(RedisClient::open(url)?)
+}
+
+#[derive(sqlx::FromRow, Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct Card {
     pub id: Uuid,
+    pub title: String,
+    pub image_url: Option<String>,
     pub rating: f64,
+    pub rating_deviation: Option<f64>,
+    pub rating_volatility: Option<f64>,
     pub owned_at: DateTime,
     pub created_at: DateTime,
     pub owner_id: Option<Uuid>,
 }
 
+impl Card {
+    /// The card if the session may act as its owner.
+    async fn fetch_owned(dbpool: &DbPool, session: &Session, id: Uuid) -> Result<Card, Error> {
+        let card = sqlx::query_as::<_, Card>("SELECT * FROM cards WHERE id = $1")
+            .bind(id)
+            .fetch_one(dbpool)
+            .await?;
+        if card.owner_id == Some(session.user_id) || session.has(Permission::CardsManage) {
+            Ok(card)
+        } else {
+            Err(Error::NotAuthorized)
+        }
+    }
+    pub fn to_rating(&self) -> Rating {
+        Rating {
+            rating: self.rating,
+            deviation: self.rating_deviation,
+            volatility: self.rating_volatility,
+        }
+    }
+    /// Change the owner and append to the ownership history in one transaction. With
+    /// `owner_id`, only while the card still belongs to that user.
+    async fn change_owner(
+        dbpool: &DbPool,
+        id: Uuid,
+        owner_id: Option<Uuid>,
+        new_owner_id: Option<Uuid>,
+        reason: OwnershipReason,
+    ) -> Result<Card, Error> {
+        let mut tx = dbpool.begin().await?;
+        let previous_owner_id = sqlx::query_scalar::<_, Option<Uuid>>("SELECT owner_id FROM cards WHERE id = $1 FOR UPDATE")
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+        let card = sqlx::query_as::<_, Card>(
+            "UPDATE cards SET owner_id = $2, owned_at = NOW() WHERE id = $1 AND ($3::UUID IS NULL OR owner_id = $3) RETURNING *")
+            .bind(id)
+            .bind(new_owner_id)
+            .bind(owner_id)
+            .fetch_optional(&mut tx)
+            .await?
+            .ok_or(Error::NotAuthorized)?;
+        sqlx::query("INSERT INTO card_ownerships (card_id, previous_owner_id, new_owner_id, reason) VALUES ($1, $2, $3, $4)")
+            .bind(id)
+            .bind(previous_owner_id)
+            .bind(new_owner_id)
+            .bind(reason)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(card)
+    }
+}
+
+#[Object]
+impl Card {
+    async fn id(&self) -> Uuid {
+        self.id
+    }
+    async fn title(&self) -> &str {
+        &self.title
+    }
+    async fn image_url(&self) -> Option<&str> {
+        self.image_url.as_deref()
+    }
+    async fn rating(&self) -> f64 {
+        self.rating
+    }
+    /// How uncertain the rating is. Null when the rating system does not track it.
+    async fn rating_deviation(&self) -> Option<f64> {
+        self.rating_deviation
+    }
+    async fn owned_at(&self) -> &DateTime {
+        &self.owned_at
+    }
+    async fn created_at(&self) -> &DateTime {
+        &self.created_at
+    }
+    async fn owner_id(&self) -> Option<Uuid> {
+        self.owner_id
+    }
+    /// Every change of owner, oldest first.
+    async fn ownership_history(
+        &self,
+        ctx: &Context<'_>,
+        after: Option<String>,
+        before: Option<String>,
+        #[graphql(validator(IntRange(min = "0", max = "100")))]
+        first: Option<i32>,
+        #[graphql(desc = "last N items. clamped by [0-100]")] last: Option<i32>,
+    ) -> Result<Connection<OwnershipCursor, CardOwnership, EmptyFields, EmptyFields>, GraphqlError> {
+        let fail = |err: GraphqlError| to_graphql_error(ctx, err.into());
+        let dbpool = ctx.data::<DbPool>().map_err(fail)?;
+        let tuning = ctx.data::<Tuning>().map_err(fail)?;
+        let (first, last) = page_limits(tuning.max_page_size, "ownershipHistory", first, last)
+            .map_err(|err| to_graphql_error(ctx, err))?;
+        async_graphql::connection::query(after, before, first, last, |after, before, first, last| resolve(ctx, async move {
+            let (sql_sorting, limit) = match (first, last) {
+                (Some(limit), None) => ("ASC", limit as i32),
+                (None, Some(limit)) => ("DESC", limit as i32),
+                _ => ("ASC", tuning.max_page_size),
+            };
+            let (after, after_id) = after.map_or((None, None), |OwnershipCursor(at, id)| (Some(at), Some(id)));
+            let (before, before_id) = before.map_or((None, None), |OwnershipCursor(at, id)| (Some(at), Some(id)));
+            let mut ownerships = sqlx::query_as::<_, CardOwnership>(&format!(
+                "SELECT * FROM card_ownerships WHERE card_id = $1 AND ($2::timestamptz IS NULL OR (created_at, id) > ($2, $3)) AND ($4::timestamptz IS NULL OR (created_at, id) < ($4, $5)) ORDER BY created_at {sort}, id {sort} LIMIT $6 + 1",
+                sort = sql_sorting))
+                .bind(self.id)
+                .bind(after)
+                .bind(after_id)
+                .bind(before)
+                .bind(before_id)
+                .bind(limit)
+                .fetch_all(dbpool)
+                .await?;
+            let mut connection = Connection::new(
+                last.filter(|limit| limit < &ownerships.len()).is_some(),
+                first.filter(|limit| limit < &ownerships.len()).is_some(),
+                );
+            ownerships.truncate(last.or(first).unwrap_or(tuning.max_page_size as usize));
+            if sql_sorting == "DESC" {
+                ownerships.reverse();
+            }
+            connection.append(
+                ownerships.into_iter().map(|ownership| Edge::new(OwnershipCursor(ownership.created_at, ownership.id), ownership))
+            );
+            Ok(connection)
+        })).await
+    }
+}
+
+#[derive(sqlx::Type, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Enum)]
+#[sqlx(type_name = "ownershipreason")]
+pub enum OwnershipReason {
+    #[sqlx(rename = "mint")]
+    Mint,
+    #[sqlx(rename = "transfer")]
+    Transfer,
+    #[sqlx(rename = "release")]
+    Release,
+    /// Changed by someone managing cards.
+    #[sqlx(rename = "admin")]
+    Admin,
+}
+
+#[derive(sqlx::FromRow, Clone, Debug, Deserialize, Serialize, PartialEq, SimpleObject)]
+pub struct CardOwnership {
+    pub id: Uuid,
+    pub card_id: Uuid,
+    /// None when the card was minted or had been released.
+    pub previous_owner_id: Option<Uuid>,
+    /// None when the card was released.
+    pub new_owner_id: Option<Uuid>,
+    pub reason: OwnershipReason,
+    pub created_at: DateTime,
+}
+
+/// Created at and id of a card ownership.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct OwnershipCursor(DateTime, Uuid);
+impl CursorType for OwnershipCursor {
+    type Error = error::Error;
+    fn decode_cursor(s: &str) -> Result<Self, Self::Error> {
+        Ok(bincode::deserialize(&base64::decode(s)?)?)
+    }
+    fn encode_cursor(&self) -> String {
+        base64::encode(bincode::serialize(&self).unwrap())
+    }
+}
+
+/// Sort value and card id. The id keeps cursors unique when sort values are equal.
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub enum CardCursor {
-    OwnedAt(DateTime),
-    Rating(f64),
+    OwnedAt(DateTime, Uuid),
+    /// Conservative rating and card id.
+    Rating(f64, Uuid),
 }
 impl CursorType for CardCursor {
     type Error = error::Error;
@@ -67,6 +271,138 @@ pub enum CardSort {
     Rating
 }
 
+/// Which cards a connection pages through and in which order.
+#[derive(Clone, Copy, Debug)]
+struct CardFilter {
+    /// All cards when None.
+    owner_id: Option<Uuid>,
+    sort: CardSort,
+    /// Highest first instead of lowest first.
+    descending: bool,
+}
+
+/// Page size from `first` and `last`, clamped to [0, max_page_size]. A full page forward
+/// when neither is given.
+fn page_limits(
+    max_page_size: i32,
+    method: &'static str,
+    first: Option<i32>,
+    last: Option<i32>,
+) -> Result<(Option<i32>, Option<i32>), Error> {
+    if first.is_some() && last.is_some() {
+        return Err(Error::BadRequest(method, "first or last, not both"));
+    }
+    let first = if first.is_none() && last.is_none() {
+        Some(max_page_size)
+    } else {
+        first
+    };
+    Ok((
+        first.map(|l| l.min(max_page_size).max(0)),
+        last.map(|l| l.min(max_page_size).max(0)),
+    ))
+}
+
+async fn query_cards(
+    ctx: &Context<'_>,
+    filter: CardFilter,
+    after: Option<String>,
+    before: Option<String>,
+    first: Option<i32>,
+    last: Option<i32>,
+) -> Result<Connection<CardCursor, Card, EmptyFields, EmptyFields>, GraphqlError> {
+    let fail = |err: GraphqlError| to_graphql_error(ctx, err.into());
+    let dbpool = ctx.data::<DbPool>().map_err(fail)?;
+    let rating_system = ctx.data::<SharedRatingSystem>().map_err(fail)?;
+    let tuning = ctx.data::<Tuning>().map_err(fail)?;
+    let (first, last) = page_limits(tuning.max_page_size, "cards", first, last)
+        .map_err(|err| to_graphql_error(ctx, err))?;
+    async_graphql::connection::query(after, before, first, last, |after, before, first, last| resolve(ctx, async move {
+        let (backward, limit) = match (first, last) {
+            (Some(limit), None) => (false, limit as i32),
+            (None, Some(limit)) => (true, limit as i32),
+            _ => (false, tuning.max_page_size),
+        };
+        let sql_sorting = if backward == filter.descending { "ASC" } else { "DESC" };
+        let (after_op, before_op) = if filter.descending { ("<", ">") } else { (">", "<") };
+        let mismatch = || Error::BadRequest("cards", "sort format and cursor type not match");
+        let mut cards = match filter.sort {
+            CardSort::OwnedAt => {
+                let (after, after_id) = match after {
+                    Some(CardCursor::OwnedAt(owned_at, id)) => (Some(owned_at), Some(id)),
+                    Some(_) => return Err(mismatch()),
+                    None => (None, None),
+                };
+                let (before, before_id) = match before {
+                    Some(CardCursor::OwnedAt(owned_at, id)) => (Some(owned_at), Some(id)),
+                    Some(_) => return Err(mismatch()),
+                    None => (None, None),
+                };
+                sqlx::query_as::<_, Card>(&format!(
+                    "SELECT * FROM cards WHERE ($1::uuid IS NULL OR owner_id = $1) AND ($2::timestamptz IS NULL OR (owned_at, id) {} ($2, $3)) AND ($4::timestamptz IS NULL OR (owned_at, id) {} ($4, $5)) ORDER BY owned_at {sort}, id {sort} LIMIT $6 + 1",
+                    after_op, before_op, sort = sql_sorting))
+                    .bind(filter.owner_id)
+                    .bind(after)
+                    .bind(after_id)
+                    .bind(before)
+                    .bind(before_id)
+                    .bind(limit)
+                    .fetch_all(dbpool)
+                    .await?
+            }
+            CardSort::Rating => {
+                let (after, after_id) = match after {
+                    Some(CardCursor::Rating(rating, id)) => (Some(rating), Some(id)),
+                    Some(_) => return Err(mismatch()),
+                    None => (None, None),
+                };
+                let (before, before_id) = match before {
+                    Some(CardCursor::Rating(rating, id)) => (Some(rating), Some(id)),
+                    Some(_) => return Err(mismatch()),
+                    None => (None, None),
+                };
+                // Sort on the conservative estimate. Cards that have not battled yet have the initial deviation.
+                let key = "rating - $2 * COALESCE(rating_deviation, $8)";
+                sqlx::query_as::<_, Card>(&format!(
+                    "SELECT * FROM cards WHERE ($1::uuid IS NULL OR owner_id = $1) AND ($3::float8 IS NULL OR ({key}, id) {} ($3, $4)) AND ($5::float8 IS NULL OR ({key}, id) {} ($5, $6)) ORDER BY {key} {sort}, id {sort} LIMIT $7 + 1",
+                    after_op, before_op, key = key, sort = sql_sorting))
+                    .bind(filter.owner_id)
+                    .bind(rating_system.conservative_factor())
+                    .bind(after)
+                    .bind(after_id)
+                    .bind(before)
+                    .bind(before_id)
+                    .bind(limit)
+                    .bind(rating_system.initial_deviation())
+                    .fetch_all(dbpool)
+                    .await?
+            }
+        };
+        let mut connection = Connection::new(
+            last.filter(|limit| limit < &cards.len()).is_some(),
+            first.filter(|limit| limit < &cards.len()).is_some(),
+            );
+        cards.truncate(last.or(first).unwrap_or(tuning.max_page_size as usize));
+        if backward {
+            cards.reverse();
+        }
+
+        match filter.sort {
+            CardSort::OwnedAt => {
+                connection.append(
+                    cards.into_iter().map(|card| Edge::new(CardCursor::OwnedAt(card.owned_at, card.id), card))
+                );
+            }
+            CardSort::Rating => {
+                connection.append(
+                    cards.into_iter().map(|card| Edge::new(CardCursor::Rating(rating_system.conservative(&card.to_rating()), card.id), card))
+                );
+            }
+        };
+        Ok(connection)
+    })).await
+}
+
 #[derive(sqlx::Type, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Enum)]
 #[sqlx(type_name = "userkind")]
 pub enum UserKind {
@@ -76,6 +412,126 @@ pub enum UserKind {
     Normal,
 }
 
+#[derive(sqlx::Type, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Enum)]
+#[sqlx(type_name = "battlestate")]
+pub enum BattleState {
+    /// Started by the challenger, waiting for an opponent to be chosen and to accept.
+    #[sqlx(rename = "pending")]
+    Pending,
+    /// Both sides agreed to battle. Waiting for them to report the same result.
+    #[sqlx(rename = "active")]
+    Active,
+    #[sqlx(rename = "finished")]
+    Finished,
+    #[sqlx(rename = "abandoned")]
+    Abandoned,
+}
+
+/// Result of a battle as one side reports it.
+#[derive(sqlx::Type, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Enum)]
+#[sqlx(type_name = "battleresult")]
+pub enum BattleResult {
+    #[sqlx(rename = "challenger")]
+    ChallengerWon,
+    #[sqlx(rename = "opponent")]
+    OpponentWon,
+    #[sqlx(rename = "draw")]
+    Draw,
+}
+
+impl BattleResult {
+    fn outcome(self) -> Outcome {
+        match self {
+            BattleResult::ChallengerWon => Outcome::Win,
+            BattleResult::OpponentWon => Outcome::Loss,
+            BattleResult::Draw => Outcome::Draw,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow, Clone, Debug, Deserialize, Serialize, PartialEq, SimpleObject)]
+pub struct Battle {
+    pub id: Uuid,
+    pub state: BattleState,
+    pub challenger_id: Uuid,
+    pub challenger_card_id: Uuid,
+    pub opponent_id: Option<Uuid>,
+    pub opponent_card_id: Option<Uuid>,
+    /// None on a draw or while the battle is not finished.
+    pub winner_card_id: Option<Uuid>,
+    pub created_at: DateTime,
+    pub started_at: Option<DateTime>,
+    pub finished_at: Option<DateTime>,
+    pub challenger_rating_before: Option<f64>,
+    pub challenger_rating_after: Option<f64>,
+    pub opponent_rating_before: Option<f64>,
+    pub opponent_rating_after: Option<f64>,
+    /// What each side reported. None until they do.
+    pub challenger_report: Option<BattleResult>,
+    pub opponent_report: Option<BattleResult>,
+}
+
+impl Battle {
+    async fn fetch(dbpool: &DbPool, id: Uuid) -> Result<Battle, Error> {
+        Ok(sqlx::query_as::<_, Battle>("SELECT * FROM battles WHERE id = $1")
+            .bind(id)
+            .fetch_one(dbpool)
+            .await?)
+    }
+    /// Whether the card is already in a pending or active battle.
+    async fn is_card_busy<'e>(executor: impl sqlx::PgExecutor<'e>, card_id: Uuid) -> Result<bool, Error> {
+        Ok(sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS (SELECT 1 FROM battles WHERE state IN ('pending', 'active') AND (challenger_card_id = $1 OR opponent_card_id = $1))")
+            .bind(card_id)
+            .fetch_one(executor)
+            .await?)
+    }
+}
+
+/// The unique indexes on live battles turn away a card that got into one concurrently.
+fn card_busy_error(method: &'static str) -> impl FnOnce(sqlx::Error) -> Error {
+    move |err| match &err {
+        sqlx::Error::Database(db_err) if db_err.code().as_deref() == Some("23505") => {
+            Error::BadRequest(method, "card is already in a battle")
+        }
+        _ => Error::Database(err),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, SimpleObject)]
+pub struct QueueStatus {
+    pub card_id: Uuid,
+    /// Place in the matchmaking queue. 0 is the longest waiting card. Null when not queued.
+    pub position: Option<i32>,
+    /// Battle started right away when an opponent was waiting.
+    pub battle: Option<Battle>,
+}
+
+fn session<'a>(ctx: &Context<'a>) -> Result<&'a Session, Error> {
+    ctx.data_opt::<Session>().ok_or(Error::NotAuthorized)
+}
+
+/// Session of the request, as long as a third-party app acting through it was granted the scope.
+fn scoped_session<'a>(ctx: &Context<'a>, scope: Scope) -> Result<&'a Session, Error> {
+    let session = session(ctx)?;
+    if session.allows(scope) {
+        Ok(session)
+    } else {
+        Err(Error::NotAuthorized)
+    }
+}
+
+/// Session of the request, unless a third-party app is acting through it. Managing the
+/// account is left to the player.
+fn own_session<'a>(ctx: &Context<'a>) -> Result<&'a Session, Error> {
+    let session = session(ctx)?;
+    if session.scopes.is_none() {
+        Ok(session)
+    } else {
+        Err(Error::NotAuthorized)
+    }
+}
+
 #[derive(sqlx::FromRow, Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct User {
     pub id: Uuid,
@@ -86,6 +542,83 @@ pub struct User {
     pub email: String,
     pub nickname: String,
     pub created_at: DateTime,
+    pub email_verified_at: Option<DateTime>,
+}
+
+lazy_static::lazy_static! {
+    /// Checked when there is no password to check, so unknown emails take as long to refuse
+    /// as wrong passwords. One per cost, made on first use, as the cost decides the time.
+    static ref DUMMY_PASSWORD_HASHES: std::sync::Mutex<std::collections::HashMap<u32, String>> =
+        Default::default();
+}
+
+fn dummy_password_hash(cost: u32) -> Result<String, Error> {
+    let mut hashes = DUMMY_PASSWORD_HASHES.lock().unwrap();
+    if let Some(hash) = hashes.get(&cost) {
+        return Ok(hash.clone());
+    }
+    let hash = hash_password("no one has this password".to_string(), cost)?;
+    hashes.insert(cost, hash.clone());
+    Ok(hash)
+}
+
+impl User {
+    /// Look up the user by email and check the password.
+    async fn authenticate(
+        dbpool: &DbPool,
+        email: &str,
+        password: &str,
+        password_hash_cost: u32,
+    ) -> Result<User, Error> {
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE lower(email) = lower($1)")
+            .bind(email)
+            .fetch_optional(dbpool)
+            .await?;
+        // accounts signed up through an identity provider have no password
+        let user = match user {
+            Some(user) if !user.password.is_empty() => user,
+            _ => {
+                verify_password(password, &dummy_password_hash(password_hash_cost)?)?;
+                return Err(Error::WrongPassword);
+            }
+        };
+        if verify_password(password, &user.password)? {
+            Ok(user)
+        } else {
+            Err(Error::WrongPassword)
+        }
+    }
+    /// `authenticate`, refused while the email or the client ip is locked out. Every bad
+    /// attempt makes the next wait longer.
+    async fn log_in(ctx: &Context<'_>, email: &str, password: &str) -> Result<User, Error> {
+        let dbpool = ctx.data::<DbPool>()?;
+        let mut redis_conn = ctx.data::<RedisPool>()?.get().await?;
+        let ip = ctx.data_opt::<RequestInfo>().and_then(|info| info.ip.as_deref());
+        lockout::check(&mut redis_conn, email, ip).await?;
+        let password_hash_cost = ctx.data::<Tuning>()?.password_hash_cost;
+        match User::authenticate(dbpool, email, password, password_hash_cost).await {
+            Ok(user) => {
+                lockout::clear(&mut redis_conn, email).await?;
+                Ok(user)
+            }
+            Err(Error::WrongPassword) => {
+                lockout::record_failure(&mut redis_conn, email, ip).await?;
+                Err(Error::WrongPassword)
+            }
+            Err(err) => Err(err),
+        }
+    }
+    /// Private fields are for the user themselves and those who may read any profile.
+    fn check_profile_access(&self, ctx: &Context<'_>) -> Result<(), Error> {
+        let session = session(ctx)?;
+        if (session.user_id == self.id || session.has(Permission::ProfileReadAny))
+            && session.allows(Scope::ProfileRead)
+        {
+            Ok(())
+        } else {
+            Err(Error::NotAuthorized)
+        }
+    }
 }
 
 #[Object]
@@ -104,14 +637,36 @@ impl User {
     }
     /// Email addr. Not fetchable by other users.
     async fn email(&self, ctx: &Context<'_>) -> Result<&str, GraphqlError> {
-        let session: &Session = ctx
-            .data_opt::<Session>()
-            .ok_or_else(|| GraphqlError::from(Error::NotAuthorized))?;
-        if session.user_id == self.id || session.user_kind == UserKind::Super {
+        resolve(ctx, async move {
+            self.check_profile_access(ctx)?;
             Ok(&self.email)
-        } else {
-            Err(GraphqlError::from(Error::NotAuthorized))
-        }
+        })
+        .await
+    }
+    /// When the email addr was verified. Null until then. Not fetchable by other users.
+    async fn email_verified_at(&self, ctx: &Context<'_>) -> Result<Option<&DateTime>, GraphqlError> {
+        resolve(ctx, async move {
+            self.check_profile_access(ctx)?;
+            Ok(self.email_verified_at.as_ref())
+        })
+        .await
+    }
+    /// Failed logins and lockout of the account.
+    #[graphql(guard(PermissionGuard(permission = "Permission::LockoutRead")))]
+    async fn login_lockout(&self, ctx: &Context<'_>) -> Result<LockoutStatus, GraphqlError> {
+        resolve(ctx, async move {
+            let mut redis_conn = ctx.data::<RedisPool>()?.get().await?;
+            lockout::status(&mut redis_conn, &self.email).await
+        })
+        .await
+    }
+    /// Names of the roles of the user. Not fetchable by other users.
+    async fn roles(&self, ctx: &Context<'_>) -> Result<Vec<String>, GraphqlError> {
+        resolve(ctx, async move {
+            self.check_profile_access(ctx)?;
+            permission::user_roles(ctx.data::<DbPool>()?, self.id).await
+        })
+        .await
     }
     /// Cards owned by the user.
     async fn cards(
@@ -124,74 +679,12 @@ impl User {
         first: Option<i32>,
         #[graphql(desc = "last N items. clamped by [0-100]")] last: Option<i32>,
     ) -> Result<Connection<CardCursor, Card, EmptyFields, EmptyFields>, GraphqlError> {
-        let dbpool = ctx.data::<DbPool>()?;
-        if first.is_some() && last.is_some() {
-            return Err(Error::BadRequest("cards", "first or last, not both").into());
-        }
-        let first = if first.is_none() && last.is_none() {
-            Some(MAX_PAGE_SIZE)
-        } else {
-            first
+        let filter = CardFilter {
+            owner_id: Some(self.id),
+            sort: sort.unwrap_or(CardSort::OwnedAt),
+            descending: false,
         };
-        let first = first.map(|l| l.min(MAX_PAGE_SIZE).max(0));
-        let last = last.map(|l| l.min(MAX_PAGE_SIZE).max(0));
-        let sort = sort.unwrap_or(CardSort::OwnedAt);
-        async_graphql::connection::query(after, before, first, last, |after, before, first, last| async move {
-            let (after, before) = match sort {
-                CardSort::OwnedAt => (after.unwrap_or(CardCursor::OwnedAt(MIN_DATETIME.clone())), before.unwrap_or(CardCursor::OwnedAt(MAX_DATETIME.clone()))),
-                CardSort::Rating => (after.unwrap_or(CardCursor::Rating(MIN_RATING)), before.unwrap_or(CardCursor::Rating(MAX_RATING))),
-            };
-            let (sql_sorting, limit) = match (first, last) {
-                (Some(limit), None) => ("ASC", limit as i32),
-                (None, Some(limit)) => ("DESC", limit as i32),
-                _ => ("ASC", MAX_PAGE_SIZE),
-            };
-            let mut cards = match (sort, after, before) {
-                (CardSort::OwnedAt, CardCursor::OwnedAt(after), CardCursor::OwnedAt(before)) => {
-                    sqlx::query_as::<_, Card>(&format!("SELECT * FROM cards WHERE owner_id = $1 AND owned_at > $2 AND owned_at < $3 ORDER BY owned_at {} LIMIT $4 + 1", sql_sorting))
-                        .bind(self.id)
-                        .bind(after)
-                        .bind(before)
-                        .bind(limit)
-                        .fetch_all(dbpool)
-                        .await?
-                }
-                (CardSort::Rating, CardCursor::Rating(after), CardCursor::Rating(before)) => {
-                    sqlx::query_as::<_, Card>(&format!("SELECT * FROM cards WHERE owner_id = $1 AND rating > $2 AND rating < $3 ORDER BY rating {} LIMIT $4 + 1", sql_sorting))
-                        .bind(self.id)
-                        .bind(after)
-                        .bind(before)
-                        .bind(limit)
-                        .fetch_all(dbpool)
-                        .await?
-                }
-                _ => {
-                    return Err(Error::BadRequest("cards", "sort format and cursor type not match").into());
-                }
-            };
-            let mut connection = Connection::new(
-                last.filter(|limit| limit < &cards.len()).is_some(),
-                first.filter(|limit| limit < &cards.len()).is_some(),
-                );
-            cards.truncate(last.or(first).unwrap_or(MAX_PAGE_SIZE as usize));
-            if sql_sorting == "DESC" {
-                cards.reverse();
-            }
-            
-            match sort {
-                CardSort::OwnedAt => {
-                    connection.append(
-                        cards.into_iter().map(|card| Edge::new(CardCursor::OwnedAt(card.owned_at), card))
-                    );
-                }
-                CardSort::Rating => {
-                    connection.append(
-                        cards.into_iter().map(|card| Edge::new(CardCursor::Rating(card.rating), card))
-                    );
-                }
-            };
-            Ok(connection)
-        }).await
+        query_cards(ctx, filter, after, before, first, last).await
     }
 }
 
@@ -199,6 +692,8 @@ pub struct Mutation;
 
 #[Object]
 impl Mutation {
+    /// Sign up and start a session. Invalid arguments fail with a `fields` error extension
+    /// listing each problem, as in `[{field: "email", message: "is already registered"}]`.
     async fn register(
         &self,
         ctx: &Context<'_>,
@@ -206,16 +701,90 @@ impl Mutation {
         password: String,
         nickname: String,
     ) -> Result<Uuid, GraphqlError> {
-        let dbpool = ctx.data::<DbPool>()?;
-        let user = sqlx::query_as::<_, User>(
-            "INSERT INTO users (email, password, nickname) VALUES ($1, $2, $3) RETURNING id, password, kind, email, nickname, created_at")
-            .bind(email)
-            .bind(hash_password(password)?)
-            .bind(nickname)
-            .fetch_one(dbpool)
-            .await?;
-        create_session(&ctx, &user).await?;
-        Ok(user.id)
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let email = email.trim();
+            Validator::default()
+                .check("email", validation::email(email))
+                .check("password", validation::password(&password))
+                .check("nickname", validation::nickname(&nickname))
+                .finish()?;
+            // emails are unique regardless of case
+            let user = sqlx::query_as::<_, User>(
+                "INSERT INTO users (email, password, nickname) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING RETURNING *")
+                .bind(email)
+                .bind(hash_password(password, ctx.data::<Tuning>()?.password_hash_cost)?)
+                .bind(nickname)
+                .fetch_optional(dbpool)
+                .await?
+                .ok_or_else(|| Error::Validation(vec![FieldError::new("email", "is already registered")]))?;
+            create_session(&ctx, &user).await?;
+            let mut redis_conn = ctx.data::<RedisPool>()?.get().await?;
+            account::send_verification_email(&mut redis_conn, ctx.data::<SharedMailer>()?, &user).await?;
+            Ok(user.id)
+        })
+        .await
+    }
+    /// Mail the current user a new email verification code.
+    async fn resend_verification_email(&self, ctx: &Context<'_>) -> Result<bool, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let session = own_session(ctx)?;
+            let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+                .bind(session.user_id)
+                .fetch_one(dbpool)
+                .await?;
+            if user.email_verified_at.is_some() {
+                return Err(Error::BadRequest("resendVerificationEmail", "email is already verified"));
+            }
+            let mut redis_conn = ctx.data::<RedisPool>()?.get().await?;
+            account::send_verification_email(&mut redis_conn, ctx.data::<SharedMailer>()?, &user).await?;
+            Ok(true)
+        })
+        .await
+    }
+    /// Verify an email addr with the code mailed to it. Returns false for unknown or used codes.
+    async fn verify_email(&self, ctx: &Context<'_>, token: String) -> Result<bool, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let mut redis_conn = ctx.data::<RedisPool>()?.get().await?;
+            account::verify_email(dbpool, &mut redis_conn, &token).await
+        })
+        .await
+    }
+    /// Mail a password reset code. Always returns true, whether or not the addr is registered.
+    async fn request_password_reset(&self, ctx: &Context<'_>, email: String) -> Result<bool, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let mut redis_conn = ctx.data::<RedisPool>()?.get().await?;
+            account::request_password_reset(dbpool, &mut redis_conn, ctx.data::<SharedMailer>()?, &email).await?;
+            Ok(true)
+        })
+        .await
+    }
+    /// Set a new password with a mailed reset code. Every session of the user is ended.
+    /// Returns false for unknown or used codes.
+    async fn reset_password(
+        &self,
+        ctx: &Context<'_>,
+        token: String,
+        new_password: String,
+    ) -> Result<bool, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            Validator::default()
+                .check("newPassword", validation::password(&new_password))
+                .finish()?;
+            let mut redis_conn = ctx.data::<RedisPool>()?.get().await?;
+            match account::reset_password(dbpool, &mut redis_conn, &token, new_password, ctx.data::<Tuning>()?.password_hash_cost).await? {
+                Some(user_id) => {
+                    remove_user_sessions(ctx, user_id).await?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        })
+        .await
     }
     async fn login(
         &self,
@@ -223,26 +792,519 @@ impl Mutation {
         email: String,
         password: String,
     ) -> Result<Uuid, GraphqlError> {
-        let dbpool = ctx.data::<DbPool>()?;
-        //let redis = ctx.data::<RedisPool>()?.get().await?;
-        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
-            .bind(email)
-            .fetch_one(dbpool)
-            .await?;
-        if !verify_password(&password, &user.password)? {
-            Err(GraphqlError::from(Error::WrongPassword))
-        } else {
+        resolve(ctx, async move {
+            let user = User::log_in(ctx, &email, &password).await?;
             create_session(&ctx, &user).await?;
             Ok(user.id)
-        }
+        })
+        .await
+    }
+    /// Log in without a cookie session, for clients that send `Authorization: Bearer` instead.
+    async fn login_token(
+        &self,
+        ctx: &Context<'_>,
+        email: String,
+        password: String,
+    ) -> Result<TokenPair, GraphqlError> {
+        resolve(ctx, async move {
+            let user = User::log_in(ctx, &email, &password).await?;
+            let mut redis_conn = ctx.data::<RedisPool>()?.get().await?;
+            issue_tokens(ctx.data::<DbPool>()?, &mut redis_conn, ctx.data::<JwtKeys>()?, &user, None).await
+        })
+        .await
+    }
+    /// Trade a refresh token for a new token pair. The given refresh token stops working.
+    async fn refresh_token(
+        &self,
+        ctx: &Context<'_>,
+        refresh_token: String,
+    ) -> Result<TokenPair, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let mut redis_conn = ctx.data::<RedisPool>()?.get().await?;
+            let keys = ctx.data::<JwtKeys>()?;
+            let (user_id, grant) = consume_refresh_token(&mut redis_conn, keys, &refresh_token).await?;
+            let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+                .bind(user_id)
+                .fetch_one(dbpool)
+                .await?;
+            issue_tokens(dbpool, &mut redis_conn, keys, &user, grant.as_ref()).await
+        })
+        .await
+    }
+    /// End the current session. Returns false when there was no session.
+    async fn logout(&self, ctx: &Context<'_>) -> Result<bool, GraphqlError> {
+        resolve(ctx, async move {
+            Ok(remove_session(ctx).await?.is_some())
+        })
+        .await
+    }
+    /// End every session of the current user, on all devices, and revoke their refresh
+    /// tokens. Returns how many sessions were ended.
+    async fn logout_all_devices(&self, ctx: &Context<'_>) -> Result<i32, GraphqlError> {
+        resolve(ctx, async move {
+            let session = own_session(ctx)?;
+            Ok(remove_user_sessions(ctx, session.user_id).await? as i32)
+        })
+        .await
     }
-    /*async fn start_battle(
+    /// Register a third-party app that players can then authorize through `/oauth/authorize`.
+    /// Redirect uris must be https, or http on the loopback address.
+    async fn register_oauth_client(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        redirect_uris: Vec<String>,
+        scopes: Vec<Scope>,
+    ) -> Result<OauthClientCredentials, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let session = own_session(ctx)?;
+            if redirect_uris.is_empty() {
+                return Err(Error::BadRequest("registerOauthClient", "at least one redirect uri is required"));
+            }
+            let mut validator = Validator::default();
+            for uri in redirect_uris.iter() {
+                validator.check("redirectUris", validation::redirect_uri(uri));
+            }
+            validator.finish()?;
+            oauth::register_client(dbpool, session.user_id, name, redirect_uris, scopes).await
+        })
+        .await
+    }
+    /// Unlink an identity provider from the current user. Returns false when it was not linked.
+    async fn unlink_identity(&self, ctx: &Context<'_>, provider: String) -> Result<bool, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let session = own_session(ctx)?;
+            oidc::unlink_identity(dbpool, session.user_id, &provider).await
+        })
+        .await
+    }
+    /// End one session of the current user, as listed by `mySessions`.
+    async fn revoke_session(&self, ctx: &Context<'_>, handle: Uuid) -> Result<bool, GraphqlError> {
+        resolve(ctx, async move {
+            let session = own_session(ctx)?;
+            revoke_session(ctx, session.user_id, handle).await
+        })
+        .await
+    }
+    /// Mint a card for the caller, or for someone else.
+    #[graphql(guard(PermissionGuard(permission = "Permission::CardsManage")))]
+    async fn create_card(
+        &self,
+        ctx: &Context<'_>,
+        title: String,
+        image_url: Option<String>,
+        owner_id: Option<Uuid>,
+    ) -> Result<Card, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let session = scoped_session(ctx, Scope::CardsWrite)?;
+            let owner_id = owner_id.unwrap_or(session.user_id);
+            let mut tx = dbpool.begin().await?;
+            let card = sqlx::query_as::<_, Card>("INSERT INTO cards (title, image_url, owner_id, owned_at) VALUES ($1, $2, $3, NOW()) RETURNING *")
+                .bind(title)
+                .bind(image_url)
+                .bind(owner_id)
+                .fetch_one(&mut tx)
+                .await?;
+            sqlx::query("INSERT INTO card_ownerships (card_id, new_owner_id, reason) VALUES ($1, $2, 'mint')")
+                .bind(card.id)
+                .bind(owner_id)
+                .execute(&mut tx)
+                .await?;
+            tx.commit().await?;
+            Ok(card)
+        })
+        .await
+    }
+    /// Give the card to another user.
+    #[graphql(guard(PermissionGuard(permission = "Permission::Play")))]
+    async fn transfer_card(
         &self,
         ctx: &Context<'_>,
         card_id: Uuid,
-    ) -> Result<Uuid, GraphqlError> {
-        let card = sqlx::query_as<_, bool>("SELECT * FROM cards WHERE id = $1 AND owner_id = $2")
-    }*/
+        to_user_id: Uuid,
+    ) -> Result<Card, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let session = scoped_session(ctx, Scope::CardsWrite)?;
+            let owner_id = Card::fetch_owned(dbpool, session, card_id).await?.owner_id;
+            if Battle::is_card_busy(dbpool, card_id).await? {
+                return Err(Error::BadRequest("transferCard", "card is in a battle"));
+            }
+            let user_exists = sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM users WHERE id = $1)")
+                .bind(to_user_id)
+                .fetch_one(dbpool)
+                .await?;
+            if !user_exists {
+                return Err(Error::BadRequest("transferCard", "no such user"));
+            }
+            let card = Card::change_owner(dbpool, card_id, owner_id, Some(to_user_id), OwnershipReason::Transfer).await?;
+            let mut redis_conn = ctx.data::<RedisPool>()?.get().await?;
+            matchmaking::leave(&mut redis_conn, card_id).await?;
+            Ok(card)
+        })
+        .await
+    }
+    /// Give up the card. It has no owner afterwards.
+    #[graphql(guard(PermissionGuard(permission = "Permission::Play")))]
+    async fn release_card(
+        &self,
+        ctx: &Context<'_>,
+        card_id: Uuid,
+    ) -> Result<Card, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let session = scoped_session(ctx, Scope::CardsWrite)?;
+            let owner_id = Card::fetch_owned(dbpool, session, card_id).await?.owner_id;
+            if Battle::is_card_busy(dbpool, card_id).await? {
+                return Err(Error::BadRequest("releaseCard", "card is in a battle"));
+            }
+            let card = Card::change_owner(dbpool, card_id, owner_id, None, OwnershipReason::Release).await?;
+            let mut redis_conn = ctx.data::<RedisPool>()?.get().await?;
+            matchmaking::leave(&mut redis_conn, card_id).await?;
+            Ok(card)
+        })
+        .await
+    }
+    /// Start a pending battle with one of the caller's cards.
+    #[graphql(guard(PermissionGuard(permission = "Permission::Play")))]
+    async fn start_battle(
+        &self,
+        ctx: &Context<'_>,
+        card_id: Uuid,
+    ) -> Result<Battle, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let session = scoped_session(ctx, Scope::BattlesWrite)?;
+            let mut tx = dbpool.begin().await?;
+            // the row lock keeps the card out of other battles until this one is written
+            let owner_id = sqlx::query_scalar::<_, Option<Uuid>>("SELECT owner_id FROM cards WHERE id = $1 FOR UPDATE")
+                .bind(card_id)
+                .fetch_optional(&mut tx)
+                .await?;
+            if owner_id != Some(Some(session.user_id)) {
+                return Err(Error::NotAuthorized);
+            }
+            if Battle::is_card_busy(&mut tx, card_id).await? {
+                return Err(Error::BadRequest("startBattle", "card is already in a battle"));
+            }
+            let battle = sqlx::query_as::<_, Battle>("INSERT INTO battles (challenger_id, challenger_card_id) VALUES ($1, $2) RETURNING *")
+                .bind(session.user_id)
+                .bind(card_id)
+                .fetch_one(&mut tx)
+                .await
+                .map_err(card_busy_error("startBattle"))?;
+            tx.commit().await?;
+            // a card in a battle can no longer be matched
+            matchmaking::leave(&mut ctx.data::<RedisPool>()?.get().await?, card_id).await?;
+            Ok(battle)
+        })
+        .await
+    }
+    /// Challenge a card of another player with a pending battle. The battle becomes active
+    /// once its owner accepts.
+    #[graphql(guard(PermissionGuard(permission = "Permission::Play")))]
+    async fn choose_opponent(
+        &self,
+        ctx: &Context<'_>,
+        battle_id: Uuid,
+        card_id: Uuid,
+    ) -> Result<Battle, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let session = scoped_session(ctx, Scope::BattlesWrite)?;
+            let battle = Battle::fetch(dbpool, battle_id).await?;
+            if battle.challenger_id != session.user_id {
+                return Err(Error::NotAuthorized);
+            }
+            if battle.state != BattleState::Pending || battle.opponent_card_id.is_some() {
+                return Err(Error::BadRequest("chooseOpponent", "battle is not waiting for an opponent"));
+            }
+            let mut tx = dbpool.begin().await?;
+            let opponent = sqlx::query_as::<_, Card>("SELECT * FROM cards WHERE id = $1 FOR UPDATE")
+                .bind(card_id)
+                .fetch_one(&mut tx)
+                .await?;
+            let opponent_id = match opponent.owner_id {
+                Some(owner_id) if owner_id != session.user_id => owner_id,
+                Some(_) => return Err(Error::BadRequest("chooseOpponent", "cannot battle against your own card")),
+                None => return Err(Error::BadRequest("chooseOpponent", "card has no owner")),
+            };
+            if Battle::is_card_busy(&mut tx, card_id).await? {
+                return Err(Error::BadRequest("chooseOpponent", "card is already in a battle"));
+            }
+            let battle = sqlx::query_as::<_, Battle>(
+                "UPDATE battles SET opponent_id = $2, opponent_card_id = $3 WHERE id = $1 AND state = 'pending' AND opponent_card_id IS NULL RETURNING *")
+                .bind(battle_id)
+                .bind(opponent_id)
+                .bind(card_id)
+                .fetch_optional(&mut tx)
+                .await
+                .map_err(card_busy_error("chooseOpponent"))?
+                .ok_or(Error::BadRequest("chooseOpponent", "battle is not waiting for an opponent"))?;
+            tx.commit().await?;
+            let redispool = ctx.data::<RedisPool>()?;
+            matchmaking::leave(&mut redispool.get().await?, card_id).await?;
+            events::notify(redispool, &events::battle_channel(battle.id), &battle).await;
+            Ok(battle)
+        })
+        .await
+    }
+    /// Accept a challenge to one of the caller's cards. The battle becomes active. Decline
+    /// with `abandonBattle`.
+    #[graphql(guard(PermissionGuard(permission = "Permission::Play")))]
+    async fn accept_battle(
+        &self,
+        ctx: &Context<'_>,
+        battle_id: Uuid,
+    ) -> Result<Battle, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let session = scoped_session(ctx, Scope::BattlesWrite)?;
+            let battle = Battle::fetch(dbpool, battle_id).await?;
+            if battle.opponent_id != Some(session.user_id) {
+                return Err(Error::NotAuthorized);
+            }
+            let battle = sqlx::query_as::<_, Battle>(
+                "UPDATE battles SET state = 'active', started_at = NOW() WHERE id = $1 AND state = 'pending' AND opponent_id = $2 RETURNING *")
+                .bind(battle_id)
+                .bind(session.user_id)
+                .fetch_optional(dbpool)
+                .await?
+                .ok_or(Error::BadRequest("acceptBattle", "battle is not pending"))?;
+            events::notify(ctx.data::<RedisPool>()?, &events::battle_channel(battle.id), &battle).await;
+            Ok(battle)
+        })
+        .await
+    }
+    /// Report the result of an active battle. No winner means a draw. The battle finishes,
+    /// and the ratings of both cards change, once both sides report the same result. Either
+    /// side may change their report until then. Someone with `BATTLES_MANAGE` who is not in
+    /// the battle settles it alone.
+    #[graphql(guard(PermissionGuard(permission = "Permission::Play")))]
+    async fn finish_battle(
+        &self,
+        ctx: &Context<'_>,
+        battle_id: Uuid,
+        winner_card_id: Option<Uuid>,
+    ) -> Result<Battle, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let rating_system = ctx.data::<SharedRatingSystem>()?;
+            let session = scoped_session(ctx, Scope::BattlesWrite)?;
+            let redispool = ctx.data::<RedisPool>()?;
+            let mut tx = dbpool.begin().await?;
+            let battle = sqlx::query_as::<_, Battle>("SELECT * FROM battles WHERE id = $1 FOR UPDATE")
+                .bind(battle_id)
+                .fetch_one(&mut tx)
+                .await?;
+            let opponent_card_id = match (battle.state, battle.opponent_card_id) {
+                (BattleState::Active, Some(opponent_card_id)) => opponent_card_id,
+                _ => return Err(Error::BadRequest("finishBattle", "battle is not active")),
+            };
+            let result = match winner_card_id {
+                None => BattleResult::Draw,
+                Some(id) if id == battle.challenger_card_id => BattleResult::ChallengerWon,
+                Some(id) if id == opponent_card_id => BattleResult::OpponentWon,
+                Some(_) => return Err(Error::BadRequest("finishBattle", "winner is not in the battle")),
+            };
+            let (challenger_report, opponent_report) = if battle.challenger_id == session.user_id {
+                (Some(result), battle.opponent_report)
+            } else if battle.opponent_id == Some(session.user_id) {
+                (battle.challenger_report, Some(result))
+            } else if session.has(Permission::BattlesManage) {
+                (Some(result), Some(result))
+            } else {
+                return Err(Error::NotAuthorized);
+            };
+            if challenger_report != opponent_report {
+                let battle = sqlx::query_as::<_, Battle>(
+                    "UPDATE battles SET challenger_report = $2, opponent_report = $3 WHERE id = $1 RETURNING *")
+                    .bind(battle_id)
+                    .bind(challenger_report)
+                    .bind(opponent_report)
+                    .fetch_one(&mut tx)
+                    .await?;
+                tx.commit().await?;
+                events::notify(redispool, &events::battle_channel(battle.id), &battle).await;
+                return Ok(battle);
+            }
+            // locked in id order, so battles between the same cards in swapped roles cannot deadlock
+            let locked = sqlx::query_as::<_, Card>("SELECT * FROM cards WHERE id IN ($1, $2) ORDER BY id FOR UPDATE")
+                .bind(battle.challenger_card_id)
+                .bind(opponent_card_id)
+                .fetch_all(&mut tx)
+                .await?;
+            let find = |id: Uuid| locked.iter().find(|card| card.id == id).cloned().ok_or(sqlx::Error::RowNotFound);
+            let challenger = find(battle.challenger_card_id)?;
+            let opponent = find(opponent_card_id)?;
+            let (challenger_after, opponent_after) =
+                rating_system.rate(&challenger.to_rating(), &opponent.to_rating(), result.outcome());
+            let mut cards = Vec::new();
+            for (card_id, rating) in [(challenger.id, challenger_after), (opponent.id, opponent_after)].iter() {
+                cards.push(
+                    sqlx::query_as::<_, Card>("UPDATE cards SET rating = $2, rating_deviation = $3, rating_volatility = $4 WHERE id = $1 RETURNING *")
+                        .bind(card_id)
+                        .bind(rating.rating)
+                        .bind(rating.deviation)
+                        .bind(rating.volatility)
+                        .fetch_one(&mut tx)
+                        .await?,
+                );
+            }
+            let battle = sqlx::query_as::<_, Battle>(
+                "UPDATE battles SET state = 'finished', winner_card_id = $2, finished_at = NOW(), challenger_rating_before = $3, challenger_rating_after = $4, opponent_rating_before = $5, opponent_rating_after = $6, challenger_report = $7, opponent_report = $7 WHERE id = $1 RETURNING *")
+                .bind(battle_id)
+                .bind(winner_card_id)
+                .bind(challenger.rating)
+                .bind(challenger_after.rating)
+                .bind(opponent.rating)
+                .bind(opponent_after.rating)
+                .bind(result)
+                .fetch_one(&mut tx)
+                .await?;
+            tx.commit().await?;
+            events::notify(redispool, &events::battle_channel(battle.id), &battle).await;
+            for card in cards.iter() {
+                events::notify(redispool, &events::card_rating_channel(card.id), card).await;
+            }
+            Ok(battle)
+        })
+        .await
+    }
+    /// Queue one of the caller's cards for matchmaking against a card of similar rating.
+    #[graphql(guard(PermissionGuard(permission = "Permission::Play")))]
+    async fn join_queue(
+        &self,
+        ctx: &Context<'_>,
+        card_id: Uuid,
+    ) -> Result<QueueStatus, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let session = scoped_session(ctx, Scope::BattlesWrite)?;
+            let card = sqlx::query_as::<_, Card>("SELECT * FROM cards WHERE id = $1 AND owner_id = $2")
+                .bind(card_id)
+                .bind(session.user_id)
+                .fetch_optional(dbpool)
+                .await?
+                .ok_or(Error::NotAuthorized)?;
+            if Battle::is_card_busy(dbpool, card_id).await? {
+                return Err(Error::BadRequest("joinQueue", "card is already in a battle"));
+            }
+            let redispool = ctx.data::<RedisPool>()?;
+            let mut redis_conn = redispool.get().await?;
+            matchmaking::join(&mut redis_conn, card.id, session.user_id, card.rating).await?;
+            let battle = matchmaking::try_match(dbpool, &mut redis_conn, card.id).await?;
+            let position = match battle {
+                Some(ref battle) => {
+                    matchmaking::announce(redispool, battle).await;
+                    None
+                }
+                None => matchmaking::position(&mut redis_conn, card.id).await?.map(|p| p as i32),
+            };
+            Ok(QueueStatus {
+                card_id,
+                position,
+                battle,
+            })
+        })
+        .await
+    }
+    /// Returns false if the card was not queued.
+    #[graphql(guard(PermissionGuard(permission = "Permission::Play")))]
+    async fn leave_queue(
+        &self,
+        ctx: &Context<'_>,
+        card_id: Uuid,
+    ) -> Result<bool, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let session = scoped_session(ctx, Scope::BattlesWrite)?;
+            let owned = sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM cards WHERE id = $1 AND owner_id = $2)")
+                .bind(card_id)
+                .bind(session.user_id)
+                .fetch_one(dbpool)
+                .await?;
+            if !owned {
+                return Err(Error::NotAuthorized);
+            }
+            let mut redis_conn = ctx.data::<RedisPool>()?.get().await?;
+            matchmaking::leave(&mut redis_conn, card_id).await
+        })
+        .await
+    }
+    /// Close a pending or active battle without a result. The opponent declines a challenge
+    /// this way.
+    #[graphql(guard(PermissionGuard(permission = "Permission::Play")))]
+    async fn abandon_battle(
+        &self,
+        ctx: &Context<'_>,
+        battle_id: Uuid,
+    ) -> Result<Battle, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let session = scoped_session(ctx, Scope::BattlesWrite)?;
+            let battle = Battle::fetch(dbpool, battle_id).await?;
+            if battle.challenger_id != session.user_id
+                && battle.opponent_id != Some(session.user_id)
+                && !session.has(Permission::BattlesManage)
+            {
+                return Err(Error::NotAuthorized);
+            }
+            let battle = sqlx::query_as::<_, Battle>(
+                "UPDATE battles SET state = 'abandoned', finished_at = NOW() WHERE id = $1 AND state IN ('pending', 'active') RETURNING *")
+                .bind(battle_id)
+                .fetch_optional(dbpool)
+                .await?
+                .ok_or(Error::BadRequest("abandonBattle", "battle is already closed"))?;
+            events::notify(ctx.data::<RedisPool>()?, &events::battle_channel(battle.id), &battle).await;
+            Ok(battle)
+        })
+        .await
+    }
+    /// Give a role to a user. Their sessions end so the next login picks up the new
+    /// permissions. Returns false when they already had it.
+    #[graphql(guard(PermissionGuard(permission = "Permission::RolesManage")))]
+    async fn grant_role(&self, ctx: &Context<'_>, user_id: Uuid, role: String) -> Result<bool, GraphqlError> {
+        resolve(ctx, async move {
+            if own_session(ctx)?.user_id == user_id {
+                return Err(Error::BadRequest("grantRole", "cannot change own roles"));
+            }
+            let granted = permission::grant_role(ctx.data::<DbPool>()?, user_id, &role).await?;
+            if granted {
+                remove_user_sessions(ctx, user_id).await?;
+            }
+            Ok(granted)
+        })
+        .await
+    }
+    /// Take a role away from a user, ending their sessions. Returns false when they did not
+    /// have it.
+    #[graphql(guard(PermissionGuard(permission = "Permission::RolesManage")))]
+    async fn revoke_role(&self, ctx: &Context<'_>, user_id: Uuid, role: String) -> Result<bool, GraphqlError> {
+        resolve(ctx, async move {
+            if own_session(ctx)?.user_id == user_id {
+                return Err(Error::BadRequest("revokeRole", "cannot change own roles"));
+            }
+            let revoked = permission::revoke_role(ctx.data::<DbPool>()?, user_id, &role).await?;
+            if revoked {
+                remove_user_sessions(ctx, user_id).await?;
+            }
+            Ok(revoked)
+        })
+        .await
+    }
+    /// Operations for staff. Each one takes its own permission.
+    async fn admin(&self, ctx: &Context<'_>) -> Result<AdminMutation, GraphqlError> {
+        resolve(ctx, async move {
+            own_session(ctx)?;
+            Ok(AdminMutation)
+        })
+        .await
+    }
 }
 
 pub struct Query;
@@ -253,29 +1315,282 @@ impl Query {
         "0.1".to_string()
     }
     async fn user(&self, ctx: &Context<'_>, id: Uuid) -> Result<User, GraphqlError> {
-        let dbpool = ctx.data::<DbPool>()?;
-        Ok(
-            sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
-                .bind(id)
-                .fetch_one(dbpool)
-                .await?,
-        )
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            Ok(
+                sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+                    .bind(id)
+                    .fetch_one(dbpool)
+                    .await?,
+            )
+        })
+        .await
+    }
+    async fn battle(&self, ctx: &Context<'_>, id: Uuid) -> Result<Battle, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            Battle::fetch(dbpool, id).await
+        })
+        .await
+    }
+    /// Cards of every owner, highest rating first. Equal ratings are ordered by card id.
+    async fn leaderboard(
+        &self,
+        ctx: &Context<'_>,
+        after: Option<String>,
+        before: Option<String>,
+        #[graphql(validator(IntRange(min = "0", max = "100")))]
+        first: Option<i32>,
+        #[graphql(desc = "last N items. clamped by [0-100]")] last: Option<i32>,
+    ) -> Result<Connection<CardCursor, Card, EmptyFields, EmptyFields>, GraphqlError> {
+        let filter = CardFilter {
+            owner_id: None,
+            sort: CardSort::Rating,
+            descending: true,
+        };
+        query_cards(ctx, filter, after, before, first, last).await
+    }
+    /// Sessions of the current user, most recently used first.
+    async fn my_sessions(&self, ctx: &Context<'_>) -> Result<Vec<SessionInfo>, GraphqlError> {
+        resolve(ctx, async move {
+            let session = own_session(ctx)?;
+            list_sessions(ctx, session.user_id).await
+        })
+        .await
+    }
+    /// Identity providers linked to the current user. Link more at `/auth/oidc/{provider}/link`.
+    async fn my_identities(&self, ctx: &Context<'_>) -> Result<Vec<Identity>, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            let session = own_session(ctx)?;
+            oidc::list_identities(dbpool, session.user_id).await
+        })
+        .await
+    }
+    /// Every role and what it grants.
+    #[graphql(guard(PermissionGuard(permission = "Permission::RolesManage")))]
+    async fn roles(&self, ctx: &Context<'_>) -> Result<Vec<Role>, GraphqlError> {
+        resolve(ctx, async move { permission::list_roles(ctx.data::<DbPool>()?).await }).await
+    }
+    /// Place of the card in the matchmaking queue. 0 is the longest waiting card.
+    async fn queue_position(&self, ctx: &Context<'_>, card_id: Uuid) -> Result<Option<i32>, GraphqlError> {
+        resolve(ctx, async move {
+            let mut redis_conn = ctx.data::<RedisPool>()?.get().await?;
+            Ok(matchmaking::position(&mut redis_conn, card_id).await?.map(|p| p as i32))
+        })
+        .await
+    }
+    /// Operations for staff. Each one takes its own permission.
+    async fn admin(&self, ctx: &Context<'_>) -> Result<AdminQuery, GraphqlError> {
+        resolve(ctx, async move {
+            own_session(ctx)?;
+            Ok(AdminQuery)
+        })
+        .await
+    }
+}
+
+pub struct AdminQuery;
+
+#[Object]
+impl AdminQuery {
+    /// Users whose email or nickname contains the query or resembles it, best match first.
+    #[graphql(guard(PermissionGuard(permission = "Permission::ProfileReadAny")))]
+    async fn search_users(
+        &self,
+        ctx: &Context<'_>,
+        query: String,
+        #[graphql(default = 20, validator(IntRange(min = "1", max = "100")))] first: i32,
+    ) -> Result<Vec<User>, GraphqlError> {
+        resolve(ctx, async move { admin::search_users(ctx.data::<DbPool>()?, &query, first as i64).await }).await
+    }
+    /// Manual rating changes of the card, most recent first.
+    #[graphql(guard(PermissionGuard(permission = "Permission::CardsManage")))]
+    async fn rating_adjustments(&self, ctx: &Context<'_>, card_id: Uuid) -> Result<Vec<RatingAdjustment>, GraphqlError> {
+        resolve(ctx, async move { admin::rating_adjustments(ctx.data::<DbPool>()?, card_id).await }).await
+    }
+}
+
+pub struct AdminMutation;
+
+#[Object]
+impl AdminMutation {
+    /// Give the banned role, so the user can log in but not play, whatever their other roles.
+    /// Their sessions end. Returns false when they were already banned.
+    #[graphql(guard(PermissionGuard(permission = "Permission::RolesManage")))]
+    async fn ban_user(&self, ctx: &Context<'_>, user_id: Uuid) -> Result<bool, GraphqlError> {
+        resolve(ctx, async move {
+            if session(ctx)?.user_id == user_id {
+                return Err(Error::BadRequest("banUser", "cannot ban yourself"));
+            }
+            let changed = admin::set_banned(ctx.data::<DbPool>()?, user_id, true).await?;
+            if changed {
+                remove_user_sessions(ctx, user_id).await?;
+            }
+            Ok(changed)
+        })
+        .await
+    }
+    /// Take the banned role away and give the player role back. Returns false when the user
+    /// was not banned.
+    #[graphql(guard(PermissionGuard(permission = "Permission::RolesManage")))]
+    async fn unban_user(&self, ctx: &Context<'_>, user_id: Uuid) -> Result<bool, GraphqlError> {
+        resolve(ctx, async move {
+            let changed = admin::set_banned(ctx.data::<DbPool>()?, user_id, false).await?;
+            if changed {
+                remove_user_sessions(ctx, user_id).await?;
+            }
+            Ok(changed)
+        })
+        .await
+    }
+    /// Make the user super or normal. Super users also get the admin role. Their sessions end.
+    #[graphql(guard(PermissionGuard(permission = "Permission::RolesManage")))]
+    async fn set_user_kind(&self, ctx: &Context<'_>, user_id: Uuid, kind: UserKind) -> Result<User, GraphqlError> {
+        resolve(ctx, async move {
+            if session(ctx)?.user_id == user_id {
+                return Err(Error::BadRequest("setUserKind", "cannot change own kind"));
+            }
+            let user = admin::set_kind(ctx.data::<DbPool>()?, user_id, kind).await?;
+            remove_user_sessions(ctx, user_id).await?;
+            Ok(user)
+        })
+        .await
+    }
+    /// Give the card to another user, or to no one, whoever owns it now.
+    #[graphql(guard(PermissionGuard(permission = "Permission::CardsManage")))]
+    async fn transfer_card(&self, ctx: &Context<'_>, card_id: Uuid, to_user_id: Option<Uuid>) -> Result<Card, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            if Battle::is_card_busy(dbpool, card_id).await? {
+                return Err(Error::BadRequest("transferCard", "card is in a battle"));
+            }
+            if let Some(to_user_id) = to_user_id {
+                let user_exists = sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM users WHERE id = $1)")
+                    .bind(to_user_id)
+                    .fetch_one(dbpool)
+                    .await?;
+                if !user_exists {
+                    return Err(Error::BadRequest("transferCard", "no such user"));
+                }
+            }
+            let card = Card::change_owner(dbpool, card_id, None, to_user_id, OwnershipReason::Admin).await?;
+            let mut redis_conn = ctx.data::<RedisPool>()?.get().await?;
+            matchmaking::leave(&mut redis_conn, card_id).await?;
+            Ok(card)
+        })
+        .await
+    }
+    /// Delete the card. Returns it as it was. Its ownership history is kept.
+    #[graphql(guard(PermissionGuard(permission = "Permission::CardsManage")))]
+    async fn delete_card(&self, ctx: &Context<'_>, card_id: Uuid) -> Result<Card, GraphqlError> {
+        resolve(ctx, async move {
+            let dbpool = ctx.data::<DbPool>()?;
+            if Battle::is_card_busy(dbpool, card_id).await? {
+                return Err(Error::BadRequest("deleteCard", "card is in a battle"));
+            }
+            let card = admin::delete_card(dbpool, card_id).await?;
+            let mut redis_conn = ctx.data::<RedisPool>()?.get().await?;
+            matchmaking::leave(&mut redis_conn, card_id).await?;
+            Ok(card)
+        })
+        .await
+    }
+    /// Set the rating of the card by hand. The reason is kept in `ratingAdjustments`.
+    #[graphql(guard(PermissionGuard(permission = "Permission::CardsManage")))]
+    async fn adjust_card_rating(
+        &self,
+        ctx: &Context<'_>,
+        card_id: Uuid,
+        rating: f64,
+        reason: String,
+    ) -> Result<Card, GraphqlError> {
+        resolve(ctx, async move {
+            Validator::default().check("reason", validation::reason(&reason)).finish()?;
+            if !rating.is_finite() || rating < 0.0 {
+                return Err(Error::BadRequest("adjustCardRating", "rating must be a positive number"));
+            }
+            let session = session(ctx)?;
+            let card = admin::adjust_rating(ctx.data::<DbPool>()?, session.user_id, card_id, rating, &reason).await?;
+            events::notify(ctx.data::<RedisPool>()?, &events::card_rating_channel(card.id), &card).await;
+            Ok(card)
+        })
+        .await
     }
 }
 
-pub type Schema = GraphqlSchema<Query, Mutation, EmptySubscription>;
+pub struct Subscription;
 
-pub async fn build_schema(dbpool: DbPool, redispool: RedisPool) -> Result<Schema, Error> {
-    Ok(GraphqlSchema::build(Query, Mutation, EmptySubscription)
+#[Subscription]
+impl Subscription {
+    /// Every state change of the battle.
+    async fn battle_updated(
+        &self,
+        ctx: &Context<'_>,
+        battle_id: Uuid,
+    ) -> Result<impl Stream<Item = Battle>, GraphqlError> {
+        resolve(ctx, async move {
+            let client = ctx.data::<RedisClient>()?;
+            events::subscribe::<Battle>(client, &events::battle_channel(battle_id)).await
+        })
+        .await
+    }
+    /// Battles started by matchmaking for the caller's cards.
+    #[graphql(guard(PermissionGuard(permission = "Permission::Play")))]
+    async fn match_found(&self, ctx: &Context<'_>) -> Result<impl Stream<Item = Battle>, GraphqlError> {
+        resolve(ctx, async move {
+            let client = ctx.data::<RedisClient>()?;
+            let session = scoped_session(ctx, Scope::BattlesRead)?;
+            events::subscribe::<Battle>(client, &events::match_channel(session.user_id)).await
+        })
+        .await
+    }
+    /// The card after its rating was updated by a finished battle.
+    async fn card_rating_changed(
+        &self,
+        ctx: &Context<'_>,
+        card_id: Uuid,
+    ) -> Result<impl Stream<Item = Card>, GraphqlError> {
+        resolve(ctx, async move {
+            let client = ctx.data::<RedisClient>()?;
+            events::subscribe::<Card>(client, &events::card_rating_channel(card_id)).await
+        })
+        .await
+    }
+}
+
+pub type Schema = GraphqlSchema<Query, Mutation, Subscription>;
+
+pub async fn build_schema(
+    dbpool: DbPool,
+    redispool: RedisPool,
+    redis_client: RedisClient,
+    rating_system: SharedRatingSystem,
+    jwt_keys: JwtKeys,
+    mailer: SharedMailer,
+    session_policy: SessionPolicy,
+    tuning: Tuning,
+) -> Result<Schema, Error> {
+    Ok(GraphqlSchema::build(Query, Mutation, Subscription)
         .data(dbpool)
         .data(redispool)
+        .data(redis_client)
+        .data(rating_system)
+        .data(jwt_keys)
+        .data(mailer)
+        .data(session_policy)
+        .data(tuning)
         .finish())
 }
 
 #[cfg(test)]
 pub mod tests {
+    use crate::model::{CardCursor, Mutation, Query, Schema, Subscription, Tuning, UserKind};
+    use crate::rating::{Glicko2, SharedRatingSystem};
+    use crate::permission::{load_permissions, Permission};
+    use crate::session::Session;
     use crate::test_util::*;
-    use async_graphql::{value, Name, Value};
+    use async_graphql::{connection::CursorType, value, Name, Request, Value};
 
     #[actix_rt::test]
     async fn test_migration_and_build_schema() {
@@ -283,13 +1598,22 @@ pub mod tests {
         let db = docker.run().await;
         let _schema = db.schema.clone();
     }
+    #[test]
+    fn test_dummy_password_hash_cost() {
+        // unknown emails must cost as much as the hashes real users have
+        for cost in [4, 5].iter() {
+            let hash = crate::model::dummy_password_hash(*cost).unwrap();
+            assert!(hash.starts_with(&format!("$2b${:02}$", cost)), "{}", hash);
+            assert_eq!(crate::model::dummy_password_hash(*cost).unwrap(), hash);
+        }
+    }
     #[actix_rt::test]
     async fn test_register() {
         let docker = TestDocker::new();
         let db = docker.run().await;
         let schema = db.schema.clone();
 
-        let query = r#"mutation { register(email:"a", password:"b", nickname:"c") }"#;
+        let query = r#"mutation { register(email:"a@example.com", password:"hunter22", nickname:"carol") }"#;
         let res = schema.execute(query).await;
         let data = res.data;
         assert_eq!(res.errors, Vec::new(),);
@@ -310,7 +1634,7 @@ pub mod tests {
             data,
             value!( {
                 "user": {
-                    "nickname": "c"
+                    "nickname": "carol"
                 }
             } )
         );
@@ -322,7 +1646,7 @@ pub mod tests {
         let db = docker.run().await;
         let schema = db.schema.clone();
 
-        let query = r#"mutation { register(email:"a", password:"b", nickname:"c") }"#;
+        let query = r#"mutation { register(email:"a@example.com", password:"hunter22", nickname:"carol") }"#;
         let res = schema.execute(query).await;
         let data = res.data;
 
@@ -334,29 +1658,356 @@ pub mod tests {
             _ => panic!("unexpected value type"),
         };
 
-        let query = r#"mutation { login(email:"a", password:"b") }"#;
-        let res = schema.execute(query).await;
-        let data = res.data;
-        assert_eq!(res.errors, Vec::new(),);
-        let user_id2 = match data {
-            Value::Object(v) => match v.get(&Name::new("login")) {
-                Some(Value::String(id)) => id.clone(),
-                _ => panic!("unexpected value type"),
-            },
-            _ => panic!("unexpected value type"),
-        };
+        let query = r#"mutation { login(email:"a@example.com", password:"hunter22") }"#;
+        let res = schema.execute(query).await;
+        let data = res.data;
+        assert_eq!(res.errors, Vec::new(),);
+        let user_id2 = match data {
+            Value::Object(v) => match v.get(&Name::new("login")) {
+                Some(Value::String(id)) => id.clone(),
+                _ => panic!("unexpected value type"),
+            },
+            _ => panic!("unexpected value type"),
+        };
+
+        assert_eq!(user_id, user_id2);
+
+        let query = r#"mutation { login(email:"a@example.com", password:"hunter33") }"#;
+        let res = schema.execute(query).await;
+        assert_eq!(
+            res.errors
+                .into_iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>(),
+            vec!["wrong email or password"]
+        );
+
+        // unknown emails look the same
+        let query = r#"mutation { login(email:"nobody@example.com", password:"hunter22") }"#;
+        let res = schema.execute(query).await;
+        assert_eq!(
+            res.errors.into_iter().map(|t| t.to_string()).collect::<Vec<_>>(),
+            vec!["wrong email or password"]
+        );
+    }
+    #[actix_rt::test]
+    async fn test_login_lockout() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = db.schema.clone();
+
+        let query = r#"mutation { register(email:"a@example.com", password:"hunter22", nickname:"carol") }"#;
+        let res = schema.execute(query).await;
+        let user_id = res.data.into_json().unwrap()["register"].as_str().unwrap().to_string();
+
+        let code = |res: async_graphql::Response| {
+            serde_json::to_value(res).unwrap()["errors"][0]["extensions"]["code"].clone()
+        };
+        for _ in 0..3 {
+            let res = schema.execute(r#"mutation { login(email:"a@example.com", password:"hunter33") }"#).await;
+            assert_eq!(code(res), "WRONG_PASSWORD");
+        }
+        // even the right password has to wait now, whatever the case of the email
+        let res = schema.execute(r#"mutation { login(email:"A@example.com", password:"hunter22") }"#).await;
+        assert_eq!(code(res), "LOCKED_OUT");
+
+        let query = format!(r#"query {{ user(id: "{}") {{ loginLockout {{ failedAttempts lockedForSeconds }} }} }}"#, user_id);
+        let owner = Session { user_id: user_id.parse().unwrap(), user_kind: UserKind::Normal, scopes: None, permissions: vec![Permission::Play] };
+        let res = schema.execute(Request::new(query.as_str()).data(owner)).await;
+        assert_eq!(res.errors.len(), 1);
+        let admin = Session { user_id: uuid::Uuid::new_v4(), user_kind: UserKind::Super, scopes: None, permissions: Permission::ALL.to_vec() };
+        let res = schema.execute(Request::new(query.as_str()).data(admin)).await;
+        let lockout = res.data.into_json().unwrap()["user"]["loginLockout"].clone();
+        assert_eq!(lockout["failedAttempts"], 3);
+        assert!(lockout["lockedForSeconds"].as_i64().unwrap() > 0);
+    }
+    #[actix_rt::test]
+    async fn test_login_token() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = db.schema.clone();
+
+        let query = r#"mutation { register(email:"a@example.com", password:"hunter22", nickname:"carol") }"#;
+        let res = schema.execute(query).await;
+        let user_id = res.data.into_json().unwrap()["register"].as_str().unwrap().to_string();
+
+        let query = r#"mutation { loginToken(email:"a@example.com", password:"hunter22") { accessToken refreshToken expiresIn } }"#;
+        let res = schema.execute(query).await;
+        assert_eq!(res.errors, Vec::new());
+        let tokens = res.data.into_json().unwrap()["loginToken"].clone();
+        let session = db.jwt_keys.verify_access_token(tokens["accessToken"].as_str().unwrap()).unwrap();
+        assert_eq!(session, Session { user_id: user_id.parse().unwrap(), user_kind: UserKind::Normal, scopes: None, permissions: vec![Permission::Play] });
+        assert_eq!(tokens["expiresIn"], 900);
+        // a refresh token is not an access token
+        assert!(db.jwt_keys.verify_access_token(tokens["refreshToken"].as_str().unwrap()).is_err());
+
+        let query = format!(r#"mutation {{ refreshToken(refreshToken:"{}") {{ accessToken refreshToken }} }}"#,
+            tokens["refreshToken"].as_str().unwrap());
+        let res = schema.execute(query.as_str()).await;
+        assert_eq!(res.errors, Vec::new());
+        let rotated = res.data.into_json().unwrap()["refreshToken"].clone();
+        assert_ne!(rotated["refreshToken"], tokens["refreshToken"]);
+        assert!(db.jwt_keys.verify_access_token(rotated["accessToken"].as_str().unwrap()).is_ok());
+
+        // refresh tokens are single use
+        let res = schema.execute(query.as_str()).await;
+        assert_eq!(res.errors.len(), 1);
+
+        // ending the sessions of a user revokes their refresh tokens too
+        let mut redis_conn = db.redispool.get().await.unwrap();
+        crate::token::revoke_refresh_tokens(&mut redis_conn, user_id.parse().unwrap()).await.unwrap();
+        let query = format!(r#"mutation {{ refreshToken(refreshToken:"{}") {{ accessToken }} }}"#,
+            rotated["refreshToken"].as_str().unwrap());
+        let res = schema.execute(query.as_str()).await;
+        assert_eq!(res.errors.len(), 1);
+
+        let query = r#"mutation { loginToken(email:"a@example.com", password:"hunter33") { accessToken } }"#;
+        let res = schema.execute(query).await;
+        assert_eq!(res.errors.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_register_validation() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = db.schema.clone();
+
+        let query = r#"mutation { register(email:"a", password:"b", nickname:"c") }"#;
+        let res = serde_json::to_value(schema.execute(query).await).unwrap();
+        assert_eq!(
+            res["errors"][0]["extensions"]["fields"],
+            serde_json::json!([
+                { "field": "email", "message": "is not an email address" },
+                { "field": "password", "message": "must be at least 8 characters" },
+                { "field": "nickname", "message": "must be 2 to 32 characters" },
+            ])
+        );
+
+        let query = r#"mutation { register(email:"a@example.com", password:"hunter22", nickname:"carol") }"#;
+        let res = schema.execute(query).await;
+        assert_eq!(res.errors, Vec::new());
+
+        // the same address in another case is taken too
+        let query = r#"mutation { register(email:"A@Example.com", password:"hunter22", nickname:"carol2") }"#;
+        let res = serde_json::to_value(schema.execute(query).await).unwrap();
+        assert_eq!(
+            res["errors"][0]["extensions"]["fields"],
+            serde_json::json!([{ "field": "email", "message": "is already registered" }])
+        );
+        let query = r#"mutation { login(email:"A@EXAMPLE.COM", password:"hunter22") }"#;
+        let res = schema.execute(query).await;
+        assert_eq!(res.errors, Vec::new());
+    }
+
+    /// The code is the last line of account mail.
+    fn mailed_token(db: &TestNodes<'_>) -> String {
+        let mail = db.mailer.sent().pop().unwrap();
+        mail.body.trim_end().lines().last().unwrap().to_string()
+    }
+
+    #[actix_rt::test]
+    async fn test_email_verification() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = db.schema.clone();
+
+        let query = r#"mutation { register(email:"a@example.com", password:"hunter22", nickname:"carol") }"#;
+        let res = schema.execute(query).await;
+        let user_id = res.data.into_json().unwrap()["register"].as_str().unwrap().to_string();
+        let session = Session { user_id: user_id.parse().unwrap(), user_kind: UserKind::Normal, scopes: None, permissions: vec![Permission::Play] };
+        assert_eq!(db.mailer.sent().len(), 1);
+        assert_eq!(db.mailer.sent()[0].to, "a@example.com");
+        let token = mailed_token(&db);
+
+        let query = format!(r#"query {{ user(id: "{}") {{ emailVerifiedAt }} }}"#, user_id);
+        let res = schema.execute(Request::new(query.as_str()).data(session.clone())).await;
+        assert_eq!(res.data.into_json().unwrap()["user"]["emailVerifiedAt"], serde_json::Value::Null);
+
+        let verify = format!(r#"mutation {{ verifyEmail(token: "{}") }}"#, token);
+        let res = schema.execute(verify.as_str()).await;
+        assert_eq!(res.data.into_json().unwrap()["verifyEmail"], true);
+        let res = schema.execute(Request::new(query.as_str()).data(session.clone())).await;
+        assert!(res.data.into_json().unwrap()["user"]["emailVerifiedAt"].is_string());
+
+        // codes are single use
+        let res = schema.execute(verify.as_str()).await;
+        assert_eq!(res.data.into_json().unwrap()["verifyEmail"], false);
+
+        let query = r#"mutation { resendVerificationEmail }"#;
+        let res = schema.execute(Request::new(query).data(session)).await;
+        assert_eq!(res.errors.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_password_reset() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = db.schema.clone();
+
+        let query = r#"mutation { register(email:"a@example.com", password:"hunter22", nickname:"carol") }"#;
+        schema.execute(query).await;
+        let sent = db.mailer.sent().len();
+
+        // unknown addresses look the same, but get no mail
+        let query = r#"mutation { requestPasswordReset(email:"nobody") }"#;
+        let res = schema.execute(query).await;
+        assert_eq!(res.data.into_json().unwrap()["requestPasswordReset"], true);
+
+        let query = r#"mutation { requestPasswordReset(email:"a@example.com") }"#;
+        let res = schema.execute(query).await;
+        assert_eq!(res.data.into_json().unwrap()["requestPasswordReset"], true);
+        // the mail goes out in the background
+        let mails = db.mailer.wait_for(sent + 1).await;
+        assert_eq!(mails.len(), sent + 1);
+        assert_eq!(mails[sent].to, "a@example.com");
+        let token = mailed_token(&db);
+
+        let reset = format!(r#"mutation {{ resetPassword(token: "{}", newPassword: "sesame42") }}"#, token);
+        let res = schema.execute(reset.as_str()).await;
+        assert_eq!(res.errors, Vec::new());
+        assert_eq!(res.data.into_json().unwrap()["resetPassword"], true);
+        let res = schema.execute(reset.as_str()).await;
+        assert_eq!(res.data.into_json().unwrap()["resetPassword"], false);
+
+        let res = schema.execute(r#"mutation { login(email:"a@example.com", password:"hunter22") }"#).await;
+        assert_eq!(res.errors.len(), 1);
+        let res = schema.execute(r#"mutation { login(email:"a@example.com", password:"sesame42") }"#).await;
+        assert_eq!(res.errors, Vec::new());
+    }
+
+    #[actix_rt::test]
+    async fn test_error_codes() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = db.schema.clone();
+
+        let error = |res: async_graphql::Response| {
+            let res = serde_json::to_value(res).unwrap();
+            (res["errors"][0]["message"].clone(), res["errors"][0]["extensions"]["code"].clone())
+        };
+
+        // no sqlx details for missing rows
+        let query = format!(r#"query {{ user(id: "{}") {{ nickname }} }}"#, uuid::Uuid::new_v4());
+        let res = schema.execute(query.as_str()).await;
+        assert_eq!(error(res), (serde_json::json!("not found"), serde_json::json!("NOT_FOUND")));
+
+        let res = schema.execute("query { mySessions { handle } }").await;
+        assert_eq!(error(res).1, "NOT_AUTHORIZED");
+
+        // nothing about why a token was refused
+        let res = schema.execute(r#"mutation { refreshToken(refreshToken:"forged") { accessToken } }"#).await;
+        assert_eq!(error(res), (serde_json::json!("invalid token"), serde_json::json!("NOT_AUTHORIZED")));
+
+        let query = r#"mutation { register(email:"a@example.com", password:"hunter22", nickname:"carol") }"#;
+        schema.execute(query).await;
+        let res = schema.execute(r#"mutation { login(email:"a@example.com", password:"hunter33") }"#).await;
+        assert_eq!(error(res), (serde_json::json!("wrong email or password"), serde_json::json!("WRONG_PASSWORD")));
+
+        let query = format!(r#"mutation {{ abandonBattle(battleId: "{}") {{ id }} }}"#, uuid::Uuid::new_v4());
+        let session = Session { user_id: uuid::Uuid::new_v4(), user_kind: UserKind::Normal, scopes: None, permissions: vec![Permission::Play] };
+        let res = schema.execute(Request::new(query.as_str()).data(session)).await;
+        assert_eq!(error(res).1, "NOT_FOUND");
+    }
+
+    #[actix_rt::test]
+    async fn test_roles() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = db.schema.clone();
+
+        let query = r#"mutation { register(email:"a@example.com", password:"hunter22", nickname:"carol") }"#;
+        let res = serde_json::to_value(schema.execute(query).await).unwrap();
+        let user_id: uuid::Uuid = res["data"]["register"].as_str().unwrap().parse().unwrap();
+        assert_eq!(load_permissions(&db.pgpool, user_id).await.unwrap(), vec![Permission::Play]);
+
+        let player = Session { user_id, user_kind: UserKind::Normal, scopes: None, permissions: vec![Permission::Play] };
+        let admin = Session { user_id: uuid::Uuid::new_v4(), user_kind: UserKind::Super, scopes: None, permissions: Permission::ALL.to_vec() };
+        let code = |res: async_graphql::Response| serde_json::to_value(res).unwrap()["errors"][0]["extensions"]["code"].clone();
+
+        let grant = |role: &str| format!(r#"mutation {{ grantRole(userId: "{}", role: "{}") }}"#, user_id, role);
+        let res = schema.execute(Request::new(grant("admin")).data(player.clone())).await;
+        assert_eq!(code(res), "NOT_AUTHORIZED");
+        let res = schema.execute(Request::new(grant("overlord")).data(admin.clone())).await;
+        assert_eq!(code(res), "BAD_REQUEST");
+
+        let res = schema.execute(Request::new(grant("banned")).data(admin.clone())).await;
+        assert_eq!(res.data, value!({ "grantRole": true }));
+        let query = format!(r#"mutation {{ revokeRole(userId: "{}", role: "player") }}"#, user_id);
+        let res = schema.execute(Request::new(query).data(admin.clone())).await;
+        assert_eq!(res.data, value!({ "revokeRole": true }));
+        let query = format!(r#"query {{ user(id: "{}") {{ roles }} }}"#, user_id);
+        let res = schema.execute(Request::new(query).data(admin.clone())).await;
+        assert_eq!(res.data, value!({ "user": { "roles": ["banned"] } }));
+
+        let permissions = load_permissions(&db.pgpool, user_id).await.unwrap();
+        assert!(permissions.is_empty());
+        let banned = Session { permissions, ..player };
+        let res = schema.execute(Request::new(r#"mutation { createCard(title: "x") { id } }"#).data(banned)).await;
+        assert_eq!(code(res), "NOT_AUTHORIZED");
+    }
+
+    #[actix_rt::test]
+    async fn test_admin() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = db.schema.clone();
+
+        let mut user_ids = Vec::new();
+        for (email, nickname) in [("a@example.com", "carol"), ("b@example.com", "bob")].iter() {
+            let query = format!(r#"mutation {{ register(email:"{}", password:"hunter22", nickname:"{}") }}"#, email, nickname);
+            let res = serde_json::to_value(schema.execute(query.as_str()).await).unwrap();
+            user_ids.push(res["data"]["register"].as_str().unwrap().parse::<uuid::Uuid>().unwrap());
+        }
+        let (carol_id, bob_id) = (user_ids[0], user_ids[1]);
+        let admin = Session { user_id: uuid::Uuid::new_v4(), user_kind: UserKind::Super, scopes: None, permissions: Permission::ALL.to_vec() };
+        let moderator = Session { permissions: vec![Permission::Play, Permission::ProfileReadAny], ..admin.clone() };
+        let code = |res: async_graphql::Response| serde_json::to_value(res).unwrap()["errors"][0]["extensions"]["code"].clone();
 
-        assert_eq!(user_id, user_id2);
+        let query = r#"query { admin { searchUsers(query: "CARO") { nickname } } }"#;
+        let res = schema.execute(Request::new(query).data(moderator.clone())).await;
+        assert_eq!(res.data, value!({ "admin": { "searchUsers": [{ "nickname": "carol" }] } }));
+        let query = r#"query { admin { searchUsers(query: "%") { nickname } } }"#;
+        let res = schema.execute(Request::new(query).data(moderator.clone())).await;
+        assert_eq!(res.data, value!({ "admin": { "searchUsers": [] } }));
 
-        let query = r#"mutation { login(email:"a", password:"c") }"#;
-        let res = schema.execute(query).await;
-        assert_eq!(
-            res.errors
-                .into_iter()
-                .map(|t| t.to_string())
-                .collect::<Vec<_>>(),
-            vec!["wrong password"]
-        );
+        let ban = format!(r#"mutation {{ admin {{ banUser(userId: "{}") }} }}"#, bob_id);
+        let res = schema.execute(Request::new(ban.as_str()).data(moderator.clone())).await;
+        assert_eq!(code(res), "NOT_AUTHORIZED");
+        let res = schema.execute(Request::new(ban.as_str()).data(admin.clone())).await;
+        assert_eq!(res.data, value!({ "admin": { "banUser": true } }));
+        assert!(load_permissions(&db.pgpool, bob_id).await.unwrap().is_empty());
+        let query = format!(r#"mutation {{ admin {{ unbanUser(userId: "{}") }} }}"#, bob_id);
+        let res = schema.execute(Request::new(query).data(admin.clone())).await;
+        assert_eq!(res.data, value!({ "admin": { "unbanUser": true } }));
+        assert_eq!(load_permissions(&db.pgpool, bob_id).await.unwrap(), vec![Permission::Play]);
+
+        let query = format!(r#"mutation {{ admin {{ setUserKind(userId: "{}", kind: SUPER) {{ kind }} }} }}"#, carol_id);
+        let res = schema.execute(Request::new(query).data(admin.clone())).await;
+        assert_eq!(res.data, value!({ "admin": { "setUserKind": { "kind": "SUPER" } } }));
+        assert_eq!(load_permissions(&db.pgpool, carol_id).await.unwrap().len(), Permission::ALL.len());
+
+        let query = format!(r#"mutation {{ createCard(title: "dragon", ownerId: "{}") {{ id }} }}"#, bob_id);
+        let res = serde_json::to_value(schema.execute(Request::new(query).data(admin.clone())).await).unwrap();
+        let card_id = res["data"]["createCard"]["id"].as_str().unwrap().to_string();
+
+        let adjust = |reason: &str| format!(r#"mutation {{ admin {{ adjustCardRating(cardId: "{}", rating: 1500, reason: "{}") {{ rating }} }} }}"#, card_id, reason);
+        let res = schema.execute(Request::new(adjust(" ")).data(admin.clone())).await;
+        assert_eq!(code(res), "BAD_REQUEST");
+        let res = schema.execute(Request::new(adjust("rating lost to a bug")).data(admin.clone())).await;
+        assert_eq!(res.data, value!({ "admin": { "adjustCardRating": { "rating": 1500.0 } } }));
+        let query = format!(r#"query {{ admin {{ ratingAdjustments(cardId: "{}") {{ ratingBefore ratingAfter reason }} }} }}"#, card_id);
+        let res = schema.execute(Request::new(query).data(admin.clone())).await;
+        assert_eq!(res.data, value!({ "admin": { "ratingAdjustments": [{ "ratingBefore": 1000.0, "ratingAfter": 1500.0, "reason": "rating lost to a bug" }] } }));
+
+        let query = format!(r#"mutation {{ admin {{ transferCard(cardId: "{}", toUserId: "{}") {{ ownerId }} }} }}"#, card_id, carol_id);
+        let res = schema.execute(Request::new(query).data(admin.clone())).await;
+        assert_eq!(res.data, value!({ "admin": { "transferCard": { "ownerId": carol_id.to_string() } } }));
+        let query = format!(r#"mutation {{ admin {{ deleteCard(cardId: "{}") {{ id }} }} }}"#, card_id);
+        let res = schema.execute(Request::new(query).data(admin.clone())).await;
+        assert_eq!(res.data, value!({ "admin": { "deleteCard": { "id": card_id.clone() } } }));
+        let query = format!(r#"mutation {{ admin {{ deleteCard(cardId: "{}") {{ id }} }} }}"#, card_id);
+        let res = schema.execute(Request::new(query).data(admin)).await;
+        assert_eq!(code(res), "NOT_FOUND");
     }
 
     #[actix_rt::test]
@@ -413,6 +2064,10 @@ pub mod tests {
         let res = schema.execute(query).await;
         let data = res.data;
         assert_eq!(res.errors, Vec::new());
+        let second_card_id: uuid::Uuid = sqlx::query_scalar("SELECT id FROM cards WHERE rating = 8")
+            .fetch_one(&dbpool)
+            .await.unwrap();
+        let end_cursor = CardCursor::OwnedAt((date - chrono::Duration::seconds(8)).with_timezone(&chrono::Utc), second_card_id).encode_cursor();
         assert_eq!(
             data,
             value!( {
@@ -424,7 +2079,7 @@ pub mod tests {
                             "node": { "ownedAt": "2020-04-13T12:09:06.274+00:00", "rating": 8.0 }
                         }],
                         "pageInfo": {
-                            "endCursor": "AAAAABgAAAAAAAAAMjAyMC0wNC0xM1QxMjowOTowNi4yNzRa",
+                            "endCursor": end_cursor,
                             "hasNextPage": true,
                             "hasPreviousPage": false,
                         }
@@ -487,4 +2142,621 @@ pub mod tests {
             Some(false)
             );
     }
+
+    #[actix_rt::test]
+    async fn test_battle_lifecycle() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = db.schema.clone();
+        let dbpool = db.pgpool;
+        let challenger_id = uuid::Uuid::new_v4();
+        let opponent_id = uuid::Uuid::new_v4();
+        for (user_id, email) in [(challenger_id, "a"), (opponent_id, "b")].iter() {
+            sqlx::query("INSERT INTO users (id, nickname, email, password) VALUES ($1, 'a', $2, 'c')")
+                .bind(user_id)
+                .bind(*email)
+                .execute(&dbpool)
+                .await.unwrap();
+        }
+        let mut card_ids = Vec::new();
+        for user_id in [challenger_id, opponent_id].iter() {
+            let card_id: uuid::Uuid = sqlx::query_scalar("INSERT INTO cards (owned_at, owner_id) VALUES (NOW(), $1) RETURNING id")
+                .bind(user_id)
+                .fetch_one(&dbpool)
+                .await.unwrap();
+            card_ids.push(card_id);
+        }
+        let challenger = Session { user_id: challenger_id, user_kind: UserKind::Normal, scopes: None, permissions: vec![Permission::Play] };
+        let opponent = Session { user_id: opponent_id, user_kind: UserKind::Normal, scopes: None, permissions: vec![Permission::Play] };
+
+        let query = format!(r#"mutation {{ startBattle(cardId: "{}") {{ id state }} }}"#, card_ids[1]);
+        let res = schema.execute(Request::new(query).data(challenger.clone())).await;
+        assert_eq!(
+            res.errors
+                .into_iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>(),
+            vec!["not authorized to do such request"]
+        );
+
+        let query = format!(r#"mutation {{ startBattle(cardId: "{}") {{ id state }} }}"#, card_ids[0]);
+        let res = schema.execute(Request::new(query).data(challenger.clone())).await;
+        assert_eq!(res.errors, Vec::new());
+        let json = res.data.into_json().unwrap();
+        assert_eq!(json["startBattle"]["state"].as_str(), Some("PENDING"));
+        let battle_id = json["startBattle"]["id"].as_str().unwrap().to_string();
+        let query = format!(r#"mutation {{ startBattle(cardId: "{}") {{ id }} }}"#, card_ids[0]);
+        let res = schema.execute(Request::new(query).data(challenger.clone())).await;
+        assert_eq!(
+            res.errors.into_iter().map(|t| t.to_string()).collect::<Vec<_>>(),
+            vec![r#"invalid request form. method="startBattle" detail="card is already in a battle""#]
+        );
+
+        let query = format!(r#"mutation {{ finishBattle(battleId: "{}", winnerCardId: "{}") {{ state }} }}"#, battle_id, card_ids[0]);
+        let res = schema.execute(Request::new(query).data(challenger.clone())).await;
+        assert_eq!(res.errors.len(), 1);
+
+        let query = format!(r#"mutation {{ chooseOpponent(battleId: "{}", cardId: "{}") {{ state opponentId }} }}"#, battle_id, card_ids[1]);
+        let res = schema.execute(Request::new(query.clone()).data(opponent.clone())).await;
+        assert_eq!(res.errors.len(), 1);
+        let res = schema.execute(Request::new(query).data(challenger.clone())).await;
+        assert_eq!(res.errors, Vec::new());
+        assert_eq!(
+            res.data,
+            value!({ "chooseOpponent": { "state": "PENDING", "opponentId": opponent_id.to_string() } })
+        );
+
+        // the opponent has to accept before there is anything to report
+        let query = format!(r#"mutation {{ acceptBattle(battleId: "{}") {{ state }} }}"#, battle_id);
+        let res = schema.execute(Request::new(query.clone()).data(challenger.clone())).await;
+        assert_eq!(res.errors.len(), 1);
+        let res = schema.execute(Request::new(query).data(opponent.clone())).await;
+        assert_eq!(res.errors, Vec::new());
+        assert_eq!(res.data, value!({ "acceptBattle": { "state": "ACTIVE" } }));
+
+        let report = |winner_card_id: uuid::Uuid| format!(r#"mutation {{ finishBattle(battleId: "{}", winnerCardId: "{}") {{
+            state winnerCardId challengerReport opponentReport challengerRatingBefore challengerRatingAfter opponentRatingBefore opponentRatingAfter
+        }} }}"#, battle_id, winner_card_id);
+        let res = schema.execute(Request::new(report(card_ids[1])).data(challenger.clone())).await;
+        assert_eq!(res.errors, Vec::new());
+        assert_eq!(
+            res.data,
+            value!({ "finishBattle": {
+                "state": "ACTIVE",
+                "winnerCardId": null,
+                "challengerReport": "OPPONENT_WON",
+                "opponentReport": null,
+                "challengerRatingBefore": null,
+                "challengerRatingAfter": null,
+                "opponentRatingBefore": null,
+                "opponentRatingAfter": null,
+            } })
+        );
+
+        // conflicting reports change nothing until one side gives in
+        let res = schema.execute(Request::new(report(card_ids[0])).data(opponent.clone())).await;
+        assert_eq!(res.errors, Vec::new());
+        assert_eq!(res.data.into_json().unwrap()["finishBattle"]["state"].as_str(), Some("ACTIVE"));
+        let ratings: Vec<f64> = sqlx::query_scalar("SELECT rating FROM cards ORDER BY rating")
+            .fetch_all(&dbpool)
+            .await.unwrap();
+        assert_eq!(ratings, vec![1000.0, 1000.0]);
+
+        let res = schema.execute(Request::new(report(card_ids[1])).data(opponent.clone())).await;
+        assert_eq!(res.errors, Vec::new());
+        assert_eq!(
+            res.data,
+            value!({ "finishBattle": {
+                "state": "FINISHED",
+                "winnerCardId": card_ids[1].to_string(),
+                "challengerReport": "OPPONENT_WON",
+                "opponentReport": "OPPONENT_WON",
+                "challengerRatingBefore": 1000.0,
+                "challengerRatingAfter": 984.0,
+                "opponentRatingBefore": 1000.0,
+                "opponentRatingAfter": 1016.0,
+            } })
+        );
+        let ratings: Vec<f64> = sqlx::query_scalar("SELECT rating FROM cards ORDER BY rating")
+            .fetch_all(&dbpool)
+            .await.unwrap();
+        assert_eq!(ratings, vec![984.0, 1016.0]);
+
+        let query = format!(r#"mutation {{ abandonBattle(battleId: "{}") {{ state }} }}"#, battle_id);
+        let res = schema.execute(Request::new(query).data(opponent.clone())).await;
+        assert_eq!(
+            res.errors
+                .into_iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>(),
+            vec![r#"invalid request form. method="abandonBattle" detail="battle is already closed""#]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_matchmaking_queue() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = db.schema.clone();
+        let dbpool = db.pgpool;
+        let alice_id = uuid::Uuid::new_v4();
+        let bob_id = uuid::Uuid::new_v4();
+        for (user_id, email) in [(alice_id, "a"), (bob_id, "b")].iter() {
+            sqlx::query("INSERT INTO users (id, nickname, email, password) VALUES ($1, 'a', $2, 'c')")
+                .bind(user_id)
+                .bind(*email)
+                .execute(&dbpool)
+                .await.unwrap();
+        }
+        let mut card_ids = Vec::new();
+        for (user_id, rating) in [(alice_id, 1000.0), (alice_id, 1010.0), (bob_id, 1020.0), (bob_id, 1030.0)].iter() {
+            let card_id: uuid::Uuid = sqlx::query_scalar("INSERT INTO cards (rating, owned_at, owner_id) VALUES ($1, NOW(), $2) RETURNING id")
+                .bind(rating)
+                .bind(user_id)
+                .fetch_one(&dbpool)
+                .await.unwrap();
+            card_ids.push(card_id);
+        }
+        let alice = Session { user_id: alice_id, user_kind: UserKind::Normal, scopes: None, permissions: vec![Permission::Play] };
+        let bob = Session { user_id: bob_id, user_kind: UserKind::Normal, scopes: None, permissions: vec![Permission::Play] };
+
+        let join = |card_id: uuid::Uuid| format!(r#"mutation {{ joinQueue(cardId: "{}") {{ position battle {{ challengerCardId opponentCardId state }} }} }}"#, card_id);
+
+        let res = schema.execute(Request::new(join(card_ids[2])).data(alice.clone())).await;
+        assert_eq!(res.errors.len(), 1);
+
+        let res = schema.execute(Request::new(join(card_ids[0])).data(alice.clone())).await;
+        assert_eq!(res.errors, Vec::new());
+        assert_eq!(res.data, value!({ "joinQueue": { "position": 0, "battle": null } }));
+
+        // Never paired with a card of the same owner.
+        let res = schema.execute(Request::new(join(card_ids[1])).data(alice.clone())).await;
+        assert_eq!(res.errors, Vec::new());
+        assert_eq!(res.data, value!({ "joinQueue": { "position": 1, "battle": null } }));
+
+        // Closest rating wins.
+        let res = schema.execute(Request::new(join(card_ids[2])).data(bob.clone())).await;
+        assert_eq!(res.errors, Vec::new());
+        assert_eq!(
+            res.data,
+            value!({ "joinQueue": { "position": null, "battle": {
+                "challengerCardId": card_ids[2].to_string(),
+                "opponentCardId": card_ids[1].to_string(),
+                "state": "ACTIVE",
+            } } })
+        );
+
+        let query = format!(r#"query {{ a: queuePosition(cardId: "{}") b: queuePosition(cardId: "{}") }}"#, card_ids[0], card_ids[1]);
+        let res = schema.execute(Request::new(query)).await;
+        assert_eq!(res.data, value!({ "a": 0, "b": null }));
+
+        let query = format!(r#"mutation {{ leaveQueue(cardId: "{}") }}"#, card_ids[0]);
+        let res = schema.execute(Request::new(query.clone()).data(bob.clone())).await;
+        assert_eq!(res.errors.len(), 1);
+        let res = schema.execute(Request::new(query.clone()).data(alice.clone())).await;
+        assert_eq!(res.data, value!({ "leaveQueue": true }));
+        let res = schema.execute(Request::new(query).data(alice.clone())).await;
+        assert_eq!(res.data, value!({ "leaveQueue": false }));
+
+        // a queued card that enters a battle by hand leaves the queue
+        let res = schema.execute(Request::new(join(card_ids[0])).data(alice.clone())).await;
+        assert_eq!(res.data, value!({ "joinQueue": { "position": 0, "battle": null } }));
+        let query = format!(r#"mutation {{ startBattle(cardId: "{}") {{ state }} }}"#, card_ids[0]);
+        let res = schema.execute(Request::new(query).data(alice.clone())).await;
+        assert_eq!(res.errors, Vec::new());
+        let query = format!(r#"query {{ queuePosition(cardId: "{}") }}"#, card_ids[0]);
+        let res = schema.execute(Request::new(query)).await;
+        assert_eq!(res.data, value!({ "queuePosition": null }));
+    }
+
+    #[actix_rt::test]
+    async fn test_matchmaking_failed_claim() {
+        use crate::matchmaking;
+
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let dbpool = db.pgpool;
+        let mut redis_conn = db.redispool.get().await.unwrap();
+        let alice_id = uuid::Uuid::new_v4();
+        let bob_id = uuid::Uuid::new_v4();
+        let carol_id = uuid::Uuid::new_v4();
+        let mut card_ids = Vec::new();
+        for user_id in [alice_id, bob_id].iter() {
+            let card_id: uuid::Uuid = sqlx::query_scalar("INSERT INTO cards (rating, owned_at, owner_id) VALUES (1000, NOW(), $1) RETURNING id")
+                .bind(user_id)
+                .fetch_one(&dbpool)
+                .await.unwrap();
+            matchmaking::join(&mut redis_conn, card_id, *user_id, 1000.0).await.unwrap();
+            card_ids.push(card_id);
+        }
+        // Bob's card changes hands while it waits, so the battle can not be written.
+        sqlx::query("UPDATE cards SET owner_id = $1 WHERE id = $2")
+            .bind(carol_id)
+            .bind(card_ids[1])
+            .execute(&dbpool)
+            .await.unwrap();
+        let battle = matchmaking::try_match(&dbpool, &mut redis_conn, card_ids[0]).await.unwrap();
+        assert!(battle.is_none());
+        assert_eq!(matchmaking::position(&mut redis_conn, card_ids[0]).await.unwrap(), Some(0));
+        assert_eq!(matchmaking::position(&mut redis_conn, card_ids[1]).await.unwrap(), None);
+
+        // No cooldown is left behind, so the two can still meet.
+        matchmaking::join(&mut redis_conn, card_ids[1], carol_id, 1000.0).await.unwrap();
+        let battle = matchmaking::try_match(&dbpool, &mut redis_conn, card_ids[1]).await.unwrap().unwrap();
+        assert_eq!(battle.challenger_card_id, card_ids[1]);
+        assert_eq!(battle.opponent_card_id, Some(card_ids[0]));
+    }
+
+    #[actix_rt::test]
+    async fn test_battle_updated_subscription() {
+        use futures::StreamExt;
+
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = db.schema.clone();
+        let dbpool = db.pgpool;
+        let challenger_id = uuid::Uuid::new_v4();
+        let opponent_id = uuid::Uuid::new_v4();
+        let mut card_ids = Vec::new();
+        for user_id in [challenger_id, opponent_id].iter() {
+            let card_id: uuid::Uuid = sqlx::query_scalar("INSERT INTO cards (owned_at, owner_id) VALUES (NOW(), $1) RETURNING id")
+                .bind(user_id)
+                .fetch_one(&dbpool)
+                .await.unwrap();
+            card_ids.push(card_id);
+        }
+        let battle_id: uuid::Uuid = sqlx::query_scalar(
+            "INSERT INTO battles (state, challenger_id, challenger_card_id, opponent_id, opponent_card_id, opponent_report) VALUES ('active', $1, $2, $3, $4, 'challenger') RETURNING id")
+            .bind(challenger_id)
+            .bind(card_ids[0])
+            .bind(opponent_id)
+            .bind(card_ids[1])
+            .fetch_one(&dbpool)
+            .await.unwrap();
+        let challenger = Session { user_id: challenger_id, user_kind: UserKind::Normal, scopes: None, permissions: vec![Permission::Play] };
+
+        let query = format!(r#"subscription {{ battleUpdated(battleId: "{}") {{ state winnerCardId }} }}"#, battle_id);
+        let mut battle_updates = schema.execute_stream(Request::new(query));
+        let query = format!(r#"subscription {{ cardRatingChanged(cardId: "{}") {{ rating }} }}"#, card_ids[0]);
+        let mut rating_updates = schema.execute_stream(Request::new(query));
+        let finish = async {
+            actix_rt::time::sleep(std::time::Duration::from_millis(500)).await;
+            let query = format!(r#"mutation {{ finishBattle(battleId: "{}", winnerCardId: "{}") {{ state }} }}"#, battle_id, card_ids[0]);
+            schema.execute(Request::new(query).data(challenger.clone())).await
+        };
+        let (battle_update, rating_update, res) = futures::join!(battle_updates.next(), rating_updates.next(), finish);
+        assert_eq!(res.errors, Vec::new());
+        assert_eq!(
+            battle_update.unwrap().data,
+            value!({ "battleUpdated": { "state": "FINISHED", "winnerCardId": card_ids[0].to_string() } })
+        );
+        assert_eq!(
+            rating_update.unwrap().data,
+            value!({ "cardRatingChanged": { "rating": 1016.0 } })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_leaderboard_with_equal_ratings() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = db.schema.clone();
+        let dbpool = db.pgpool;
+        for rating in [1000.0, 1000.0, 1200.0, 1000.0, 900.0, 1000.0, 1000.0].iter() {
+            sqlx::query("INSERT INTO cards (rating, owned_at, owner_id) VALUES ($1, NOW(), $2)")
+                .bind(rating)
+                .bind(uuid::Uuid::new_v4())
+                .execute(&dbpool)
+                .await.unwrap();
+        }
+        let mut seen = Vec::new();
+        let mut after = String::new();
+        loop {
+            let query = format!(r#"query {{
+                leaderboard(first: 2{}) {{
+                    edges {{ node {{ id rating }} }}
+                    pageInfo {{ endCursor hasNextPage }}
+                }}
+            }}"#, after);
+            let res = schema.execute(query).await;
+            assert_eq!(res.errors, Vec::new());
+            let json = res.data.into_json().unwrap();
+            for edge in json["leaderboard"]["edges"].as_array().unwrap() {
+                seen.push((
+                    edge["node"]["rating"].as_f64().unwrap(),
+                    edge["node"]["id"].as_str().unwrap().to_string(),
+                ));
+            }
+            if !json["leaderboard"]["pageInfo"]["hasNextPage"].as_bool().unwrap() {
+                break;
+            }
+            after = format!(r#", after: "{}""#, json["leaderboard"]["pageInfo"]["endCursor"].as_str().unwrap());
+        }
+        assert_eq!(
+            seen.iter().map(|(rating, _)| *rating).collect::<Vec<_>>(),
+            vec![1200.0, 1000.0, 1000.0, 1000.0, 1000.0, 1000.0, 900.0]
+        );
+        let mut ids = seen.iter().map(|(_, id)| id.clone()).collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 7);
+        let mut tied = seen[1..6].iter().map(|(_, id)| id.clone()).collect::<Vec<_>>();
+        let expected = tied.clone();
+        tied.sort();
+        tied.reverse();
+        assert_eq!(tied, expected);
+
+        let query = r#"query {
+            leaderboard(last: 3) {
+                edges { node { rating } }
+                pageInfo { hasNextPage hasPreviousPage }
+            }
+        }"#;
+        let res = schema.execute(query).await;
+        assert_eq!(res.errors, Vec::new());
+        assert_eq!(
+            res.data,
+            value!({ "leaderboard": {
+                "edges": [
+                    { "node": { "rating": 1000.0 } },
+                    { "node": { "rating": 1000.0 } },
+                    { "node": { "rating": 900.0 } },
+                ],
+                "pageInfo": { "hasNextPage": false, "hasPreviousPage": true },
+            } })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_leaderboard_with_unplayed_cards() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = Schema::build(Query, Mutation, Subscription)
+            .data(db.pgpool.clone())
+            .data(std::sync::Arc::new(Glicko2::default()) as SharedRatingSystem)
+            .data(Tuning::default())
+            .finish();
+        // Conservatively 1600 - 2 * 200 = 1200, against 1500 - 2 * 350 = 800 for the unplayed card.
+        for (rating, deviation) in [(1500.0, None), (1600.0, Some(200.0))].iter() {
+            sqlx::query("INSERT INTO cards (rating, rating_deviation, owned_at, owner_id) VALUES ($1, $2, NOW(), $3)")
+                .bind(rating)
+                .bind(deviation)
+                .bind(uuid::Uuid::new_v4())
+                .execute(&db.pgpool)
+                .await.unwrap();
+        }
+        let query = r#"query { leaderboard(first: 1) {
+            edges { node { rating } }
+            pageInfo { endCursor }
+        } }"#;
+        let res = schema.execute(query).await;
+        assert_eq!(res.errors, Vec::new());
+        let json = res.data.into_json().unwrap();
+        assert_eq!(json["leaderboard"]["edges"][0]["node"]["rating"], 1600.0);
+        let query = format!(r#"query {{ leaderboard(first: 1, after: "{}") {{
+            edges {{ node {{ rating }} }}
+        }} }}"#, json["leaderboard"]["pageInfo"]["endCursor"].as_str().unwrap());
+        let res = schema.execute(query).await;
+        assert_eq!(res.errors, Vec::new());
+        assert_eq!(res.data, value!({ "leaderboard": { "edges": [{ "node": { "rating": 1500.0 } }] } }));
+    }
+
+    async fn collect_card_ids(schema: &Schema, user_id: uuid::Uuid, sort: &str, backward: bool) -> Vec<String> {
+        let mut ids = Vec::new();
+        let mut cursor = String::new();
+        loop {
+            let page = if backward {
+                format!("last: 4, sort: {}{}", sort, cursor)
+            } else {
+                format!("first: 4, sort: {}{}", sort, cursor)
+            };
+            let query = format!(r#"query {{
+                user(id: "{}") {{
+                    cards({}) {{
+                        edges {{ node {{ id }} }}
+                        pageInfo {{ startCursor endCursor hasNextPage hasPreviousPage }}
+                    }}
+                }}
+            }}"#, user_id, page);
+            let res = schema.execute(query).await;
+            assert_eq!(res.errors, Vec::new());
+            let json = res.data.into_json().unwrap();
+            let cards = &json["user"]["cards"];
+            let mut page_ids = cards["edges"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|edge| edge["node"]["id"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>();
+            assert!(page_ids.len() <= 4);
+            if backward {
+                page_ids.append(&mut ids);
+                ids = page_ids;
+                if !cards["pageInfo"]["hasPreviousPage"].as_bool().unwrap() {
+                    break;
+                }
+                cursor = format!(r#", before: "{}""#, cards["pageInfo"]["startCursor"].as_str().unwrap());
+            } else {
+                ids.append(&mut page_ids);
+                if !cards["pageInfo"]["hasNextPage"].as_bool().unwrap() {
+                    break;
+                }
+                cursor = format!(r#", after: "{}""#, cards["pageInfo"]["endCursor"].as_str().unwrap());
+            }
+        }
+        ids
+    }
+
+    #[actix_rt::test]
+    async fn test_cards_pagination_with_equal_values() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = db.schema.clone();
+        let user_id = uuid::Uuid::new_v4();
+        let dbpool = db.pgpool;
+        sqlx::query("INSERT INTO users (id, nickname, email, password) VALUES ($1, 'a', 'b', 'c')")
+            .bind(user_id)
+            .execute(&dbpool)
+            .await.unwrap();
+        let date = chrono::DateTime::parse_from_str("2020 Apr 13 12:09:14.274 +0000", "%Y %b %d %H:%M:%S%.3f %z").unwrap();
+        for _ in 0..25 {
+            sqlx::query("INSERT INTO cards (rating, owned_at, owner_id) VALUES (1000.0, $1, $2)")
+                .bind(date)
+                .bind(user_id)
+                .execute(&dbpool)
+                .await.unwrap();
+        }
+        let mut expected: Vec<String> = sqlx::query_scalar::<_, uuid::Uuid>("SELECT id FROM cards ORDER BY id")
+            .fetch_all(&dbpool)
+            .await.unwrap()
+            .into_iter()
+            .map(|id| id.to_string())
+            .collect();
+        expected.sort();
+        for sort in ["OWNED_AT", "RATING"].iter() {
+            for backward in [false, true].iter() {
+                let ids = collect_card_ids(&schema, user_id, sort, *backward).await;
+                assert_eq!(ids, expected, "sort={} backward={}", sort, backward);
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_card_ownership() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = db.schema.clone();
+        let dbpool = db.pgpool;
+        let alice_id = uuid::Uuid::new_v4();
+        let bob_id = uuid::Uuid::new_v4();
+        for (user_id, email) in [(alice_id, "a"), (bob_id, "b")].iter() {
+            sqlx::query("INSERT INTO users (id, nickname, email, password) VALUES ($1, 'a', $2, 'c')")
+                .bind(user_id)
+                .bind(*email)
+                .execute(&dbpool)
+                .await.unwrap();
+        }
+        let alice = Session { user_id: alice_id, user_kind: UserKind::Normal, scopes: None, permissions: vec![Permission::Play] };
+        let bob = Session { user_id: bob_id, user_kind: UserKind::Normal, scopes: None, permissions: vec![Permission::Play] };
+        let admin = Session { user_id: uuid::Uuid::new_v4(), user_kind: UserKind::Super, scopes: None, permissions: Permission::ALL.to_vec() };
+
+        let query = format!(r#"mutation {{ createCard(title: "dragon", ownerId: "{}") {{ id }} }}"#, bob_id);
+        let res = schema.execute(Request::new(query).data(alice.clone())).await;
+        assert_eq!(res.errors.len(), 1);
+
+        let query = format!(r#"mutation {{ createCard(title: "dragon", imageUrl: "dragon.png", ownerId: "{}") {{ id title imageUrl ownerId rating }} }}"#, alice_id);
+        let res = schema.execute(Request::new(query.clone()).data(alice.clone())).await;
+        assert_eq!(res.errors.len(), 1);
+        let res = schema.execute(Request::new(query).data(admin.clone())).await;
+        assert_eq!(res.errors, Vec::new());
+        let json = res.data.into_json().unwrap();
+        let card_id = json["createCard"]["id"].as_str().unwrap().to_string();
+        assert_eq!(json["createCard"]["title"].as_str(), Some("dragon"));
+        assert_eq!(json["createCard"]["imageUrl"].as_str(), Some("dragon.png"));
+        assert_eq!(json["createCard"]["ownerId"].as_str(), Some(alice_id.to_string().as_str()));
+        assert_eq!(json["createCard"]["rating"].as_f64(), Some(1000.0));
+        let minted_at: chrono::DateTime<chrono::Utc> = sqlx::query_scalar("SELECT owned_at FROM cards")
+            .fetch_one(&dbpool)
+            .await.unwrap();
+
+        let query = format!(r#"mutation {{ transferCard(cardId: "{}", toUserId: "{}") {{ ownerId }} }}"#, card_id, bob_id);
+        let res = schema.execute(Request::new(query.clone()).data(bob.clone())).await;
+        assert_eq!(res.errors.len(), 1);
+        let res = schema.execute(Request::new(query).data(alice.clone())).await;
+        assert_eq!(res.errors, Vec::new());
+        assert_eq!(res.data, value!({ "transferCard": { "ownerId": bob_id.to_string() } }));
+        let transferred_at: chrono::DateTime<chrono::Utc> = sqlx::query_scalar("SELECT owned_at FROM cards")
+            .fetch_one(&dbpool)
+            .await.unwrap();
+        assert!(transferred_at > minted_at);
+
+        let query = format!(r#"mutation {{ releaseCard(cardId: "{}") {{ ownerId }} }}"#, card_id);
+        let res = schema.execute(Request::new(query.clone()).data(alice.clone())).await;
+        assert_eq!(
+            res.errors
+                .into_iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>(),
+            vec!["not authorized to do such request"]
+        );
+
+        let query = format!(r#"mutation {{ transferCard(cardId: "{}", toUserId: "{}") {{ ownerId }} }}"#, card_id, alice_id);
+        let res = schema.execute(Request::new(query).data(admin.clone())).await;
+        assert_eq!(res.data, value!({ "transferCard": { "ownerId": alice_id.to_string() } }));
+
+        let query = format!(r#"mutation {{ releaseCard(cardId: "{}") {{ ownerId }} }}"#, card_id);
+        let res = schema.execute(Request::new(query).data(alice.clone())).await;
+        assert_eq!(res.errors, Vec::new());
+        assert_eq!(res.data, value!({ "releaseCard": { "ownerId": null } }));
+    }
+
+    #[actix_rt::test]
+    async fn test_card_ownership_history() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let schema = db.schema.clone();
+        let dbpool = db.pgpool;
+        let alice_id = uuid::Uuid::new_v4();
+        let bob_id = uuid::Uuid::new_v4();
+        for (user_id, email) in [(alice_id, "a"), (bob_id, "b")].iter() {
+            sqlx::query("INSERT INTO users (id, nickname, email, password) VALUES ($1, 'a', $2, 'c')")
+                .bind(user_id)
+                .bind(*email)
+                .execute(&dbpool)
+                .await.unwrap();
+        }
+        let alice = Session { user_id: alice_id, user_kind: UserKind::Normal, scopes: None, permissions: vec![Permission::Play] };
+        let bob = Session { user_id: bob_id, user_kind: UserKind::Normal, scopes: None, permissions: vec![Permission::Play] };
+        let admin = Session { user_id: uuid::Uuid::new_v4(), user_kind: UserKind::Super, scopes: None, permissions: Permission::ALL.to_vec() };
+
+        let query = format!(r#"mutation {{ createCard(title: "dragon", ownerId: "{}") {{ id }} }}"#, alice_id);
+        let res = schema.execute(Request::new(query).data(admin)).await;
+        assert_eq!(res.errors, Vec::new());
+        let card_id = res.data.into_json().unwrap()["createCard"]["id"].as_str().unwrap().to_string();
+        let query = format!(r#"mutation {{ transferCard(cardId: "{}", toUserId: "{}") {{ id }} }}"#, card_id, bob_id);
+        let res = schema.execute(Request::new(query).data(alice.clone())).await;
+        assert_eq!(res.errors, Vec::new());
+        let query = format!(r#"mutation {{ releaseCard(cardId: "{}") {{ id }} }}"#, card_id);
+        let res = schema.execute(Request::new(query).data(bob.clone())).await;
+        assert_eq!(res.errors, Vec::new());
+
+        let query = r#"query { leaderboard { edges { node {
+            ownershipHistory(first: 2) {
+                edges { node { previousOwnerId newOwnerId reason } }
+                pageInfo { endCursor hasNextPage }
+            }
+        } } } }"#;
+        let res = schema.execute(query).await;
+        assert_eq!(res.errors, Vec::new());
+        let json = res.data.into_json().unwrap();
+        let history = &json["leaderboard"]["edges"][0]["node"]["ownershipHistory"];
+        assert_eq!(
+            history["edges"],
+            serde_json::json!([
+                { "node": { "previousOwnerId": null, "newOwnerId": alice_id.to_string(), "reason": "MINT" } },
+                { "node": { "previousOwnerId": alice_id.to_string(), "newOwnerId": bob_id.to_string(), "reason": "TRANSFER" } },
+            ])
+        );
+        assert_eq!(history["pageInfo"]["hasNextPage"].as_bool(), Some(true));
+
+        let query = format!(r#"query {{ leaderboard {{ edges {{ node {{
+            ownershipHistory(after: "{}") {{
+                edges {{ node {{ previousOwnerId newOwnerId reason }} }}
+                pageInfo {{ hasNextPage }}
+            }}
+        }} }} }} }}"#, history["pageInfo"]["endCursor"].as_str().unwrap());
+        let res = schema.execute(query).await;
+        assert_eq!(res.errors, Vec::new());
+        let json = res.data.into_json().unwrap();
+        assert_eq!(
+            json["leaderboard"]["edges"][0]["node"]["ownershipHistory"],
+            serde_json::json!({
+                "edges": [{ "node": { "previousOwnerId": bob_id.to_string(), "newOwnerId": null, "reason": "RELEASE" } }],
+                "pageInfo": { "hasNextPage": false },
+            })
+        );
+
+        let res = sqlx::query("DELETE FROM card_ownerships").execute(&dbpool).await;
+        assert!(res.is_err());
+    }
 }
@@ -0,0 +1,438 @@
+use crate::error::Error;
+use crate::model::{DbPool, User};
+use crate::session::Session;
+use crate::token::{consume_refresh_token, issue_access_token, issue_tokens, Grant, JwtKeys};
+use crate::util::{
+    hash_password, put_temporary, random_string, take_temporary, verify_password,
+};
+use crate::validation;
+use async_graphql::{Enum, SimpleObject};
+use chrono::Utc;
+use deadpool_redis::ConnectionWrapper as RedisConn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+type DateTime = chrono::DateTime<Utc>;
+
+const CLIENT_SECRET_LENGTH: usize = 40;
+const CODE_LENGTH: usize = 30;
+const AUTHORIZATION_CODE_LIFETIME_SECONDS: i64 = 60;
+const CONSENT_LIFETIME_SECONDS: i64 = 60 * 10;
+
+/// What a third-party app may do on behalf of a player.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Enum)]
+pub enum Scope {
+    /// Read the player's email.
+    #[serde(rename = "profile:read")]
+    ProfileRead,
+    /// Cards are public, so this only tells the player what the app looks at.
+    #[serde(rename = "cards:read")]
+    CardsRead,
+    /// Mint, transfer and release cards.
+    #[serde(rename = "cards:write")]
+    CardsWrite,
+    /// Get notified of matches found for the player.
+    #[serde(rename = "battles:read")]
+    BattlesRead,
+    /// Start, finish and queue for battles.
+    #[serde(rename = "battles:write")]
+    BattlesWrite,
+}
+
+impl Scope {
+    const ALL: [Scope; 5] = [
+        Scope::ProfileRead,
+        Scope::CardsRead,
+        Scope::CardsWrite,
+        Scope::BattlesRead,
+        Scope::BattlesWrite,
+    ];
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ProfileRead => "profile:read",
+            Scope::CardsRead => "cards:read",
+            Scope::CardsWrite => "cards:write",
+            Scope::BattlesRead => "battles:read",
+            Scope::BattlesWrite => "battles:write",
+        }
+    }
+    /// Parse a space separated `scope` parameter.
+    pub fn parse_list(s: &str) -> Option<Vec<Scope>> {
+        s.split_whitespace()
+            .map(|name| Scope::ALL.iter().copied().find(|scope| scope.as_str() == name))
+            .collect()
+    }
+    pub fn format_list(scopes: &[Scope]) -> String {
+        scopes
+            .iter()
+            .map(Scope::as_str)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// A registered third-party app.
+#[derive(sqlx::FromRow, Clone, Debug, PartialEq, SimpleObject)]
+pub struct OauthClient {
+    pub id: Uuid,
+    #[graphql(skip)]
+    pub secret: String,
+    pub name: String,
+    pub owner_id: Uuid,
+    /// Where players may be sent back to after the consent page. Must match exactly.
+    pub redirect_uris: Vec<String>,
+    /// Most a player can grant the app.
+    #[graphql(skip)]
+    pub scopes: Vec<String>,
+    pub created_at: DateTime,
+}
+
+impl OauthClient {
+    pub async fn fetch(dbpool: &DbPool, id: Uuid) -> Result<OauthClient, Error> {
+        sqlx::query_as::<_, OauthClient>("SELECT * FROM oauth_clients WHERE id = $1")
+            .bind(id)
+            .fetch_optional(dbpool)
+            .await?
+            .ok_or(Error::Oauth("invalid_client"))
+    }
+    /// Client of the token endpoint, checked against its secret.
+    async fn authenticate(dbpool: &DbPool, id: Uuid, secret: &str) -> Result<OauthClient, Error> {
+        let client = OauthClient::fetch(dbpool, id).await?;
+        if verify_password(secret, &client.secret)? {
+            Ok(client)
+        } else {
+            Err(Error::Oauth("invalid_client"))
+        }
+    }
+    fn allows(&self, scopes: &[Scope]) -> bool {
+        scopes
+            .iter()
+            .all(|scope| self.scopes.iter().any(|allowed| allowed == scope.as_str()))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, SimpleObject)]
+pub struct OauthClientCredentials {
+    pub client: OauthClient,
+    /// Shown only once. Only a hash is kept.
+    pub client_secret: String,
+}
+
+pub async fn register_client(
+    dbpool: &DbPool,
+    owner_id: Uuid,
+    name: String,
+    redirect_uris: Vec<String>,
+    scopes: Vec<Scope>,
+) -> Result<OauthClientCredentials, Error> {
+    let client_secret = random_string(CLIENT_SECRET_LENGTH);
+    let scopes: Vec<&str> = scopes.iter().map(Scope::as_str).collect();
+    // secrets are long and random, so the default cost is plenty
+    let client = sqlx::query_as::<_, OauthClient>("INSERT INTO oauth_clients (secret, name, owner_id, redirect_uris, scopes) VALUES ($1, $2, $3, $4, $5) RETURNING *")
+        .bind(hash_password(client_secret.clone(), bcrypt::DEFAULT_COST)?)
+        .bind(name)
+        .bind(owner_id)
+        .bind(redirect_uris)
+        .bind(scopes)
+        .fetch_one(dbpool)
+        .await?;
+    Ok(OauthClientCredentials {
+        client,
+        client_secret,
+    })
+}
+
+/// Query of `GET /oauth/authorize`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct AuthorizeParams {
+    pub response_type: String,
+    pub client_id: Uuid,
+    pub redirect_uri: String,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+}
+
+/// An authorization request waiting for the player to allow or deny it.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct PendingAuthorization {
+    pub user_id: Uuid,
+    pub client_id: Uuid,
+    pub client_name: String,
+    pub redirect_uri: String,
+    pub scopes: Vec<Scope>,
+    pub state: Option<String>,
+    pub code_challenge: Option<String>,
+}
+
+impl PendingAuthorization {
+    /// Where to send the player back to with the outcome.
+    pub fn redirect_with(&self, params: &[(&str, &str)]) -> String {
+        let mut params = params.to_vec();
+        if let Some(state) = &self.state {
+            params.push(("state", state.as_str()));
+        }
+        let separator = if self.redirect_uri.contains('?') { '&' } else { '?' };
+        format!(
+            "{}{}{}",
+            self.redirect_uri,
+            separator,
+            serde_urlencoded::to_string(params).unwrap_or_default()
+        )
+    }
+}
+
+/// Why an authorization request failed.
+#[derive(Debug)]
+pub enum AuthorizeError {
+    /// The client or redirect uri can not be trusted, so the player must not be sent there.
+    Invalid(Error),
+    /// Reported back to the client by redirecting with an `error` parameter.
+    Redirect(String),
+}
+
+fn consent_key(consent_id: &str) -> String {
+    format!("oauth-consent/{}", consent_id)
+}
+
+fn code_key(code: &str) -> String {
+    format!("oauth-code/{}", code)
+}
+
+/// Check an authorization request and park it until the player decides. Returns the id of
+/// the pending request to put in the consent form.
+pub async fn start_authorization(
+    dbpool: &DbPool,
+    redis_conn: &mut RedisConn,
+    session: &Session,
+    params: AuthorizeParams,
+) -> Result<(String, PendingAuthorization), AuthorizeError> {
+    let client = OauthClient::fetch(dbpool, params.client_id)
+        .await
+        .map_err(AuthorizeError::Invalid)?;
+    // clients registered before redirect uris were validated may still hold bad ones
+    if !client.redirect_uris.contains(&params.redirect_uri)
+        || validation::redirect_uri(&params.redirect_uri).is_err()
+    {
+        return Err(AuthorizeError::Invalid(Error::Oauth("invalid_request")));
+    }
+    let mut pending = PendingAuthorization {
+        user_id: session.user_id,
+        client_id: client.id,
+        client_name: client.name.clone(),
+        redirect_uri: params.redirect_uri,
+        scopes: Vec::new(),
+        state: params.state,
+        code_challenge: params.code_challenge,
+    };
+    let fail = |pending: &PendingAuthorization, error: &str| {
+        AuthorizeError::Redirect(pending.redirect_with(&[("error", error)]))
+    };
+    if params.response_type != "code" {
+        return Err(fail(&pending, "unsupported_response_type"));
+    }
+    // only S256 is supported, plain challenges would leak the verifier
+    if pending.code_challenge.is_some() && params.code_challenge_method.as_deref() != Some("S256") {
+        return Err(fail(&pending, "invalid_request"));
+    }
+    pending.scopes = match params.scope.as_deref().and_then(Scope::parse_list) {
+        Some(scopes) if !scopes.is_empty() && client.allows(&scopes) => scopes,
+        _ => return Err(fail(&pending, "invalid_scope")),
+    };
+    let consent_id = random_string(CODE_LENGTH);
+    put_temporary(redis_conn, &consent_key(&consent_id), &pending, CONSENT_LIFETIME_SECONDS)
+        .await
+        .map_err(AuthorizeError::Invalid)?;
+    Ok((consent_id, pending))
+}
+
+/// Apply the player's decision on the consent page. Returns where to redirect the player.
+pub async fn finish_authorization(
+    redis_conn: &mut RedisConn,
+    session: &Session,
+    consent_id: &str,
+    allow: bool,
+) -> Result<String, Error> {
+    let pending: PendingAuthorization = take_temporary(redis_conn, &consent_key(consent_id))
+        .await?
+        .ok_or(Error::Oauth("invalid_request"))?;
+    if pending.user_id != session.user_id {
+        return Err(Error::NotAuthorized);
+    }
+    if !allow {
+        return Ok(pending.redirect_with(&[("error", "access_denied")]));
+    }
+    let code = random_string(CODE_LENGTH);
+    put_temporary(redis_conn, &code_key(&code), &pending, AUTHORIZATION_CODE_LIFETIME_SECONDS).await?;
+    Ok(pending.redirect_with(&[("code", &code)]))
+}
+
+/// Form of `POST /oauth/token`. The client id and secret may come in the form or in an
+/// `Authorization: Basic` header instead, but not both.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct TokenParams {
+    pub grant_type: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub refresh_token: Option<String>,
+    /// For `client_credentials`. Defaults to every scope the client was registered with.
+    pub scope: Option<String>,
+}
+
+/// Body of a successful `POST /oauth/token`, as in RFC 6749.
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i32,
+    /// None for `client_credentials`, as the client can always ask again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    pub scope: String,
+}
+
+impl TokenResponse {
+    fn new(access_token: String, expires_in: i32, refresh_token: Option<String>, scope: String) -> Self {
+        TokenResponse {
+            access_token,
+            token_type: "Bearer",
+            expires_in,
+            refresh_token,
+            scope,
+        }
+    }
+}
+
+/// Client id and secret of an `Authorization: Basic` header. Both are form encoded before
+/// being joined, as RFC 6749 section 2.3.1 asks.
+pub fn basic_credentials(authorization: &str) -> Option<(String, String)> {
+    let encoded = authorization.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (id, secret) = decoded.split_once(':')?;
+    let form_decode = |value: &str| {
+        let mut pairs: Vec<(String, String)> = serde_urlencoded::from_str(&format!("v={}", value)).ok()?;
+        match pairs.len() {
+            1 => pairs.pop().map(|(_, value)| value),
+            _ => None,
+        }
+    };
+    Some((form_decode(id)?, form_decode(secret)?))
+}
+
+fn verify_code_challenge(challenge: &str, verifier: &str) -> bool {
+    base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD) == challenge
+}
+
+pub async fn exchange_token(
+    dbpool: &DbPool,
+    redis_conn: &mut RedisConn,
+    keys: &JwtKeys,
+    basic: Option<(String, String)>,
+    params: TokenParams,
+) -> Result<TokenResponse, Error> {
+    let (client_id, client_secret) = match (basic, params.client_id, params.client_secret) {
+        (Some(basic), None, None) => basic,
+        (None, Some(id), Some(secret)) => (id, secret),
+        (Some(_), _, _) => return Err(Error::Oauth("invalid_request")),
+        _ => return Err(Error::Oauth("invalid_client")),
+    };
+    let client_id = Uuid::parse_str(&client_id).map_err(|_| Error::Oauth("invalid_client"))?;
+    let client = OauthClient::authenticate(dbpool, client_id, &client_secret).await?;
+    let (user_id, grant) = match params.grant_type.as_str() {
+        "authorization_code" => {
+            let code = params.code.as_deref().ok_or(Error::Oauth("invalid_request"))?;
+            let pending: PendingAuthorization = take_temporary(redis_conn, &code_key(code))
+                .await?
+                .ok_or(Error::Oauth("invalid_grant"))?;
+            if pending.client_id != client.id
+                || params.redirect_uri.as_ref() != Some(&pending.redirect_uri)
+            {
+                return Err(Error::Oauth("invalid_grant"));
+            }
+            if let Some(challenge) = &pending.code_challenge {
+                match &params.code_verifier {
+                    Some(verifier) if verify_code_challenge(challenge, verifier) => {}
+                    _ => return Err(Error::Oauth("invalid_grant")),
+                }
+            }
+            let grant = Grant {
+                client_id: client.id,
+                scopes: pending.scopes,
+            };
+            (pending.user_id, grant)
+        }
+        "refresh_token" => {
+            let token = params.refresh_token.as_deref().ok_or(Error::Oauth("invalid_request"))?;
+            match consume_refresh_token(redis_conn, keys, token).await {
+                Ok((user_id, Some(grant))) if grant.client_id == client.id => (user_id, grant),
+                _ => return Err(Error::Oauth("invalid_grant")),
+            }
+        }
+        // the client acts for its owner, within the scopes it was registered with
+        "client_credentials" => {
+            let scopes = match params.scope.as_deref() {
+                Some(scope) => Scope::parse_list(scope).ok_or(Error::Oauth("invalid_scope"))?,
+                None => client.scopes.iter().filter_map(|scope| Scope::parse_list(scope)).flatten().collect(),
+            };
+            if scopes.is_empty() || !client.allows(&scopes) {
+                return Err(Error::Oauth("invalid_scope"));
+            }
+            let grant = Grant {
+                client_id: client.id,
+                scopes,
+            };
+            (client.owner_id, grant)
+        }
+        _ => return Err(Error::Oauth("unsupported_grant_type")),
+    };
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(dbpool)
+        .await?;
+    let scope = Scope::format_list(&grant.scopes);
+    if params.grant_type == "client_credentials" {
+        let (access_token, expires_in) = issue_access_token(dbpool, keys, &user, &grant).await?;
+        return Ok(TokenResponse::new(access_token, expires_in, None, scope));
+    }
+    let tokens = issue_tokens(dbpool, redis_conn, keys, &user, Some(&grant)).await?;
+    Ok(TokenResponse::new(tokens.access_token, tokens.expires_in, Some(tokens.refresh_token), scope))
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Page asking the player whether to let the app act for them.
+pub fn consent_page(consent_id: &str, pending: &PendingAuthorization) -> String {
+    let scopes: String = pending
+        .scopes
+        .iter()
+        .map(|scope| format!("<li>{}</li>", scope.as_str()))
+        .collect();
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<body>
+<p><b>{client}</b> wants to:</p>
+<ul>{scopes}</ul>
+<form method="post" action="/oauth/authorize">
+<input type="hidden" name="consent_id" value="{consent_id}">
+<button type="submit" name="decision" value="allow">Allow</button>
+<button type="submit" name="decision" value="deny">Deny</button>
+</form>
+</body>
+</html>"#,
+        client = escape_html(&pending.client_name),
+        scopes = scopes,
+        consent_id = escape_html(consent_id),
+    )
+}
@@ -0,0 +1,378 @@
+use crate::error::Error;
+use crate::model::{DbPool, User};
+use crate::util::{put_temporary, random_string, take_temporary};
+use crate::validation;
+use async_graphql::SimpleObject;
+use chrono::Utc;
+use deadpool_redis::ConnectionWrapper as RedisConn;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+type DateTime = chrono::DateTime<Utc>;
+
+const LOGIN_STATE_LIFETIME_SECONDS: i64 = 60 * 10;
+const STATE_LENGTH: usize = 30;
+const GENERATED_NICKNAME_SUFFIX_LENGTH: usize = 8;
+
+/// `Set-Cookie` value that makes the browser drop the login state cookie.
+pub const CLEARED_STATE_COOKIE: &str =
+    "oidc-state=; Secure; HttpOnly; SameSite=Lax; Path=/auth/oidc; Expires=Thu, 01 Jan 1970 00:00:00 GMT";
+
+/// An external identity provider players can sign in with.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct OidcProvider {
+    /// Used in urls, as in `/auth/oidc/{name}/login`.
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Our `/auth/oidc/{name}/callback`, as registered with the provider.
+    pub redirect_uri: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OidcProviders(pub Vec<OidcProvider>);
+
+impl OidcProviders {
+    pub fn get(&self, name: &str) -> Option<&OidcProvider> {
+        self.0.iter().find(|provider| provider.name == name)
+    }
+}
+
+/// The parts of the provider metadata at `/.well-known/openid-configuration` we use.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+struct Jwk {
+    kid: Option<String>,
+    kty: String,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    name: Option<String>,
+}
+
+/// A login in progress, kept between the redirect to the provider and the callback.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+struct LoginState {
+    provider: String,
+    nonce: String,
+    code_verifier: String,
+    /// Set when an existing account is linking the provider instead of logging in.
+    link_user_id: Option<Uuid>,
+}
+
+/// An external account linked to a user.
+#[derive(sqlx::FromRow, Clone, Debug, PartialEq, SimpleObject)]
+pub struct Identity {
+    #[graphql(skip)]
+    pub id: Uuid,
+    #[graphql(skip)]
+    pub user_id: Uuid,
+    pub provider: String,
+    /// Id of the account at the provider.
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: DateTime,
+}
+
+/// What the callback ended up doing.
+pub enum LoginOutcome {
+    LoggedIn(User),
+    Linked(Identity),
+}
+
+fn login_state_key(state: &str) -> String {
+    format!("oidc-state/{}", state)
+}
+
+fn state_hash(state: &str) -> String {
+    base64::encode_config(Sha256::digest(state.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// `Set-Cookie` value tying a login to the browser that started it, so a callback with
+/// someone else's state is refused. Lax, as the provider sends the player back cross-site.
+fn state_cookie(state: &str) -> String {
+    format!(
+        "oidc-state={}; Secure; HttpOnly; SameSite=Lax; Path=/auth/oidc; Max-Age={}",
+        state_hash(state),
+        LOGIN_STATE_LIFETIME_SECONDS
+    )
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(url: &str) -> Result<T, Error> {
+    let mut response = awc::Client::default()
+        .get(url)
+        .send()
+        .await
+        .map_err(|err| Error::HttpClientError(err.to_string()))?;
+    response
+        .json::<T>()
+        .await
+        .map_err(|err| Error::HttpClientError(err.to_string()))
+}
+
+async fn discover(provider: &OidcProvider) -> Result<Discovery, Error> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        provider.issuer.trim_end_matches('/')
+    );
+    let discovery: Discovery = get_json(&url).await?;
+    if discovery.issuer != provider.issuer {
+        return Err(Error::HttpClientError(format!(
+            "issuer mismatch: {}",
+            discovery.issuer
+        )));
+    }
+    Ok(discovery)
+}
+
+/// Where to send the player to log in at the provider, and the `Set-Cookie` value to send
+/// along.
+pub async fn start_login(
+    redis_conn: &mut RedisConn,
+    provider: &OidcProvider,
+    link_user_id: Option<Uuid>,
+) -> Result<(String, String), Error> {
+    let discovery = discover(provider).await?;
+    let state = random_string(STATE_LENGTH);
+    let login_state = LoginState {
+        provider: provider.name.clone(),
+        nonce: random_string(STATE_LENGTH),
+        code_verifier: random_string(64),
+        link_user_id,
+    };
+    put_temporary(
+        redis_conn,
+        &login_state_key(&state),
+        &login_state,
+        LOGIN_STATE_LIFETIME_SECONDS,
+    )
+    .await?;
+    let code_challenge = base64::encode_config(
+        Sha256::digest(login_state.code_verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    );
+    let query = serde_urlencoded::to_string(&[
+        ("response_type", "code"),
+        ("client_id", provider.client_id.as_str()),
+        ("redirect_uri", provider.redirect_uri.as_str()),
+        ("scope", "openid email profile"),
+        ("state", state.as_str()),
+        ("nonce", login_state.nonce.as_str()),
+        ("code_challenge", code_challenge.as_str()),
+        ("code_challenge_method", "S256"),
+    ])
+    .map_err(|err| Error::HttpClientError(err.to_string()))?;
+    let separator = if discovery.authorization_endpoint.contains('?') { '&' } else { '?' };
+    let location = format!("{}{}{}", discovery.authorization_endpoint, separator, query);
+    Ok((location, state_cookie(&state)))
+}
+
+/// Exchange the code for an id token and check its signature, issuer, audience and nonce.
+async fn verify_login(
+    provider: &OidcProvider,
+    login_state: &LoginState,
+    code: &str,
+) -> Result<IdTokenClaims, Error> {
+    let discovery = discover(provider).await?;
+    let mut response = awc::Client::default()
+        .post(&discovery.token_endpoint)
+        .send_form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", provider.redirect_uri.as_str()),
+            ("client_id", provider.client_id.as_str()),
+            ("client_secret", provider.client_secret.as_str()),
+            ("code_verifier", login_state.code_verifier.as_str()),
+        ])
+        .await
+        .map_err(|err| Error::HttpClientError(err.to_string()))?;
+    if !response.status().is_success() {
+        return Err(Error::NotAuthorized);
+    }
+    let tokens: TokenResponse = response
+        .json()
+        .await
+        .map_err(|err| Error::HttpClientError(err.to_string()))?;
+
+    let kid = decode_header(&tokens.id_token)?.kid;
+    let jwks: Jwks = get_json(&discovery.jwks_uri).await?;
+    let key = jwks
+        .keys
+        .iter()
+        .filter(|key| key.kty == "RSA")
+        .find(|key| kid.is_none() || key.kid == kid)
+        .ok_or(Error::NotAuthorized)?;
+    let (n, e) = match (&key.n, &key.e) {
+        (Some(n), Some(e)) => (n, e),
+        _ => return Err(Error::NotAuthorized),
+    };
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.iss = Some(discovery.issuer);
+    validation.set_audience(&[&provider.client_id]);
+    let claims = decode::<IdTokenClaims>(
+        &tokens.id_token,
+        &DecodingKey::from_rsa_components(n, e),
+        &validation,
+    )?
+    .claims;
+    if claims.nonce.as_ref() != Some(&login_state.nonce) {
+        return Err(Error::NotAuthorized);
+    }
+    Ok(claims)
+}
+
+/// Handle the provider redirecting the player back with a code. Logs in, signing up on the
+/// first visit, or links the provider to the account that started the flow. `state_cookie`
+/// is the `oidc-state` cookie the browser sent.
+pub async fn finish_login(
+    dbpool: &DbPool,
+    redis_conn: &mut RedisConn,
+    provider: &OidcProvider,
+    state: &str,
+    state_cookie: Option<&str>,
+    code: &str,
+) -> Result<LoginOutcome, Error> {
+    if state_cookie != Some(state_hash(state).as_str()) {
+        return Err(Error::NotAuthorized);
+    }
+    let login_state: LoginState = take_temporary(redis_conn, &login_state_key(state))
+        .await?
+        .filter(|login_state: &LoginState| login_state.provider == provider.name)
+        .ok_or(Error::NotAuthorized)?;
+    let claims = verify_login(provider, &login_state, code).await?;
+
+    if let Some(user_id) = login_state.link_user_id {
+        let identity = sqlx::query_as::<_, Identity>("INSERT INTO user_identities (user_id, provider, subject, email) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING RETURNING *")
+            .bind(user_id)
+            .bind(&provider.name)
+            .bind(&claims.sub)
+            .bind(&claims.email)
+            .fetch_optional(dbpool)
+            .await?
+            .ok_or(Error::BadRequest(
+                "oidcCallback",
+                "the account at the provider is already linked",
+            ))?;
+        return Ok(LoginOutcome::Linked(identity));
+    }
+
+    let user = sqlx::query_as::<_, User>("SELECT users.* FROM users JOIN user_identities ON users.id = user_identities.user_id WHERE provider = $1 AND subject = $2")
+        .bind(&provider.name)
+        .bind(&claims.sub)
+        .fetch_optional(dbpool)
+        .await?;
+    if let Some(user) = user {
+        return Ok(LoginOutcome::LoggedIn(user));
+    }
+
+    // sign up. an existing account with the same email has to link the provider itself,
+    // or anyone controlling an identity provider could take it over
+    let email = claims.email.clone().ok_or(Error::BadRequest(
+        "oidcCallback",
+        "the provider did not share an email",
+    ))?;
+    // an unverified address could belong to someone else
+    if !claims.email_verified {
+        return Err(Error::BadRequest(
+            "oidcCallback",
+            "the provider has not verified the email",
+        ));
+    }
+    if validation::email(&email).is_err() {
+        return Err(Error::BadRequest(
+            "oidcCallback",
+            "the provider shared an email that is not valid",
+        ));
+    }
+    let nickname = match claims.name.as_deref() {
+        Some(name) if validation::nickname(name).is_ok() => name.to_string(),
+        _ => format!(
+            "player-{}",
+            random_string(GENERATED_NICKNAME_SUFFIX_LENGTH).to_lowercase()
+        ),
+    };
+    let mut tx = dbpool.begin().await?;
+    let user = sqlx::query_as::<_, User>("INSERT INTO users (email, password, nickname, email_verified_at) VALUES ($1, '', $2, NOW()) ON CONFLICT (lower(email)) DO NOTHING RETURNING *")
+        .bind(&email)
+        .bind(&nickname)
+        .fetch_optional(&mut tx)
+        .await?
+        .ok_or(Error::BadRequest(
+            "oidcCallback",
+            "email already registered. log in and link the provider instead",
+        ))?;
+    sqlx::query("INSERT INTO user_identities (user_id, provider, subject, email) VALUES ($1, $2, $3, $4)")
+        .bind(user.id)
+        .bind(&provider.name)
+        .bind(&claims.sub)
+        .bind(&claims.email)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(LoginOutcome::LoggedIn(user))
+}
+
+pub async fn list_identities(dbpool: &DbPool, user_id: Uuid) -> Result<Vec<Identity>, Error> {
+    Ok(sqlx::query_as::<_, Identity>(
+        "SELECT * FROM user_identities WHERE user_id = $1 ORDER BY created_at",
+    )
+    .bind(user_id)
+    .fetch_all(dbpool)
+    .await?)
+}
+
+/// Unlink a provider. The last one can not be unlinked from an account without a password,
+/// since there would be no way left to log in.
+pub async fn unlink_identity(dbpool: &DbPool, user_id: Uuid, provider: &str) -> Result<bool, Error> {
+    let mut tx = dbpool.begin().await?;
+    let has_password = sqlx::query_scalar::<_, bool>("SELECT password <> '' FROM users WHERE id = $1 FOR UPDATE")
+        .bind(user_id)
+        .fetch_one(&mut tx)
+        .await?;
+    let removed = sqlx::query("DELETE FROM user_identities WHERE user_id = $1 AND provider = $2")
+        .bind(user_id)
+        .bind(provider)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    let remaining = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM user_identities WHERE user_id = $1")
+        .bind(user_id)
+        .fetch_one(&mut tx)
+        .await?;
+    if !has_password && remaining == 0 {
+        return Err(Error::BadRequest(
+            "unlinkIdentity",
+            "the last provider of an account without a password can not be unlinked",
+        ));
+    }
+    tx.commit().await?;
+    Ok(removed > 0)
+}
@@ -0,0 +1,165 @@
+use crate::error::Error;
+use crate::model::DbPool;
+use crate::session::Session;
+use async_graphql::{guard::Guard, Context, Enum, ErrorExtensions, SimpleObject};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Something a user may do, granted through their roles. Roles and what they grant are
+/// stored in postgres.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Enum)]
+pub enum Permission {
+    /// Own cards and battle with them. Banned users lack it.
+    #[serde(rename = "play")]
+    Play,
+    /// Read the private fields of any user.
+    #[serde(rename = "profile:read-any")]
+    ProfileReadAny,
+    /// Mint cards for anyone, and act as the owner of any card.
+    #[serde(rename = "cards:manage")]
+    CardsManage,
+    /// Finish and abandon battles of other users.
+    #[serde(rename = "battles:manage")]
+    BattlesManage,
+    /// See failed logins and lockouts.
+    #[serde(rename = "lockout:read")]
+    LockoutRead,
+    /// Grant and revoke roles.
+    #[serde(rename = "roles:manage")]
+    RolesManage,
+    /// See the details of internal errors.
+    #[serde(rename = "errors:detail")]
+    ErrorDetails,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 7] = [
+        Permission::Play,
+        Permission::ProfileReadAny,
+        Permission::CardsManage,
+        Permission::BattlesManage,
+        Permission::LockoutRead,
+        Permission::RolesManage,
+        Permission::ErrorDetails,
+    ];
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::Play => "play",
+            Permission::ProfileReadAny => "profile:read-any",
+            Permission::CardsManage => "cards:manage",
+            Permission::BattlesManage => "battles:manage",
+            Permission::LockoutRead => "lockout:read",
+            Permission::RolesManage => "roles:manage",
+            Permission::ErrorDetails => "errors:detail",
+        }
+    }
+    pub fn parse(s: &str) -> Option<Permission> {
+        Permission::ALL.iter().copied().find(|permission| permission.as_str() == s)
+    }
+}
+
+/// Lets a field or mutation through only when the session has the permission.
+pub struct PermissionGuard {
+    pub permission: Permission,
+}
+
+#[async_trait]
+impl Guard for PermissionGuard {
+    async fn check(&self, ctx: &Context<'_>) -> async_graphql::Result<()> {
+        match ctx.data_opt::<Session>() {
+            Some(session) if session.has(self.permission) => Ok(()),
+            _ => Err(Error::NotAuthorized.extend()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, SimpleObject)]
+pub struct Role {
+    pub name: String,
+    pub description: String,
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(sqlx::FromRow)]
+struct RoleRow {
+    name: String,
+    description: String,
+    permissions: Vec<String>,
+}
+
+/// Everything the roles of the user grant. Permissions this build does not know are left out.
+/// The banned role takes `Play` away, whatever the other roles grant, so staff can be banned
+/// without losing their other roles.
+pub async fn load_permissions(dbpool: &DbPool, user_id: Uuid) -> Result<Vec<Permission>, Error> {
+    let names = sqlx::query_scalar::<_, String>(
+        "SELECT DISTINCT permission FROM user_roles JOIN role_permissions USING (role) WHERE user_id = $1
+        AND NOT (permission = 'play' AND EXISTS (SELECT 1 FROM user_roles WHERE user_id = $1 AND role = 'banned'))
+        ORDER BY permission",
+    )
+    .bind(user_id)
+    .fetch_all(dbpool)
+    .await?;
+    Ok(names.iter().filter_map(|name| Permission::parse(name)).collect())
+}
+
+pub async fn list_roles(dbpool: &DbPool) -> Result<Vec<Role>, Error> {
+    let rows = sqlx::query_as::<_, RoleRow>(
+        "SELECT name, description, ARRAY(SELECT permission FROM role_permissions WHERE role = name ORDER BY permission) AS permissions FROM roles ORDER BY name",
+    )
+    .fetch_all(dbpool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| Role {
+            name: row.name,
+            description: row.description,
+            permissions: row.permissions.iter().filter_map(|name| Permission::parse(name)).collect(),
+        })
+        .collect())
+}
+
+pub async fn user_roles(dbpool: &DbPool, user_id: Uuid) -> Result<Vec<String>, Error> {
+    Ok(sqlx::query_scalar::<_, String>(
+        "SELECT role FROM user_roles WHERE user_id = $1 ORDER BY role",
+    )
+    .bind(user_id)
+    .fetch_all(dbpool)
+    .await?)
+}
+
+/// Returns false when the user already had the role.
+pub async fn grant_role(dbpool: &DbPool, user_id: Uuid, role: &str) -> Result<bool, Error> {
+    let role_exists = sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM roles WHERE name = $1)")
+        .bind(role)
+        .fetch_one(dbpool)
+        .await?;
+    if !role_exists {
+        return Err(Error::BadRequest("grantRole", "no such role"));
+    }
+    let user_exists = sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM users WHERE id = $1)")
+        .bind(user_id)
+        .fetch_one(dbpool)
+        .await?;
+    if !user_exists {
+        return Err(Error::BadRequest("grantRole", "no such user"));
+    }
+    let granted = sqlx::query("INSERT INTO user_roles (user_id, role) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+        .bind(user_id)
+        .bind(role)
+        .execute(dbpool)
+        .await?
+        .rows_affected();
+    Ok(granted > 0)
+}
+
+/// Returns false when the user did not have the role.
+pub async fn revoke_role(dbpool: &DbPool, user_id: Uuid, role: &str) -> Result<bool, Error> {
+    let revoked = sqlx::query("DELETE FROM user_roles WHERE user_id = $1 AND role = $2")
+        .bind(user_id)
+        .bind(role)
+        .execute(dbpool)
+        .await?
+        .rows_affected();
+    Ok(revoked > 0)
+}
@@ -0,0 +1,173 @@
+use crate::error::Error;
+use crate::model::RedisPool;
+use crate::session::{client_ip, request_session, Session};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, HttpRequest};
+use async_graphql::parser::{parse_query, types::Selection};
+use chrono::Utc;
+use deadpool_redis::{cmd, ConnectionWrapper as RedisConn};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use uuid::Uuid;
+
+/// How many requests are allowed within a sliding window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Budget {
+    pub requests: i64,
+    pub window_seconds: i64,
+}
+
+/// Every request to `/graphql`.
+pub const REQUEST_BUDGET: Budget = Budget {
+    requests: 300,
+    window_seconds: 60,
+};
+
+/// Operations that are costly or worth guessing at, on top of `REQUEST_BUDGET`. Each use of
+/// the field in a request counts, aliased or not.
+pub const OPERATION_BUDGETS: &[(&str, Budget)] = &[
+    ("login", Budget { requests: 10, window_seconds: 60 }),
+    ("loginToken", Budget { requests: 10, window_seconds: 60 }),
+    ("register", Budget { requests: 5, window_seconds: 60 * 60 }),
+    ("requestPasswordReset", Budget { requests: 5, window_seconds: 60 * 60 }),
+    ("resendVerificationEmail", Budget { requests: 5, window_seconds: 60 * 60 }),
+    ("startBattle", Budget { requests: 30, window_seconds: 60 }),
+    ("joinQueue", Budget { requests: 30, window_seconds: 60 }),
+];
+
+/// Trims the window, then counts the request unless the budget is used up. Returns 0 when
+/// counted, or else when the oldest request falls out of the window, in unix milliseconds.
+/// One script, so concurrent requests can not all see the last free slot.
+const HIT_SCRIPT: &str = r#"
+local now = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+redis.call('ZREMRANGEBYSCORE', KEYS[1], 0, now - window)
+if redis.call('ZCARD', KEYS[1]) >= tonumber(ARGV[3]) then
+  local oldest = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+  if oldest[2] then
+    return tonumber(oldest[2]) + window
+  end
+  return now + window
+end
+redis.call('ZADD', KEYS[1], now, ARGV[4])
+redis.call('PEXPIRE', KEYS[1], window)
+return 0
+"#;
+
+/// Who a request is counted against: the user when there is a session, or else the ip.
+pub fn client_key(session: Option<&Session>, req: &HttpRequest) -> String {
+    match session {
+        Some(session) => format!("user/{}", session.user_id),
+        None => match client_ip(req) {
+            Some(ip) => format!("ip/{}", ip),
+            None => "ip/unknown".to_string(),
+        },
+    }
+}
+
+/// Count a request against the budget. Fails with `Error::RateLimited` once the budget is
+/// used up.
+pub async fn hit(
+    redis_conn: &mut RedisConn,
+    client: &str,
+    name: &str,
+    budget: Budget,
+) -> Result<(), Error> {
+    let key = format!("rate-limit/{}/{}", name, client);
+    let now = Utc::now().timestamp_millis();
+    let freed_at: i64 = cmd("EVAL")
+        .arg(HIT_SCRIPT)
+        .arg(1)
+        .arg(&key)
+        .arg(now)
+        .arg(budget.window_seconds * 1000)
+        .arg(budget.requests)
+        .arg(Uuid::new_v4().to_string())
+        .query_async(redis_conn)
+        .await?;
+    if freed_at > 0 {
+        return Err(Error::RateLimited(((freed_at - now) / 1000).max(1)));
+    }
+    Ok(())
+}
+
+/// Root fields of every operation in the document. Empty when it does not parse, which the
+/// schema reports itself.
+fn root_fields(query: &str) -> Vec<String> {
+    let document = match parse_query(query) {
+        Ok(document) => document,
+        Err(_) => return Vec::new(),
+    };
+    document
+        .operations
+        .iter()
+        .flat_map(|(_, operation)| operation.node.selection_set.node.items.iter())
+        .filter_map(|selection| match &selection.node {
+            Selection::Field(field) => Some(field.node.name.node.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Count the budgeted operations of a GraphQL request.
+pub async fn check_operations(redis_conn: &mut RedisConn, client: &str, query: &str) -> Result<(), Error> {
+    for field in root_fields(query) {
+        if let Some((name, budget)) = OPERATION_BUDGETS.iter().find(|(name, _)| *name == field) {
+            hit(redis_conn, client, name, *budget).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Middleware applying `REQUEST_BUDGET`. Does nothing when the app has no redis pool.
+pub struct RateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service: Rc::new(service),
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            if let Some(redis_pool) = req.app_data::<web::Data<RedisPool>>().cloned() {
+                let mut redis_conn = redis_pool.get().await.map_err(Error::from)?;
+                let session = request_session(&mut redis_conn, req.request()).await?;
+                let client = client_key(session.as_ref(), req.request());
+                hit(&mut redis_conn, &client, "request", REQUEST_BUDGET).await?;
+            }
+            service.call(req).await
+        })
+    }
+}
@@ -0,0 +1,394 @@
+use serde::Deserialize;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+pub const DEFAULT_ELO_K_FACTOR: f64 = 32.0;
+
+/// Result of a battle from one side's point of view.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Win,
+    Loss,
+    Draw,
+}
+
+impl Outcome {
+    pub fn score(self) -> f64 {
+        match self {
+            Outcome::Win => 1.0,
+            Outcome::Loss => 0.0,
+            Outcome::Draw => 0.5,
+        }
+    }
+    pub fn flip(self) -> Outcome {
+        match self {
+            Outcome::Win => Outcome::Loss,
+            Outcome::Loss => Outcome::Win,
+            Outcome::Draw => Outcome::Draw,
+        }
+    }
+}
+
+/// Rating columns of a card. Systems without a deviation leave it `None`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rating {
+    pub rating: f64,
+    pub deviation: Option<f64>,
+    pub volatility: Option<f64>,
+}
+
+impl Rating {
+    pub fn new(rating: f64) -> Self {
+        Rating {
+            rating,
+            deviation: None,
+            volatility: None,
+        }
+    }
+}
+
+pub trait RatingSystem: Send + Sync {
+    /// New ratings of `a` and `b`. `outcome` is from `a`'s side.
+    fn rate(&self, a: &Rating, b: &Rating, outcome: Outcome) -> (Rating, Rating);
+    /// How many deviations are subtracted for the conservative estimate.
+    fn conservative_factor(&self) -> f64 {
+        0.0
+    }
+    /// Deviation of a card that has not battled yet, whose column is still empty.
+    fn initial_deviation(&self) -> f64 {
+        0.0
+    }
+    /// Rating we are fairly sure the card is above. Used for sorting.
+    fn conservative(&self, rating: &Rating) -> f64 {
+        let deviation = rating.deviation.unwrap_or_else(|| self.initial_deviation());
+        rating.rating - self.conservative_factor() * deviation
+    }
+}
+
+pub type SharedRatingSystem = Arc<dyn RatingSystem>;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RatingSystemKind {
+    #[default]
+    Elo,
+    Glicko2,
+    TrueSkill,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Elo {
+    pub k_factor: f64,
+}
+
+impl Default for Elo {
+    fn default() -> Self {
+        Elo {
+            k_factor: DEFAULT_ELO_K_FACTOR,
+        }
+    }
+}
+
+impl Elo {
+    /// Probability of `rating` beating `opponent`.
+    pub fn expected(rating: f64, opponent: f64) -> f64 {
+        1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0))
+    }
+}
+
+impl RatingSystem for Elo {
+    fn rate(&self, a: &Rating, b: &Rating, outcome: Outcome) -> (Rating, Rating) {
+        let delta = self.k_factor * (outcome.score() - Elo::expected(a.rating, b.rating));
+        (Rating::new(a.rating + delta), Rating::new(b.rating - delta))
+    }
+}
+
+/// Glicko-2 with every battle treated as its own rating period.
+/// http://www.glicko.net/glicko/glicko2.pdf
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Glicko2 {
+    /// Constrains the change in volatility over time.
+    pub tau: f64,
+    pub initial_deviation: f64,
+    pub initial_volatility: f64,
+}
+
+impl Default for Glicko2 {
+    fn default() -> Self {
+        Glicko2 {
+            tau: 0.5,
+            initial_deviation: 350.0,
+            initial_volatility: 0.06,
+        }
+    }
+}
+
+const GLICKO2_SCALE: f64 = 173.7178;
+const GLICKO2_CONVERGENCE: f64 = 0.000001;
+
+impl Glicko2 {
+    fn g(phi: f64) -> f64 {
+        1.0 / (1.0 + 3.0 * phi * phi / (PI * PI)).sqrt()
+    }
+    fn update(&self, player: &Rating, opponent: &Rating, score: f64) -> Rating {
+        let mu = player.rating / GLICKO2_SCALE;
+        let phi = player.deviation.unwrap_or(self.initial_deviation) / GLICKO2_SCALE;
+        let sigma = player.volatility.unwrap_or(self.initial_volatility);
+        let mu_j = opponent.rating / GLICKO2_SCALE;
+        let phi_j = opponent.deviation.unwrap_or(self.initial_deviation) / GLICKO2_SCALE;
+
+        let g = Glicko2::g(phi_j);
+        let e = 1.0 / (1.0 + (-g * (mu - mu_j)).exp());
+        let v = 1.0 / (g * g * e * (1.0 - e));
+        let delta = v * g * (score - e);
+
+        // Illinois algorithm for the new volatility.
+        let a = (sigma * sigma).ln();
+        let tau = self.tau;
+        let f = |x: f64| {
+            let ex = x.exp();
+            let d = phi * phi + v + ex;
+            ex * (delta * delta - phi * phi - v - ex) / (2.0 * d * d) - (x - a) / (tau * tau)
+        };
+        let mut big_a = a;
+        let mut big_b = if delta * delta > phi * phi + v {
+            (delta * delta - phi * phi - v).ln()
+        } else {
+            let mut k = 1.0;
+            while f(a - k * tau) < 0.0 {
+                k += 1.0;
+            }
+            a - k * tau
+        };
+        let mut f_a = f(big_a);
+        let mut f_b = f(big_b);
+        while (big_b - big_a).abs() > GLICKO2_CONVERGENCE {
+            let big_c = big_a + (big_a - big_b) * f_a / (f_b - f_a);
+            let f_c = f(big_c);
+            if f_c * f_b <= 0.0 {
+                big_a = big_b;
+                f_a = f_b;
+            } else {
+                f_a /= 2.0;
+            }
+            big_b = big_c;
+            f_b = f_c;
+        }
+        let sigma = (big_a / 2.0).exp();
+
+        let phi_star = (phi * phi + sigma * sigma).sqrt();
+        let phi = 1.0 / (1.0 / (phi_star * phi_star) + 1.0 / v).sqrt();
+        let mu = mu + phi * phi * g * (score - e);
+        Rating {
+            rating: mu * GLICKO2_SCALE,
+            deviation: Some(phi * GLICKO2_SCALE),
+            volatility: Some(sigma),
+        }
+    }
+}
+
+impl RatingSystem for Glicko2 {
+    fn rate(&self, a: &Rating, b: &Rating, outcome: Outcome) -> (Rating, Rating) {
+        (
+            self.update(a, b, outcome.score()),
+            self.update(b, a, outcome.flip().score()),
+        )
+    }
+    fn conservative_factor(&self) -> f64 {
+        2.0
+    }
+    fn initial_deviation(&self) -> f64 {
+        self.initial_deviation
+    }
+}
+
+/// Two player TrueSkill. The deviation column holds sigma.
+/// Defaults are the usual 25/(25/3) parameters scaled to our 1000 starting rating.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrueSkill {
+    pub initial_sigma: f64,
+    /// Distance that guarantees about 76% chance of winning.
+    pub beta: f64,
+    /// Dynamics added to sigma before every battle.
+    pub tau: f64,
+    pub draw_probability: f64,
+}
+
+impl Default for TrueSkill {
+    fn default() -> Self {
+        let initial_sigma = 1000.0 / 3.0;
+        TrueSkill {
+            initial_sigma,
+            beta: initial_sigma / 2.0,
+            tau: initial_sigma / 100.0,
+            draw_probability: 0.1,
+        }
+    }
+}
+
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let r = t * (-z * z - 1.26551223
+        + t * (1.00002368
+            + t * (0.37409196
+                + t * (0.09678418
+                    + t * (-0.18628806
+                        + t * (0.27886807
+                            + t * (-1.13520398
+                                + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+        .exp();
+    if x >= 0.0 {
+        r
+    } else {
+        2.0 - r
+    }
+}
+
+fn normal_pdf(x: f64) -> f64 {
+    (-x * x / 2.0).exp() / (2.0 * PI).sqrt()
+}
+
+fn normal_cdf(x: f64) -> f64 {
+    0.5 * erfc(-x / 2f64.sqrt())
+}
+
+fn normal_ppf(p: f64) -> f64 {
+    let (mut lo, mut hi) = (-10.0, 10.0);
+    for _ in 0..100 {
+        let mid = (lo + hi) / 2.0;
+        if normal_cdf(mid) < p {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    (lo + hi) / 2.0
+}
+
+impl TrueSkill {
+    fn draw_margin(&self) -> f64 {
+        normal_ppf((self.draw_probability + 1.0) / 2.0) * 2f64.sqrt() * self.beta
+    }
+}
+
+impl RatingSystem for TrueSkill {
+    fn rate(&self, a: &Rating, b: &Rating, outcome: Outcome) -> (Rating, Rating) {
+        if outcome == Outcome::Loss {
+            let (b, a) = self.rate(b, a, Outcome::Win);
+            return (a, b);
+        }
+        let sigma_a = a.deviation.unwrap_or(self.initial_sigma);
+        let sigma_b = b.deviation.unwrap_or(self.initial_sigma);
+        let var_a = sigma_a * sigma_a + self.tau * self.tau;
+        let var_b = sigma_b * sigma_b + self.tau * self.tau;
+        let c = (2.0 * self.beta * self.beta + var_a + var_b).sqrt();
+        let t = (a.rating - b.rating) / c;
+        let e = self.draw_margin() / c;
+        let (v, w) = if outcome == Outcome::Win {
+            let v = normal_pdf(t - e) / normal_cdf(t - e);
+            (v, v * (v + t - e))
+        } else {
+            let d = normal_cdf(e - t) - normal_cdf(-e - t);
+            let v = (normal_pdf(-e - t) - normal_pdf(e - t)) / d;
+            (
+                v,
+                v * v + ((e - t) * normal_pdf(e - t) + (e + t) * normal_pdf(e + t)) / d,
+            )
+        };
+        (
+            Rating {
+                rating: a.rating + var_a / c * v,
+                deviation: Some((var_a * (1.0 - var_a / (c * c) * w)).sqrt()),
+                volatility: None,
+            },
+            Rating {
+                rating: b.rating - var_b / c * v,
+                deviation: Some((var_b * (1.0 - var_b / (c * c) * w)).sqrt()),
+                volatility: None,
+            },
+        )
+    }
+    fn conservative_factor(&self) -> f64 {
+        3.0
+    }
+    fn initial_deviation(&self) -> f64 {
+        self.initial_sigma
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64, tolerance: f64) {
+        assert!((a - b).abs() < tolerance, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_conservative_unrated() {
+        let glicko2 = Glicko2::default();
+        assert_close(glicko2.conservative(&Rating::new(1500.0)), 800.0, 1e-9);
+        let trueskill = TrueSkill::default();
+        assert_close(trueskill.conservative(&Rating::new(1000.0)), 0.0, 1e-9);
+        assert_close(Elo::default().conservative(&Rating::new(1000.0)), 1000.0, 1e-9);
+    }
+
+    #[test]
+    fn test_elo_equal_ratings() {
+        let elo = Elo::default();
+        let r = Rating::new(1000.0);
+        assert_eq!(elo.rate(&r, &r, Outcome::Win), (Rating::new(1016.0), Rating::new(984.0)));
+        assert_eq!(elo.rate(&r, &r, Outcome::Loss), (Rating::new(984.0), Rating::new(1016.0)));
+        assert_eq!(elo.rate(&r, &r, Outcome::Draw), (r, r));
+    }
+    #[test]
+    fn test_elo_upset_moves_more() {
+        let elo = Elo { k_factor: 20.0 };
+        let (favorite, underdog) = elo.rate(&Rating::new(1400.0), &Rating::new(1000.0), Outcome::Loss);
+        assert_close(favorite.rating, 1400.0 - 20.0 * 0.9090909090909091, 1e-9);
+        assert_close(underdog.rating + favorite.rating, 2400.0, 1e-9);
+        let (favorite, _) = elo.rate(&Rating::new(1400.0), &Rating::new(1000.0), Outcome::Win);
+        assert!(favorite.rating - 1400.0 < 2.0);
+    }
+    #[test]
+    fn test_glicko2() {
+        let glicko2 = Glicko2::default();
+        let player = Rating {
+            rating: 1500.0,
+            deviation: Some(200.0),
+            volatility: Some(0.06),
+        };
+        let opponent = Rating {
+            rating: 1400.0,
+            deviation: Some(30.0),
+            volatility: Some(0.06),
+        };
+        let (player, _) = glicko2.rate(&player, &opponent, Outcome::Win);
+        assert_close(player.rating, 1563.564, 0.01);
+        assert_close(player.deviation.unwrap(), 175.403, 0.01);
+        assert_close(player.volatility.unwrap(), 0.06, 0.0001);
+
+        let (winner, loser) = glicko2.rate(&Rating::new(1000.0), &Rating::new(1000.0), Outcome::Win);
+        assert_close(winner.rating, 1162.311, 0.01);
+        assert_close(loser.rating, 837.689, 0.01);
+        assert_eq!(winner.deviation, loser.deviation);
+        assert!(glicko2.conservative(&winner) < winner.rating);
+    }
+    #[test]
+    fn test_trueskill() {
+        let trueskill = TrueSkill::default();
+        let r = Rating::new(1000.0);
+        let (winner, loser) = trueskill.rate(&r, &r, Outcome::Win);
+        assert_close(winner.rating, 29.396 * 40.0, 0.1);
+        assert_close(loser.rating, 20.604 * 40.0, 0.1);
+        assert_close(winner.deviation.unwrap(), 7.171 * 40.0, 0.1);
+        let (loser, winner) = trueskill.rate(&r, &r, Outcome::Loss);
+        assert_close(winner.rating, 29.396 * 40.0, 0.1);
+        assert_close(loser.rating, 20.604 * 40.0, 0.1);
+        let (a, b) = trueskill.rate(&r, &r, Outcome::Draw);
+        assert_close(a.rating, 1000.0, 1e-6);
+        assert_close(b.rating, 1000.0, 1e-6);
+        assert_close(a.deviation.unwrap(), 6.458 * 40.0, 0.1);
+    }
+}
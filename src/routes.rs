@@ -1,24 +1,261 @@
 use crate::error::Error;
-use crate::model::{RedisPool, Schema};
-use crate::session::extract_session;
-use actix_web::{get, post, web, HttpRequest, HttpResponse, Result as ActixWebResult};
+use crate::model::{DbPool, RedisPool, Schema};
+use crate::oauth::{self, AuthorizeError, AuthorizeParams, TokenParams};
+use crate::oidc::{self, LoginOutcome, OidcProviders};
+use crate::rate_limit::{self, RateLimit};
+use crate::token::JwtKeys;
+use crate::session::{
+    bearer_token, extract_session, request_session, session_cookie, start_session, RequestInfo,
+    SessionId, SessionPolicy, CLEARED_SESSION_COOKIE,
+};
+use actix_web::http::{header, HeaderValue};
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder, Result as ActixWebResult};
 use async_graphql::http::{playground_source, GraphQLPlaygroundConfig};
-use async_graphql_actix_web::{Request, Response};
+use async_graphql::Data;
+use async_graphql_actix_web::{Request, Response, WSSubscription};
 
-#[post("/graphql")]
+fn found(location: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .append_header((header::LOCATION, location))
+        .finish()
+}
+
+#[post("/graphql", wrap = "RateLimit")]
 async fn graphql(
     schema: web::Data<Schema>,
     redis_pool: web::Data<RedisPool>,
     req: HttpRequest,
     gql_request: Request,
-) -> ActixWebResult<Response> {
+) -> ActixWebResult<HttpResponse> {
+    let mut request = gql_request
+        .into_inner()
+        .data(RequestInfo::from_http_request(&req));
     let mut redis_conn = redis_pool.get().await.map_err(Error::from)?;
-    let session = extract_session(&mut redis_conn, &req).await?;
-    let mut request = gql_request.into_inner();
+    let session = request_session(&mut redis_conn, &req).await?;
+    let client = rate_limit::client_key(session.as_ref(), &req);
+    rate_limit::check_operations(&mut redis_conn, &client, &request.query).await?;
+    // token clients never get cookies, so there is nothing to renew or clear for them
+    if bearer_token(&req).is_some() {
+        if let Some(session) = session {
+            request = request.data(session);
+        }
+        return Ok(Response::from(schema.execute(request).await).respond_to(&req));
+    }
+    let session_id = req
+        .cookie("session-id")
+        .filter(|_| session.is_some())
+        .map(|cookie| cookie.value().to_string());
     if let Some(session) = session {
         request = request.data(session);
     }
-    Ok(schema.execute(request).await.into())
+    if let Some(session_id) = &session_id {
+        request = request.data(SessionId(session_id.clone()));
+    }
+    let mut response = Response::from(schema.execute(request).await).respond_to(&req);
+    // renew the cookie along with the session, or drop a cookie that led nowhere, unless the
+    // request itself replaced or cleared it
+    if !response.headers().contains_key(header::SET_COOKIE) {
+        if let Some(session_id) = session_id {
+            if let Ok(value) = HeaderValue::from_str(&session_cookie(&session_id, &SessionPolicy::of_request(&req))) {
+                response.headers_mut().append(header::SET_COOKIE, value);
+            }
+        } else if req.cookie("session-id").is_some() {
+            response.headers_mut().append(
+                header::SET_COOKIE,
+                HeaderValue::from_static(CLEARED_SESSION_COOKIE),
+            );
+        }
+    }
+    Ok(response)
+}
+
+/// graphql-ws subscriptions. The session cookie is read once on the upgrade request.
+#[get("/graphql")]
+async fn graphql_ws(
+    schema: web::Data<Schema>,
+    redis_pool: web::Data<RedisPool>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> ActixWebResult<HttpResponse> {
+    let mut redis_conn = redis_pool.get().await.map_err(Error::from)?;
+    let session = extract_session(&mut redis_conn, &req).await?;
+    WSSubscription::start_with_initializer(
+        Schema::clone(&*schema),
+        &req,
+        payload,
+        move |_| async move {
+            let mut data = Data::default();
+            if let Some(session) = session {
+                data.insert(session);
+            }
+            Ok(data)
+        },
+    )
+}
+
+/// Consent page of the OAuth authorization code flow. The player must be logged in with a
+/// cookie session.
+#[get("/oauth/authorize")]
+async fn oauth_authorize(
+    dbpool: web::Data<DbPool>,
+    redis_pool: web::Data<RedisPool>,
+    req: HttpRequest,
+    params: web::Query<AuthorizeParams>,
+) -> ActixWebResult<HttpResponse> {
+    let mut redis_conn = redis_pool.get().await.map_err(Error::from)?;
+    let session = extract_session(&mut redis_conn, &req)
+        .await?
+        .ok_or(Error::NotAuthorized)?;
+    match oauth::start_authorization(&dbpool, &mut redis_conn, &session, params.into_inner()).await {
+        // other sites must not frame the page to trick the player into allowing
+        Ok((consent_id, pending)) => Ok(HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .append_header((header::X_FRAME_OPTIONS, "DENY"))
+            .append_header((header::CONTENT_SECURITY_POLICY, "frame-ancestors 'none'"))
+            .body(oauth::consent_page(&consent_id, &pending))),
+        Err(AuthorizeError::Redirect(location)) => Ok(found(&location)),
+        Err(AuthorizeError::Invalid(err)) => Err(err.into()),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ConsentForm {
+    consent_id: String,
+    decision: String,
+}
+
+#[post("/oauth/authorize")]
+async fn oauth_consent(
+    redis_pool: web::Data<RedisPool>,
+    req: HttpRequest,
+    form: web::Form<ConsentForm>,
+) -> ActixWebResult<HttpResponse> {
+    let mut redis_conn = redis_pool.get().await.map_err(Error::from)?;
+    let session = extract_session(&mut redis_conn, &req)
+        .await?
+        .ok_or(Error::NotAuthorized)?;
+    let location = oauth::finish_authorization(
+        &mut redis_conn,
+        &session,
+        &form.consent_id,
+        form.decision == "allow",
+    )
+    .await?;
+    Ok(found(&location))
+}
+
+#[post("/oauth/token")]
+async fn oauth_token(
+    req: HttpRequest,
+    dbpool: web::Data<DbPool>,
+    redis_pool: web::Data<RedisPool>,
+    jwt_keys: web::Data<JwtKeys>,
+    form: web::Form<TokenParams>,
+) -> ActixWebResult<HttpResponse> {
+    let basic = match req.headers().get(header::AUTHORIZATION) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(oauth::basic_credentials)
+                .ok_or(Error::Oauth("invalid_client"))?,
+        ),
+        None => None,
+    };
+    let mut redis_conn = redis_pool.get().await.map_err(Error::from)?;
+    let tokens = oauth::exchange_token(&dbpool, &mut redis_conn, &jwt_keys, basic, form.into_inner()).await?;
+    Ok(HttpResponse::Ok()
+        .append_header((header::CACHE_CONTROL, "no-store"))
+        .json(tokens))
+}
+
+/// Send the player to log in at an external identity provider.
+#[get("/auth/oidc/{provider}/login")]
+async fn oidc_login(
+    providers: Option<web::Data<OidcProviders>>,
+    redis_pool: web::Data<RedisPool>,
+    provider: web::Path<String>,
+) -> ActixWebResult<HttpResponse> {
+    let provider = match providers.as_ref().and_then(|providers| providers.get(&provider)) {
+        Some(provider) => provider,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let mut redis_conn = redis_pool.get().await.map_err(Error::from)?;
+    let (location, state_cookie) = oidc::start_login(&mut redis_conn, provider, None).await?;
+    Ok(HttpResponse::Found()
+        .append_header((header::LOCATION, location))
+        .append_header((header::SET_COOKIE, state_cookie))
+        .finish())
+}
+
+/// Like `oidc_login`, but links the provider to the account of the cookie session.
+#[get("/auth/oidc/{provider}/link")]
+async fn oidc_link(
+    providers: Option<web::Data<OidcProviders>>,
+    redis_pool: web::Data<RedisPool>,
+    req: HttpRequest,
+    provider: web::Path<String>,
+) -> ActixWebResult<HttpResponse> {
+    let provider = match providers.as_ref().and_then(|providers| providers.get(&provider)) {
+        Some(provider) => provider,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let mut redis_conn = redis_pool.get().await.map_err(Error::from)?;
+    let session = extract_session(&mut redis_conn, &req)
+        .await?
+        .ok_or(Error::NotAuthorized)?;
+    let (location, state_cookie) =
+        oidc::start_login(&mut redis_conn, provider, Some(session.user_id)).await?;
+    Ok(HttpResponse::Found()
+        .append_header((header::LOCATION, location))
+        .append_header((header::SET_COOKIE, state_cookie))
+        .finish())
+}
+
+#[derive(serde::Deserialize)]
+struct OidcCallbackParams {
+    state: String,
+    code: Option<String>,
+}
+
+#[get("/auth/oidc/{provider}/callback")]
+async fn oidc_callback(
+    providers: Option<web::Data<OidcProviders>>,
+    dbpool: web::Data<DbPool>,
+    redis_pool: web::Data<RedisPool>,
+    req: HttpRequest,
+    provider: web::Path<String>,
+    params: web::Query<OidcCallbackParams>,
+) -> ActixWebResult<HttpResponse> {
+    let provider = match providers.as_ref().and_then(|providers| providers.get(&provider)) {
+        Some(provider) => provider,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    // the provider sends an `error` instead of a code when the player declined
+    let code = params.code.as_deref().ok_or(Error::NotAuthorized)?;
+    let mut redis_conn = redis_pool.get().await.map_err(Error::from)?;
+    let state_cookie = req.cookie("oidc-state");
+    let outcome = oidc::finish_login(
+        &dbpool,
+        &mut redis_conn,
+        provider,
+        &params.state,
+        state_cookie.as_ref().map(|cookie| cookie.value()),
+        code,
+    )
+    .await?;
+    let mut response = HttpResponse::Found();
+    response
+        .append_header((header::LOCATION, "/"))
+        .append_header((header::SET_COOKIE, oidc::CLEARED_STATE_COOKIE));
+    if let LoginOutcome::LoggedIn(user) = outcome {
+        let policy = SessionPolicy::of_request(&req);
+        let session_id =
+            start_session(&dbpool, &mut redis_conn, &user, RequestInfo::from_http_request(&req), &policy)
+                .await?;
+        response.append_header((header::SET_COOKIE, session_cookie(&session_id, &policy)));
+    }
+    Ok(response.finish())
 }
 
 #[get("/graphiql")]
@@ -26,12 +263,20 @@ async fn graphiql() -> HttpResponse {
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
         .body(playground_source(
-            GraphQLPlaygroundConfig::new("/").subscription_endpoint("/"),
+            GraphQLPlaygroundConfig::new("/graphql").subscription_endpoint("/graphql"),
         ))
 }
 
 pub fn routes(cfg: &mut web::ServiceConfig) {
-    cfg.service(graphql).service(graphiql);
+    cfg.service(graphql)
+        .service(graphql_ws)
+        .service(oauth_authorize)
+        .service(oauth_consent)
+        .service(oauth_token)
+        .service(oidc_login)
+        .service(oidc_link)
+        .service(oidc_callback)
+        .service(graphiql);
 }
 
 #[cfg(test)]
@@ -41,6 +286,25 @@ mod tests {
     use actix_web::{http::header::IntoHeaderValue, test, App};
     //use async_graphql::{value, Name, Value};
 
+    #[test]
+    fn test_client_ip() {
+        use crate::session::{client_ip, TrustedProxies};
+
+        let request = |peer: &str, forwarded: &str, trusted: &[&str]| {
+            test::TestRequest::default()
+                .peer_addr(format!("{}:5000", peer).parse().unwrap())
+                .insert_header(("X-Forwarded-For", forwarded))
+                .app_data(web::Data::new(TrustedProxies(trusted.iter().map(|ip| ip.parse().unwrap()).collect())))
+                .to_http_request()
+        };
+        let ip = |s: &str| -> Option<std::net::IpAddr> { Some(s.parse().unwrap()) };
+        // anyone can send the header
+        assert_eq!(client_ip(&request("1.2.3.4", "5.6.7.8", &[])), ip("1.2.3.4"));
+        assert_eq!(client_ip(&request("1.2.3.4", "5.6.7.8", &["10.0.0.1"])), ip("1.2.3.4"));
+        // behind proxies, addresses the client made up come first
+        assert_eq!(client_ip(&request("10.0.0.1", "6.6.6.6, 5.6.7.8, 10.0.0.2", &["10.0.0.1", "10.0.0.2"])), ip("5.6.7.8"));
+    }
+
     #[actix_rt::test]
     async fn test_session_id_cookie_set() {
         let docker = TestDocker::new();
@@ -56,7 +320,7 @@ mod tests {
         .await;
 
         let query =
-            r#"{"query":"mutation { register(email:\"a\", password:\"b\", nickname:\"c\") }"}"#;
+            r#"{"query":"mutation { register(email:\"a@example.com\", password:\"hunter22\", nickname:\"carol\") }"}"#;
         let req = test::TestRequest::post()
             .insert_header(("Content-Type", "application/json"))
             .uri("/graphql")
@@ -88,7 +352,7 @@ mod tests {
         .await;
 
         let query =
-            r#"{"query":"mutation { register(email:\"a\", password:\"b\", nickname:\"c\") }"}"#;
+            r#"{"query":"mutation { register(email:\"a@example.com\", password:\"hunter22\", nickname:\"carol\") }"}"#;
         let req = test::TestRequest::post()
             .insert_header(("Content-Type", "application/json"))
             .uri("/graphql")
@@ -145,4 +409,1048 @@ mod tests {
             .find("error")
             .is_none());
     }
+    #[actix_rt::test]
+    async fn test_logout() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+
+        let mut app = test::init_service(
+            App::new()
+                .data(db.schema.clone())
+                .data(db.pgpool.clone())
+                .data(db.redispool.clone())
+                .configure(routes),
+        )
+        .await;
+
+        let query =
+            r#"{"query":"mutation { register(email:\"a@example.com\", password:\"hunter22\", nickname:\"carol\") }"}"#;
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .uri("/graphql")
+            .set_payload(query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let cookie_header = resp
+            .headers()
+            .get("Set-Cookie")
+            .unwrap()
+            .try_into_value()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let cookie = cookie_header.split(';').next().unwrap().to_string();
+
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("COOKIE", cookie.as_str()))
+            .uri("/graphql")
+            .set_payload(r#"{"query":"mutation { logout }"}"#)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp
+            .headers()
+            .get("Set-Cookie")
+            .unwrap()
+            .try_into_value()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("session-id=;"));
+        let body = test::read_body(resp).await;
+        assert_eq!(body.as_ref(), br#"{"data":{"logout":true}}"#);
+
+        // the old cookie no longer authorizes anything
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("COOKIE", cookie.as_str()))
+            .uri("/graphql")
+            .set_payload(r#"{"query":"mutation { logout }"}"#)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let body = test::read_body(resp).await;
+        assert_eq!(body.as_ref(), br#"{"data":{"logout":false}}"#);
+    }
+    #[actix_rt::test]
+    async fn test_logout_all_devices() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+
+        let mut app = test::init_service(
+            App::new()
+                .data(db.schema.clone())
+                .data(db.pgpool.clone())
+                .data(db.redispool.clone())
+                .configure(routes),
+        )
+        .await;
+
+        let mut cookies = Vec::new();
+        for query in [
+            r#"{"query":"mutation { register(email:\"a@example.com\", password:\"hunter22\", nickname:\"carol\") }"}"#,
+            r#"{"query":"mutation { login(email:\"a@example.com\", password:\"hunter22\") }"}"#,
+        ]
+        .iter()
+        {
+            let req = test::TestRequest::post()
+                .insert_header(("Content-Type", "application/json"))
+                .uri("/graphql")
+                .set_payload(*query)
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            let cookie_header = resp
+                .headers()
+                .get("Set-Cookie")
+                .unwrap()
+                .try_into_value()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string();
+            cookies.push(cookie_header.split(';').next().unwrap().to_string());
+        }
+
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("COOKIE", cookies[0].as_str()))
+            .uri("/graphql")
+            .set_payload(r#"{"query":"mutation { logoutAllDevices }"}"#)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let body = test::read_body(resp).await;
+        assert_eq!(body.as_ref(), br#"{"data":{"logoutAllDevices":2}}"#);
+
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("COOKIE", cookies[1].as_str()))
+            .uri("/graphql")
+            .set_payload(r#"{"query":"mutation { logoutAllDevices }"}"#)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let body = test::read_body(resp).await;
+        assert!(String::from_utf8_lossy(body.as_ref())
+            .find("error")
+            .is_some());
+    }
+    #[actix_rt::test]
+    async fn test_my_sessions() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+
+        let mut app = test::init_service(
+            App::new()
+                .data(db.schema.clone())
+                .data(db.pgpool.clone())
+                .data(db.redispool.clone())
+                .configure(routes),
+        )
+        .await;
+
+        let mut cookies = Vec::new();
+        for (query, user_agent) in [
+            (r#"{"query":"mutation { register(email:\"a@example.com\", password:\"hunter22\", nickname:\"carol\") }"}"#, "phone"),
+            (r#"{"query":"mutation { login(email:\"a@example.com\", password:\"hunter22\") }"}"#, "desktop"),
+        ]
+        .iter()
+        {
+            let req = test::TestRequest::post()
+                .insert_header(("Content-Type", "application/json"))
+                .insert_header(("User-Agent", *user_agent))
+                .uri("/graphql")
+                .set_payload(*query)
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            let cookie_header = resp
+                .headers()
+                .get("Set-Cookie")
+                .unwrap()
+                .try_into_value()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string();
+            cookies.push(cookie_header.split(';').next().unwrap().to_string());
+        }
+
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("COOKIE", cookies[0].as_str()))
+            .uri("/graphql")
+            .set_payload(r#"{"query":"query { mySessions { handle userAgent current } }"}"#)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        // an authenticated request renews the cookie
+        assert!(resp
+            .headers()
+            .get("Set-Cookie")
+            .unwrap()
+            .try_into_value()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with(&format!("{};", cookies[0])));
+        let body: serde_json::Value =
+            serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+        let sessions = body["data"]["mySessions"].as_array().unwrap();
+        assert_eq!(sessions.len(), 2);
+        // the session just used is the most recent one
+        assert_eq!(sessions[0]["userAgent"], "phone");
+        assert_eq!(sessions[0]["current"], true);
+        assert_eq!(sessions[1]["userAgent"], "desktop");
+        assert_eq!(sessions[1]["current"], false);
+
+        let query = format!(
+            r#"{{"query":"mutation {{ revokeSession(handle:\"{}\") }}"}}"#,
+            sessions[1]["handle"].as_str().unwrap()
+        );
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("COOKIE", cookies[0].as_str()))
+            .uri("/graphql")
+            .set_payload(query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let body = test::read_body(resp).await;
+        assert_eq!(body.as_ref(), br#"{"data":{"revokeSession":true}}"#);
+
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("COOKIE", cookies[1].as_str()))
+            .uri("/graphql")
+            .set_payload(r#"{"query":"query { mySessions { handle } }"}"#)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let body = test::read_body(resp).await;
+        assert!(String::from_utf8_lossy(body.as_ref())
+            .find("error")
+            .is_some());
+    }
+    #[actix_rt::test]
+    async fn test_expired_session_cookie() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+
+        let mut app = test::init_service(
+            App::new()
+                .data(db.schema.clone())
+                .data(db.pgpool.clone())
+                .data(db.redispool.clone())
+                .configure(routes),
+        )
+        .await;
+
+        let query =
+            r#"{"query":"mutation { register(email:\"a@example.com\", password:\"hunter22\", nickname:\"carol\") }"}"#;
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .uri("/graphql")
+            .set_payload(query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let cookie_header = resp
+            .headers()
+            .get("Set-Cookie")
+            .unwrap()
+            .try_into_value()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let cookie = cookie_header.split(';').next().unwrap().to_string();
+        let session_id = cookie.trim_start_matches("session-id=");
+
+        let mut redis_conn = db.redispool.get().await.unwrap();
+        deadpool_redis::cmd("DEL")
+            .arg(&[format!("session/{}", session_id)])
+            .execute_async(&mut redis_conn)
+            .await
+            .unwrap();
+
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("COOKIE", cookie.as_str()))
+            .uri("/graphql")
+            .set_payload(r#"{"query":"query { apiVersion }"}"#)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(
+            resp.headers().get("Set-Cookie").unwrap().to_str().unwrap(),
+            CLEARED_SESSION_COOKIE
+        );
+        let body = test::read_body(resp).await;
+        assert!(String::from_utf8_lossy(body.as_ref())
+            .find("error")
+            .is_none());
+    }
+    #[actix_rt::test]
+    async fn test_session_revoked_while_renewing() {
+        use crate::session::{end_user_sessions, renew_session, SessionInfo};
+
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+
+        let mut app = test::init_service(
+            App::new()
+                .data(db.schema.clone())
+                .data(db.pgpool.clone())
+                .data(db.redispool.clone())
+                .configure(routes),
+        )
+        .await;
+
+        let query =
+            r#"{"query":"mutation { register(email:\"a@example.com\", password:\"hunter22\", nickname:\"carol\") }"}"#;
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .uri("/graphql")
+            .set_payload(query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let cookie_header = resp.headers().get("Set-Cookie").unwrap().to_str().unwrap().to_string();
+        let cookie = cookie_header.split(';').next().unwrap().to_string();
+        let session_id = cookie.trim_start_matches("session-id=").to_string();
+
+        // read by a request, then revoked before that request writes it back
+        let mut redis_conn = db.redispool.get().await.unwrap();
+        let key = format!("session/{}", session_id);
+        let bytes: Vec<u8> = deadpool_redis::cmd("GET").arg(&[&key]).query_async(&mut redis_conn).await.unwrap();
+        let info: SessionInfo = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(end_user_sessions(&mut redis_conn, info.session.user_id).await.unwrap(), 1);
+        assert!(!renew_session(&mut redis_conn, &session_id, &info, 60).await.unwrap());
+        let exists: bool = deadpool_redis::cmd("EXISTS").arg(&[&key]).query_async(&mut redis_conn).await.unwrap();
+        assert!(!exists);
+
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("COOKIE", cookie.as_str()))
+            .uri("/graphql")
+            .set_payload(r#"{"query":"mutation { logout }"}"#)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let body = test::read_body(resp).await;
+        assert_eq!(body.as_ref(), br#"{"data":{"logout":false}}"#);
+    }
+    #[actix_rt::test]
+    async fn test_tampered_session_cookie() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+
+        let mut app = test::init_service(
+            App::new()
+                .data(db.schema.clone())
+                .data(db.pgpool.clone())
+                .data(db.redispool.clone())
+                .configure(routes),
+        )
+        .await;
+
+        let query =
+            r#"{"query":"mutation { register(email:\"a@example.com\", password:\"hunter22\", nickname:\"carol\") }"}"#;
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .uri("/graphql")
+            .set_payload(query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let cookie_header = resp
+            .headers()
+            .get("Set-Cookie")
+            .unwrap()
+            .try_into_value()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let cookie = cookie_header.split(';').next().unwrap().to_string();
+        let session_id = cookie.trim_start_matches("session-id=").to_string();
+
+        // a guessed session id of the right shape
+        let last = if session_id.ends_with('a') { 'b' } else { 'a' };
+        let tampered = format!("session-id={}{}", &session_id[..session_id.len() - 1], last);
+        // a session value that no longer deserializes
+        let mut redis_conn = db.redispool.get().await.unwrap();
+        deadpool_redis::cmd("SET")
+            .arg(&[format!("session/{}", session_id), "garbage".to_string()])
+            .execute_async(&mut redis_conn)
+            .await
+            .unwrap();
+
+        for cookie in [tampered, cookie].iter() {
+            let req = test::TestRequest::post()
+                .insert_header(("Content-Type", "application/json"))
+                .insert_header(("COOKIE", cookie.as_str()))
+                .uri("/graphql")
+                .set_payload(r#"{"query":"mutation { logout }"}"#)
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert!(resp.status().is_success());
+            assert_eq!(
+                resp.headers().get("Set-Cookie").unwrap().to_str().unwrap(),
+                CLEARED_SESSION_COOKIE
+            );
+            let body = test::read_body(resp).await;
+            assert_eq!(body.as_ref(), br#"{"data":{"logout":false}}"#);
+        }
+    }
+    #[actix_rt::test]
+    async fn test_malformed_session_cookie() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+
+        let mut app = test::init_service(
+            App::new()
+                .data(db.schema.clone())
+                .data(db.pgpool.clone())
+                .data(db.redispool.clone())
+                .configure(routes),
+        )
+        .await;
+
+        for cookie in ["session-id=", "session-id=short", "session-id=../../../../etc/passwd"].iter() {
+            let req = test::TestRequest::post()
+                .insert_header(("Content-Type", "application/json"))
+                .insert_header(("COOKIE", *cookie))
+                .uri("/graphql")
+                .set_payload(r#"{"query":"query { apiVersion }"}"#)
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert!(resp.status().is_success());
+            assert_eq!(
+                resp.headers().get("Set-Cookie").unwrap().to_str().unwrap(),
+                CLEARED_SESSION_COOKIE
+            );
+            let body = test::read_body(resp).await;
+            assert!(String::from_utf8_lossy(body.as_ref())
+                .find("error")
+                .is_none());
+        }
+    }
+    #[actix_rt::test]
+    async fn test_bearer_token() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+
+        let mut app = test::init_service(
+            App::new()
+                .data(db.schema.clone())
+                .data(db.pgpool.clone())
+                .data(db.redispool.clone())
+                .data(db.jwt_keys.clone())
+                .configure(routes),
+        )
+        .await;
+
+        let query =
+            r#"{"query":"mutation { register(email:\"a@example.com\", password:\"hunter22\", nickname:\"carol\") }"}"#;
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .uri("/graphql")
+            .set_payload(query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let body: serde_json::Value =
+            serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+        let user_id = body["data"]["register"].as_str().unwrap().to_string();
+
+        let query = r#"{"query":"mutation { loginToken(email:\"a@example.com\", password:\"hunter22\") { accessToken } }"}"#;
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .uri("/graphql")
+            .set_payload(query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.headers().get("Set-Cookie").is_none());
+        let body: serde_json::Value =
+            serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+        let access_token = body["data"]["loginToken"]["accessToken"].as_str().unwrap().to_string();
+
+        let query = format!(
+            r#"{{"query":"query {{ user(id:\"{}\") {{ email }} }}"}}"#,
+            user_id
+        );
+        for (token, authorized) in [(access_token.as_str(), true), ("not-a-token", false)].iter() {
+            let req = test::TestRequest::post()
+                .insert_header(("Content-Type", "application/json"))
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .uri("/graphql")
+                .set_payload(query.clone())
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            let body = test::read_body(resp).await;
+            assert_eq!(
+                String::from_utf8_lossy(body.as_ref()).find("error").is_none(),
+                *authorized
+            );
+        }
+    }
+    #[actix_rt::test]
+    async fn test_rate_limit() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+
+        let mut app = test::init_service(
+            App::new()
+                .data(db.schema.clone())
+                .data(db.pgpool.clone())
+                .data(db.redispool.clone())
+                .configure(routes),
+        )
+        .await;
+
+        // two fields in one request count twice
+        let query = r#"{"query":"mutation { a: requestPasswordReset(email:\"a@example.com\") b: requestPasswordReset(email:\"b@example.com\") }"}"#;
+        for expected in [200, 200, 429].iter() {
+            let req = test::TestRequest::post()
+                .insert_header(("Content-Type", "application/json"))
+                .uri("/graphql")
+                .set_payload(query)
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status().as_u16(), *expected);
+            if *expected == 429 {
+                let retry_after: i64 = resp.headers().get("Retry-After").unwrap().to_str().unwrap().parse().unwrap();
+                assert!(retry_after > 0 && retry_after <= 60 * 60);
+            }
+        }
+
+        // other operations have their own budget
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .uri("/graphql")
+            .set_payload(r#"{"query":"query { apiVersion }"}"#)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+
+        // concurrent requests can not share out the last slot
+        let budget = rate_limit::Budget { requests: 3, window_seconds: 60 };
+        let mut conns = Vec::new();
+        for _ in 0..10 {
+            conns.push(db.redispool.get().await.unwrap());
+        }
+        let results = futures::future::join_all(
+            conns.iter_mut().map(|conn| rate_limit::hit(conn, "ip/concurrent", "test", budget)),
+        )
+        .await;
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 3);
+    }
+    #[actix_rt::test]
+    async fn test_oauth_authorization_code_flow() {
+        use sha2::{Digest, Sha256};
+
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+
+        let mut app = test::init_service(
+            App::new()
+                .data(db.schema.clone())
+                .data(db.pgpool.clone())
+                .data(db.redispool.clone())
+                .data(db.jwt_keys.clone())
+                .configure(routes),
+        )
+        .await;
+
+        let query =
+            r#"{"query":"mutation { register(email:\"a@example.com\", password:\"hunter22\", nickname:\"carol\") }"}"#;
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .uri("/graphql")
+            .set_payload(query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let cookie_header = resp
+            .headers()
+            .get("Set-Cookie")
+            .unwrap()
+            .try_into_value()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let cookie = cookie_header.split(';').next().unwrap().to_string();
+        let body: serde_json::Value =
+            serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+        let user_id = body["data"]["register"].as_str().unwrap().to_string();
+
+        let query = r#"{"query":"mutation { registerOauthClient(name:\"<tool>\", redirectUris:[\"https://tool.example/cb\"], scopes:[CARDS_READ, BATTLES_WRITE]) { client { id } clientSecret } }"}"#;
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("COOKIE", cookie.as_str()))
+            .uri("/graphql")
+            .set_payload(query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let body: serde_json::Value =
+            serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+        let client_id = body["data"]["registerOauthClient"]["client"]["id"].as_str().unwrap().to_string();
+        let client_secret = body["data"]["registerOauthClient"]["clientSecret"].as_str().unwrap().to_string();
+
+        // scopes the client was not registered for are refused
+        let req = test::TestRequest::get()
+            .insert_header(("COOKIE", cookie.as_str()))
+            .uri(&format!(
+                "/oauth/authorize?response_type=code&client_id={}&redirect_uri=https%3A%2F%2Ftool.example%2Fcb&scope=cards%3Awrite&state=xyz",
+                client_id
+            ))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(
+            resp.headers().get("Location").unwrap().to_str().unwrap(),
+            "https://tool.example/cb?error=invalid_scope&state=xyz"
+        );
+
+        let code_verifier = "a-verifier-that-is-long-enough-for-the-tests-1234567890";
+        let code_challenge = base64::encode_config(Sha256::digest(code_verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+        let req = test::TestRequest::get()
+            .insert_header(("COOKIE", cookie.as_str()))
+            .uri(&format!(
+                "/oauth/authorize?response_type=code&client_id={}&redirect_uri=https%3A%2F%2Ftool.example%2Fcb&scope=cards%3Aread%20battles%3Awrite&state=xyz&code_challenge={}&code_challenge_method=S256",
+                client_id, code_challenge
+            ))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("X-Frame-Options").unwrap(), "DENY");
+        let page = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(page.contains("&lt;tool&gt;"));
+        let consent_id = page
+            .split(r#"name="consent_id" value=""#)
+            .nth(1)
+            .unwrap()
+            .split('"')
+            .next()
+            .unwrap()
+            .to_string();
+
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+            .insert_header(("COOKIE", cookie.as_str()))
+            .uri("/oauth/authorize")
+            .set_payload(format!("consent_id={}&decision=allow", consent_id))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap().to_string();
+        assert!(location.starts_with("https://tool.example/cb?code="));
+        assert!(location.ends_with("&state=xyz"));
+        let code = location
+            .trim_start_matches("https://tool.example/cb?code=")
+            .split('&')
+            .next()
+            .unwrap()
+            .to_string();
+
+        let token_form = format!(
+            "grant_type=authorization_code&code={}&redirect_uri=https%3A%2F%2Ftool.example%2Fcb&client_id={}&client_secret={}&code_verifier={}",
+            code, client_id, client_secret, code_verifier
+        );
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+            .uri("/oauth/token")
+            .set_payload(token_form.clone())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+        let tokens: serde_json::Value =
+            serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+        assert_eq!(tokens["token_type"], "Bearer");
+        assert_eq!(tokens["scope"], "cards:read battles:write");
+        let access_token = tokens["access_token"].as_str().unwrap().to_string();
+
+        // codes are single use
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+            .uri("/oauth/token")
+            .set_payload(token_form)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status().as_u16(), 400);
+        let body = test::read_body(resp).await;
+        assert_eq!(body.as_ref(), br#"{"error":"invalid_grant"}"#);
+
+        // the token acts for the player only within the granted scopes
+        for (query, authorized) in [
+            (format!(r#"{{"query":"query {{ user(id:\"{}\") {{ nickname }} }}"}}"#, user_id), true),
+            (format!(r#"{{"query":"query {{ user(id:\"{}\") {{ email }} }}"}}"#, user_id), false),
+            (r#"{"query":"mutation { createCard(title:\"dragon\") { id } }"}"#.to_string(), false),
+            (r#"{"query":"query { mySessions { handle } }"}"#.to_string(), false),
+        ]
+        .iter()
+        {
+            let req = test::TestRequest::post()
+                .insert_header(("Content-Type", "application/json"))
+                .insert_header(("Authorization", format!("Bearer {}", access_token)))
+                .uri("/graphql")
+                .set_payload(query.clone())
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            let body = test::read_body(resp).await;
+            assert_eq!(
+                String::from_utf8_lossy(body.as_ref()).find("error").is_none(),
+                *authorized
+            );
+        }
+
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+            .uri("/oauth/token")
+            .set_payload(format!(
+                "grant_type=refresh_token&refresh_token={}&client_id={}&client_secret={}",
+                tokens["refresh_token"].as_str().unwrap(),
+                client_id,
+                client_secret
+            ))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+        let refreshed: serde_json::Value =
+            serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+        assert_eq!(refreshed["scope"], "cards:read battles:write");
+
+        // a fresh code for each attempt, as a failed exchange burns the code
+        for (redirect_uri, secret, verifier, error) in [
+            ("https%3A%2F%2Ftool.example%2Fcb", "wrong", code_verifier, "invalid_client"),
+            ("https%3A%2F%2Ftool.example%2Fcb", client_secret.as_str(), "wrong-verifier-that-is-long-enough-1234567890", "invalid_grant"),
+            ("https%3A%2F%2Fevil.example%2Fcb", client_secret.as_str(), code_verifier, "invalid_grant"),
+        ]
+        .iter()
+        {
+            let req = test::TestRequest::get()
+                .insert_header(("COOKIE", cookie.as_str()))
+                .uri(&format!(
+                    "/oauth/authorize?response_type=code&client_id={}&redirect_uri=https%3A%2F%2Ftool.example%2Fcb&scope=cards%3Aread&code_challenge={}&code_challenge_method=S256",
+                    client_id, code_challenge
+                ))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            let page = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+            let consent_id = page
+                .split(r#"name="consent_id" value=""#)
+                .nth(1)
+                .unwrap()
+                .split('"')
+                .next()
+                .unwrap()
+                .to_string();
+            let req = test::TestRequest::post()
+                .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+                .insert_header(("COOKIE", cookie.as_str()))
+                .uri("/oauth/authorize")
+                .set_payload(format!("consent_id={}&decision=allow", consent_id))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            let location = resp.headers().get("Location").unwrap().to_str().unwrap().to_string();
+            let code = location.trim_start_matches("https://tool.example/cb?code=").to_string();
+
+            let req = test::TestRequest::post()
+                .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+                .uri("/oauth/token")
+                .set_payload(format!(
+                    "grant_type=authorization_code&code={}&redirect_uri={}&client_id={}&client_secret={}&code_verifier={}",
+                    code, redirect_uri, client_id, secret, verifier
+                ))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert!(resp.status().is_client_error());
+            let body: serde_json::Value =
+                serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+            assert_eq!(body["error"], *error);
+        }
+    }
+    #[actix_rt::test]
+    async fn test_oauth_client_credentials() {
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+
+        let mut app = test::init_service(
+            App::new()
+                .data(db.schema.clone())
+                .data(db.pgpool.clone())
+                .data(db.redispool.clone())
+                .data(db.jwt_keys.clone())
+                .configure(routes),
+        )
+        .await;
+
+        let query =
+            r#"{"query":"mutation { register(email:\"a@example.com\", password:\"hunter22\", nickname:\"carol\") }"}"#;
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .uri("/graphql")
+            .set_payload(query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let cookie_header = resp.headers().get("Set-Cookie").unwrap().to_str().unwrap().to_string();
+        let cookie = cookie_header.split(';').next().unwrap().to_string();
+        let body: serde_json::Value =
+            serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+        let user_id = body["data"]["register"].as_str().unwrap().to_string();
+
+        let register = |redirect_uri: &str| {
+            let query = format!(
+                r#"{{"query":"mutation {{ registerOauthClient(name:\"tool\", redirectUris:[\"{}\"], scopes:[CARDS_READ, BATTLES_WRITE]) {{ client {{ id }} clientSecret }} }}"}}"#,
+                redirect_uri
+            );
+            test::TestRequest::post()
+                .insert_header(("Content-Type", "application/json"))
+                .insert_header(("COOKIE", cookie.as_str()))
+                .uri("/graphql")
+                .set_payload(query)
+                .to_request()
+        };
+        // redirect uris must be absolute https urls
+        for redirect_uri in ["http://tool.example/cb", "/cb", "https://tool.example/cb#top"].iter() {
+            let resp = test::call_service(&mut app, register(redirect_uri)).await;
+            let body: serde_json::Value =
+                serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+            assert_eq!(body["errors"][0]["extensions"]["fields"][0]["field"], "redirectUris", "{}", redirect_uri);
+        }
+        let resp = test::call_service(&mut app, register("https://tool.example/cb")).await;
+        let body: serde_json::Value =
+            serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+        let client_id = body["data"]["registerOauthClient"]["client"]["id"].as_str().unwrap().to_string();
+        let client_secret = body["data"]["registerOauthClient"]["clientSecret"].as_str().unwrap().to_string();
+
+        // only the registered uri itself, not one that starts with it
+        for redirect_uri in ["https%3A%2F%2Ftool.example%2Fcb%2Fextra", "https%3A%2F%2Ftool.example%2Fcb%3Fx%3D1"].iter() {
+            let req = test::TestRequest::get()
+                .insert_header(("COOKIE", cookie.as_str()))
+                .uri(&format!(
+                    "/oauth/authorize?response_type=code&client_id={}&redirect_uri={}&scope=cards%3Aread",
+                    client_id, redirect_uri
+                ))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status().as_u16(), 400);
+            assert!(resp.headers().get("Location").is_none());
+        }
+
+        let basic = |id: &str, secret: &str| format!("Basic {}", base64::encode(format!("{}:{}", id, secret)));
+        let token = |authorization: Option<String>, form: String| {
+            let mut req = test::TestRequest::post()
+                .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+                .uri("/oauth/token")
+                .set_payload(form);
+            if let Some(authorization) = authorization {
+                req = req.insert_header(("Authorization", authorization));
+            }
+            req.to_request()
+        };
+
+        let req = token(Some(basic(&client_id, &client_secret)), "grant_type=client_credentials&scope=cards%3Aread".to_string());
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+        let tokens: serde_json::Value =
+            serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+        assert_eq!(tokens["scope"], "cards:read");
+        assert!(tokens.get("refresh_token").is_none());
+
+        // the token acts for the owner of the client, within its scopes
+        for (query, authorized) in [
+            (format!(r#"{{"query":"query {{ user(id:\"{}\") {{ nickname }} }}"}}"#, user_id), true),
+            (format!(r#"{{"query":"query {{ user(id:\"{}\") {{ email }} }}"}}"#, user_id), false),
+        ]
+        .iter()
+        {
+            let req = test::TestRequest::post()
+                .insert_header(("Content-Type", "application/json"))
+                .insert_header(("Authorization", format!("Bearer {}", tokens["access_token"].as_str().unwrap())))
+                .uri("/graphql")
+                .set_payload(query.clone())
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            let body = test::read_body(resp).await;
+            assert_eq!(String::from_utf8_lossy(body.as_ref()).find("error").is_none(), *authorized);
+        }
+
+        // credentials in the form work too, and without a scope every registered one is granted
+        let req = token(None, format!("grant_type=client_credentials&client_id={}&client_secret={}", client_id, client_secret));
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+        let tokens: serde_json::Value =
+            serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+        assert_eq!(tokens["scope"], "cards:read battles:write");
+
+        for (authorization, form, status, error) in [
+            (Some(basic(&client_id, &client_secret)), "grant_type=client_credentials&scope=cards%3Awrite".to_string(), 400, "invalid_scope"),
+            (Some(basic(&client_id, "wrong")), "grant_type=client_credentials".to_string(), 401, "invalid_client"),
+            (Some("Basic !!!".to_string()), "grant_type=client_credentials".to_string(), 401, "invalid_client"),
+            (None, "grant_type=client_credentials".to_string(), 401, "invalid_client"),
+            // one way of authenticating per request
+            (
+                Some(basic(&client_id, &client_secret)),
+                format!("grant_type=client_credentials&client_id={}&client_secret={}", client_id, client_secret),
+                400,
+                "invalid_request",
+            ),
+        ]
+        .iter()
+        {
+            let resp = test::call_service(&mut app, token(authorization.clone(), form.clone())).await;
+            assert_eq!(resp.status().as_u16(), *status, "{}", form);
+            if *status == 401 {
+                assert_eq!(resp.headers().get("WWW-Authenticate").unwrap(), r#"Basic realm="oauth""#);
+            }
+            let body: serde_json::Value =
+                serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+            assert_eq!(body["error"], *error);
+        }
+    }
+    #[actix_rt::test]
+    async fn test_oidc_login_and_link() {
+        use std::collections::HashMap;
+
+        let docker = TestDocker::new();
+        let db = docker.run().await;
+        let issuer = MockIssuer::start();
+
+        let mut app = test::init_service(
+            App::new()
+                .data(db.schema.clone())
+                .data(db.pgpool.clone())
+                .data(db.redispool.clone())
+                .data(OidcProviders(vec![issuer.provider("mock")]))
+                .configure(routes),
+        )
+        .await;
+
+        // state, nonce and state cookie of the redirect to the provider
+        macro_rules! start {
+            ($uri:expr, $cookie:expr) => {{
+                let mut req = test::TestRequest::get().uri($uri);
+                if let Some(cookie) = $cookie {
+                    req = req.insert_header(("COOKIE", cookie));
+                }
+                let resp = test::call_service(&mut app, req.to_request()).await;
+                let location = resp.headers().get("Location").unwrap().to_str().unwrap().to_string();
+                assert!(location.starts_with(&format!("{}/authorize?", issuer.issuer)));
+                let params: HashMap<String, String> =
+                    serde_urlencoded::from_str(location.split('?').nth(1).unwrap()).unwrap();
+                let cookie_header = resp.headers().get("Set-Cookie").unwrap().to_str().unwrap().to_string();
+                let state_cookie = cookie_header.split(';').next().unwrap().to_string();
+                (params["state"].clone(), params["nonce"].clone(), state_cookie)
+            }};
+        }
+        macro_rules! callback {
+            ($subject:expr, $state:expr, $nonce:expr, $state_cookie:expr) => {{
+                let req = test::TestRequest::get()
+                    .insert_header(("COOKIE", $state_cookie))
+                    .uri(&format!(
+                        "/auth/oidc/mock/callback?state={}&code={}.{}",
+                        $state, $subject, $nonce
+                    ))
+                    .to_request();
+                test::call_service(&mut app, req).await
+            }};
+        }
+        macro_rules! session_cookie {
+            ($resp:expr) => {{
+                $resp
+                    .headers()
+                    .get_all("Set-Cookie")
+                    .map(|value| value.to_str().unwrap().split(';').next().unwrap().to_string())
+                    .find(|cookie| cookie.starts_with("session-id="))
+            }};
+        }
+        macro_rules! graphql {
+            ($cookie:expr, $query:expr) => {{
+                let req = test::TestRequest::post()
+                    .insert_header(("Content-Type", "application/json"))
+                    .insert_header(("COOKIE", $cookie))
+                    .uri("/graphql")
+                    .set_payload($query)
+                    .to_request();
+                let resp = test::call_service(&mut app, req).await;
+                let body: serde_json::Value =
+                    serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+                body
+            }};
+        }
+
+        // signing up through the provider
+        let (state, nonce, state_cookie) = start!("/auth/oidc/mock/login", None::<&str>);
+        let resp = callback!("alice", state, nonce, state_cookie.as_str());
+        assert_eq!(resp.headers().get("Location").unwrap(), "/");
+        let alice_cookie = session_cookie!(resp).unwrap();
+        // set from under /auth/oidc, but must reach /graphql
+        assert!(resp.headers().get_all("Set-Cookie").any(|value| {
+            let value = value.to_str().unwrap();
+            value.starts_with("session-id=") && value.contains("; Path=/;")
+        }));
+        let body = graphql!(alice_cookie.as_str(), r#"{"query":"query { myIdentities { provider subject email } }"}"#);
+        assert_eq!(
+            body["data"]["myIdentities"],
+            serde_json::json!([{ "provider": "mock", "subject": "alice", "email": "alice@example.com" }])
+        );
+        // the only way alice can log in
+        let body = graphql!(alice_cookie.as_str(), r#"{"query":"mutation { unlinkIdentity(provider:\"mock\") }"}"#);
+        assert!(body["errors"].is_array());
+
+        // a state is good for one callback, and the nonce must match
+        let resp = callback!("alice", state, nonce, state_cookie.as_str());
+        assert!(!resp.status().is_redirection());
+        let (state, _, state_cookie) = start!("/auth/oidc/mock/login", None::<&str>);
+        let resp = callback!("alice", state, "forged", state_cookie.as_str());
+        assert!(!resp.status().is_redirection());
+
+        // a callback from another browser, as when someone plants their own login
+        let (state, nonce, _) = start!("/auth/oidc/mock/login", None::<&str>);
+        let (_, _, other_state_cookie) = start!("/auth/oidc/mock/login", None::<&str>);
+        let resp = callback!("alice", state, nonce, other_state_cookie.as_str());
+        assert!(!resp.status().is_redirection());
+
+        // sign up needs an email the provider verified
+        let (state, nonce, state_cookie) = start!("/auth/oidc/mock/login", None::<&str>);
+        let resp = callback!("unverified", state, nonce, state_cookie.as_str());
+        assert!(!resp.status().is_redirection());
+
+        // a name that is not a valid nickname is replaced
+        let (state, nonce, state_cookie) = start!("/auth/oidc/mock/login", None::<&str>);
+        let resp = callback!("x", state, nonce, state_cookie.as_str());
+        assert!(session_cookie!(resp).is_some());
+        let (nickname, verified) = sqlx::query_as::<_, (String, bool)>(
+            "SELECT nickname, email_verified_at IS NOT NULL FROM users WHERE email = 'x@example.com'",
+        )
+        .fetch_one(&db.pgpool)
+        .await
+        .unwrap();
+        assert!(nickname.starts_with("player-"));
+        assert!(verified);
+
+        // an existing account links the provider, then logs in with it
+        let query =
+            r#"{"query":"mutation { register(email:\"b@example.com\", password:\"hunter22\", nickname:\"bob\") }"}"#;
+        let req = test::TestRequest::post()
+            .insert_header(("Content-Type", "application/json"))
+            .uri("/graphql")
+            .set_payload(query)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let cookie_header = resp.headers().get("Set-Cookie").unwrap().to_str().unwrap().to_string();
+        let bob_cookie = cookie_header.split(';').next().unwrap().to_string();
+        let body: serde_json::Value =
+            serde_json::from_slice(test::read_body(resp).await.as_ref()).unwrap();
+        let bob_id = body["data"]["register"].as_str().unwrap().to_string();
+
+        let (state, nonce, state_cookie) = start!("/auth/oidc/mock/link", Some(bob_cookie.as_str()));
+        let resp = callback!("bob-elsewhere", state, nonce, state_cookie.as_str());
+        assert_eq!(resp.headers().get("Location").unwrap(), "/");
+        assert!(session_cookie!(resp).is_none());
+
+        let (state, nonce, state_cookie) = start!("/auth/oidc/mock/login", None::<&str>);
+        let resp = callback!("bob-elsewhere", state, nonce, state_cookie.as_str());
+        let cookie = session_cookie!(resp).unwrap();
+        let body = graphql!(
+            cookie.as_str(),
+            format!(r#"{{"query":"query {{ user(id:\"{}\") {{ email }} }}"}}"#, bob_id)
+        );
+        assert_eq!(body["data"]["user"]["email"], "b@example.com");
+
+        let body = graphql!(bob_cookie.as_str(), r#"{"query":"mutation { unlinkIdentity(provider:\"mock\") }"}"#);
+        assert_eq!(body["data"]["unlinkIdentity"], true);
+        let body = graphql!(bob_cookie.as_str(), r#"{"query":"query { myIdentities { provider } }"}"#);
+        assert_eq!(body["data"]["myIdentities"], serde_json::json!([]));
+    }
 }
@@ -1,74 +1,486 @@
 use crate::error::Error;
-use crate::model::{User, UserKind};
-use actix_web::HttpRequest;
+use crate::model::{DbPool, User, UserKind};
+use crate::oauth::Scope;
+use crate::permission::{load_permissions, Permission};
+use crate::token::{revoke_refresh_tokens, JwtKeys};
+use crate::util::random_string;
+use actix_web::{http::header, web, HttpMessage, HttpRequest};
+use async_graphql::SimpleObject;
+use chrono::Utc;
 use deadpool_redis::{cmd, ConnectionWrapper as RedisConn, Pool as RedisPool};
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 use uuid::Uuid;
 
-const SESSION_LIFETIME_SECONDS: i64 = 60 * 60 * 24;
-const SESSION_LENGTH: usize = 30;
+type DateTime = chrono::DateTime<Utc>;
+
+/// Lengths a session id may have. Cookies are checked against these bounds rather than the
+/// configured length, so changing it does not log everyone out.
+pub const SESSION_ID_LENGTHS: std::ops::RangeInclusive<usize> = 20..=128;
+
+/// How long sessions live and how long their ids are.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SessionPolicy {
+    /// Sessions expire after this long without a request.
+    pub lifetime_seconds: i64,
+    /// Length of new session ids. Within `SESSION_ID_LENGTHS`.
+    pub id_length: usize,
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        SessionPolicy {
+            lifetime_seconds: 60 * 60 * 24,
+            id_length: 30,
+        }
+    }
+}
+
+impl SessionPolicy {
+    pub fn of_request(req: &HttpRequest) -> Self {
+        req.app_data::<web::Data<SessionPolicy>>()
+            .map(|policy| *policy.get_ref())
+            .unwrap_or_default()
+    }
+    fn of_context(ctx: &async_graphql::Context<'_>) -> Self {
+        ctx.data_opt::<SessionPolicy>().copied().unwrap_or_default()
+    }
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct Session {
     pub user_id: Uuid,
     pub user_kind: UserKind,
+    /// What a third-party app was allowed to do. None when the player is acting themselves.
+    pub scopes: Option<Vec<Scope>>,
+    /// What the roles of the user granted when the session started.
+    pub permissions: Vec<Permission>,
+}
+
+impl Session {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.as_ref().map_or(true, |scopes| scopes.contains(&scope))
+    }
+    /// Whether the session may do what the permission covers. Third-party apps only ever get
+    /// to play, whatever the roles of the player.
+    pub fn has(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission) && (self.scopes.is_none() || permission == Permission::Play)
+    }
+}
+
+/// What is stored in redis for a session, along with the device it was made from.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, SimpleObject)]
+pub struct SessionInfo {
+    #[graphql(skip)]
+    pub session: Session,
+    /// Public identifier of the session. The session id itself is never exposed.
+    pub handle: Uuid,
+    pub user_agent: Option<String>,
+    /// Address of the last request made with the session.
+    pub ip: Option<String>,
+    pub created_at: DateTime,
+    pub last_seen_at: DateTime,
+    /// Whether the current request was made with this session.
+    #[serde(skip)]
+    pub current: bool,
+}
+
+/// Value of the `session-id` cookie the current request was made with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionId(pub String);
+
+/// Device metadata of the current request.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RequestInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl RequestInfo {
+    pub fn from_http_request(req: &HttpRequest) -> Self {
+        RequestInfo {
+            user_agent: req
+                .headers()
+                .get(actix_web::http::header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from),
+            ip: client_ip(req).map(|ip| ip.to_string()),
+        }
+    }
+}
+
+/// Proxies in front of the server. Their `X-Forwarded-For` is believed, anyone else's is not.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrustedProxies(pub Vec<IpAddr>);
+
+/// Address of the client. Without trusted proxies this is the peer, as a forwarded header
+/// could say anything. Behind them, the last address in `X-Forwarded-For` that is not one of
+/// the proxies, since each proxy appends the address it got the request from.
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    let trusted = match req.app_data::<web::Data<TrustedProxies>>() {
+        Some(trusted) if trusted.0.contains(&peer) => trusted,
+        _ => return Some(peer),
+    };
+    let forwarded: Vec<IpAddr> = req
+        .headers()
+        .get_all("X-Forwarded-For")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+    Some(
+        forwarded
+            .iter()
+            .rev()
+            .find(|ip| !trusted.0.contains(ip))
+            .or_else(|| forwarded.first())
+            .copied()
+            .unwrap_or(peer),
+    )
+}
+
+fn session_key(session_id: &str) -> String {
+    format!("session/{}", session_id)
+}
+
+/// Set of session ids of a user, used to revoke every session at once.
+fn user_sessions_key(user_id: Uuid) -> String {
+    format!("user-sessions/{}", user_id)
+}
+
+/// `Set-Cookie` value that keeps the session alive for another lifetime. The path is given
+/// because the cookie is also set from routes like the OIDC callback, and would otherwise only
+/// be sent back under them.
+pub fn session_cookie(session_id: &str, policy: &SessionPolicy) -> String {
+    let expire_at = Utc::now() + chrono::Duration::seconds(policy.lifetime_seconds);
+    format!(
+        "session-id={}; Secure; HttpOnly; SameSite=Lax; Path=/; Expires={}",
+        session_id,
+        expire_at.format("%a, %d %b %Y %H:%M:%S GMT")
+    )
+}
+
+/// `Set-Cookie` value that makes the browser drop the session cookie.
+pub const CLEARED_SESSION_COOKIE: &str =
+    "session-id=; Secure; HttpOnly; SameSite=Lax; Path=/; Expires=Thu, 01 Jan 1970 00:00:00 GMT";
+
+fn clear_session_cookie(ctx: &async_graphql::Context<'_>) {
+    ctx.append_http_header("Set-Cookie", CLEARED_SESSION_COOKIE);
+}
+
+fn is_well_formed_session_id(session_id: &str) -> bool {
+    SESSION_ID_LENGTHS.contains(&session_id.len()) && session_id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Write the session and push back the expiry of both the session and the user's session set.
+async fn store_session(
+    redis_conn: &mut RedisConn,
+    session_id: &str,
+    info: &SessionInfo,
+    lifetime_seconds: i64,
+) -> Result<(), Error> {
+    cmd("SET")
+        .arg(session_key(session_id))
+        .arg(bincode::serialize(info)?)
+        .arg("EX")
+        .arg(lifetime_seconds)
+        .execute_async(redis_conn)
+        .await?;
+    let user_sessions_key = user_sessions_key(info.session.user_id);
+    cmd("SADD")
+        .arg(&[&user_sessions_key, session_id])
+        .execute_async(redis_conn)
+        .await?;
+    cmd("EXPIRE")
+        .arg(&[user_sessions_key, lifetime_seconds.to_string()])
+        .execute_async(redis_conn)
+        .await?;
+    Ok(())
+}
+
+/// Overwrites the session only while it still exists, so a session revoked after it was read
+/// is not brought back by the request that read it.
+const RENEW_SCRIPT: &str = r#"
+if redis.call('SET', KEYS[1], ARGV[1], 'XX', 'EX', ARGV[2]) then
+  redis.call('SADD', KEYS[2], ARGV[3])
+  redis.call('EXPIRE', KEYS[2], ARGV[2])
+  return 1
+end
+return 0
+"#;
+
+/// `store_session` for a session that was read earlier. Returns false if it was revoked since.
+pub async fn renew_session(
+    redis_conn: &mut RedisConn,
+    session_id: &str,
+    info: &SessionInfo,
+    lifetime_seconds: i64,
+) -> Result<bool, Error> {
+    let renewed: i64 = cmd("EVAL")
+        .arg(RENEW_SCRIPT)
+        .arg(2)
+        .arg(session_key(session_id))
+        .arg(user_sessions_key(info.session.user_id))
+        .arg(bincode::serialize(info)?)
+        .arg(lifetime_seconds)
+        .arg(session_id)
+        .query_async(redis_conn)
+        .await?;
+    Ok(renewed == 1)
+}
+
+/// Sessions of the user that are still alive, with their session ids. Expired ones are
+/// dropped from the user's session set on the way.
+async fn load_user_sessions(
+    redis_conn: &mut RedisConn,
+    user_id: Uuid,
+) -> Result<Vec<(String, SessionInfo)>, Error> {
+    let user_sessions_key = user_sessions_key(user_id);
+    let session_ids: Vec<String> = cmd("SMEMBERS")
+        .arg(&[&user_sessions_key])
+        .query_async(redis_conn)
+        .await?;
+    if session_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let keys: Vec<String> = session_ids.iter().map(|id| session_key(id)).collect();
+    let values: Vec<Option<Vec<u8>>> = cmd("MGET").arg(&keys).query_async(redis_conn).await?;
+    let mut sessions = Vec::new();
+    for (session_id, value) in session_ids.into_iter().zip(values) {
+        match value {
+            Some(bytes) => sessions.push((session_id, bincode::deserialize(&bytes)?)),
+            None => {
+                cmd("SREM")
+                    .arg(&[&user_sessions_key, &session_id])
+                    .execute_async(redis_conn)
+                    .await?;
+            }
+        }
+    }
+    Ok(sessions)
+}
+
+/// Start a session for the user and return its id, to be sent as the `session-id` cookie.
+pub async fn start_session(
+    dbpool: &DbPool,
+    redis_conn: &mut RedisConn,
+    user: &User,
+    request_info: RequestInfo,
+    policy: &SessionPolicy,
+) -> Result<String, Error> {
+    let now = Utc::now();
+    let info = SessionInfo {
+        session: Session {
+            user_id: user.id,
+            user_kind: user.kind,
+            scopes: None,
+            permissions: load_permissions(dbpool, user.id).await?,
+        },
+        handle: Uuid::new_v4(),
+        user_agent: request_info.user_agent,
+        ip: request_info.ip,
+        created_at: now,
+        last_seen_at: now,
+        current: false,
+    };
+    let session_id = random_string(policy.id_length);
+    store_session(redis_conn, &session_id, &info, policy.lifetime_seconds).await?;
+    Ok(session_id)
 }
 
 pub async fn create_session(ctx: &async_graphql::Context<'_>, user: &User) -> Result<(), Error> {
+    let dbpool = ctx.data::<DbPool>()?;
     let mut redis_conn = ctx
         .data_opt::<RedisPool>()
         .ok_or(Error::RedisPoolNotFoundInContext)?
         .get()
         .await?;
-    let session = Session {
-        user_id: user.id,
-        user_kind: user.kind,
+    let request_info = ctx.data_opt::<RequestInfo>().cloned().unwrap_or_default();
+    let policy = SessionPolicy::of_context(ctx);
+    let session_id = start_session(dbpool, &mut redis_conn, user, request_info, &policy).await?;
+    ctx.append_http_header("Set-Cookie", session_cookie(&session_id, &policy));
+    Ok(())
+}
+
+/// Remove the session of the current request and clear its cookie.
+pub async fn remove_session(ctx: &async_graphql::Context<'_>) -> Result<Option<Session>, Error> {
+    let (session, session_id) = match (ctx.data_opt::<Session>(), ctx.data_opt::<SessionId>()) {
+        (Some(session), Some(SessionId(session_id))) => (session, session_id),
+        _ => return Ok(None),
     };
-    let session_id: String = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(SESSION_LENGTH)
-        .map(char::from)
-        .collect();
-    let expire_at = chrono::Utc::now() + chrono::Duration::seconds(SESSION_LIFETIME_SECONDS);
-    let session_cookie_header = format!(
-        "session-id={}; Secure; HttpOnly; Expires={}",
-        session_id,
-        expire_at.format("%a, %d %b %Y %H:%M:%S GMT")
-    );
-    ctx.append_http_header("Set-Cookie", session_cookie_header);
-    let key = format!("session/{}", session_id);
-    cmd("SET")
-        .arg(&[key.as_bytes(), bincode::serialize(&session)?.as_ref()])
+    let mut redis_conn = ctx
+        .data_opt::<RedisPool>()
+        .ok_or(Error::RedisPoolNotFoundInContext)?
+        .get()
+        .await?;
+    cmd("DEL")
+        .arg(&[session_key(session_id)])
         .execute_async(&mut redis_conn)
         .await?;
-    cmd("EXPIRE")
-        .arg(&[
-            key.as_bytes(),
-            SESSION_LIFETIME_SECONDS.to_string().as_bytes(),
-        ])
+    cmd("SREM")
+        .arg(&[&user_sessions_key(session.user_id), session_id])
         .execute_async(&mut redis_conn)
         .await?;
-    Ok(())
+    clear_session_cookie(ctx);
+    Ok(Some(session.clone()))
+}
+
+/// Remove every session of the user, on all devices, and revoke their refresh tokens.
+/// Returns how many sessions were removed.
+pub async fn end_user_sessions(redis_conn: &mut RedisConn, user_id: Uuid) -> Result<usize, Error> {
+    let user_sessions_key = user_sessions_key(user_id);
+    let session_ids: Vec<String> = cmd("SMEMBERS")
+        .arg(&[&user_sessions_key])
+        .query_async(redis_conn)
+        .await?;
+    let keys: Vec<String> = session_ids.iter().map(|id| session_key(id)).collect();
+    let removed: usize = if keys.is_empty() {
+        0
+    } else {
+        cmd("DEL").arg(&keys).query_async(redis_conn).await?
+    };
+    cmd("DEL")
+        .arg(&[user_sessions_key])
+        .execute_async(redis_conn)
+        .await?;
+    revoke_refresh_tokens(redis_conn, user_id).await?;
+    Ok(removed)
 }
 
-/*pub async fn remove_session(
+/// `end_user_sessions`, also dropping the cookie when the user is the caller.
+pub async fn remove_user_sessions(
     ctx: &async_graphql::Context<'_>,
-) -> Result<Option<Session>, Error> {
-}*/
+    user_id: Uuid,
+) -> Result<usize, Error> {
+    let mut redis_conn = ctx
+        .data_opt::<RedisPool>()
+        .ok_or(Error::RedisPoolNotFoundInContext)?
+        .get()
+        .await?;
+    let removed = end_user_sessions(&mut redis_conn, user_id).await?;
+    if ctx.data_opt::<Session>().map(|s| s.user_id) == Some(user_id) {
+        clear_session_cookie(ctx);
+    }
+    Ok(removed)
+}
+
+/// Sessions of the user, most recently used first.
+pub async fn list_sessions(
+    ctx: &async_graphql::Context<'_>,
+    user_id: Uuid,
+) -> Result<Vec<SessionInfo>, Error> {
+    let mut redis_conn = ctx
+        .data_opt::<RedisPool>()
+        .ok_or(Error::RedisPoolNotFoundInContext)?
+        .get()
+        .await?;
+    let current = ctx.data_opt::<SessionId>().map(|SessionId(id)| id.as_str());
+    let mut sessions: Vec<SessionInfo> = load_user_sessions(&mut redis_conn, user_id)
+        .await?
+        .into_iter()
+        .map(|(session_id, info)| SessionInfo {
+            current: current == Some(session_id.as_str()),
+            ..info
+        })
+        .collect();
+    sessions.sort_by(|a, b| b.last_seen_at.cmp(&a.last_seen_at));
+    Ok(sessions)
+}
+
+/// Remove one session of the user by its handle. Returns false when there is no such session.
+pub async fn revoke_session(
+    ctx: &async_graphql::Context<'_>,
+    user_id: Uuid,
+    handle: Uuid,
+) -> Result<bool, Error> {
+    let mut redis_conn = ctx
+        .data_opt::<RedisPool>()
+        .ok_or(Error::RedisPoolNotFoundInContext)?
+        .get()
+        .await?;
+    let session_id = match load_user_sessions(&mut redis_conn, user_id)
+        .await?
+        .into_iter()
+        .find(|(_, info)| info.handle == handle)
+    {
+        Some((session_id, _)) => session_id,
+        None => return Ok(false),
+    };
+    cmd("DEL")
+        .arg(&[session_key(&session_id)])
+        .execute_async(&mut redis_conn)
+        .await?;
+    cmd("SREM")
+        .arg(&[&user_sessions_key(user_id), &session_id])
+        .execute_async(&mut redis_conn)
+        .await?;
+    if ctx.data_opt::<SessionId>() == Some(&SessionId(session_id)) {
+        clear_session_cookie(ctx);
+    }
+    Ok(true)
+}
 
+/// Look up the session of the request and slide its expiry forward.
+///
+/// A cookie that does not lead to a valid session, because it expired, was revoked or was
+/// tampered with, is treated as no session at all.
 pub async fn extract_session(
     redis_conn: &mut RedisConn,
     req: &HttpRequest,
 ) -> Result<Option<Session>, Error> {
-    if let Some(session_id) = req.cookie("session-id") {
-        let bytes: Vec<u8> = cmd("GET")
-            .arg(&[&format!("session/{}", session_id.value())])
-            .query_async(redis_conn)
-            .await?;
-        let sess: Session = bincode::deserialize(&bytes)?;
-        Ok(Some(sess))
-    } else {
-        Ok(None)
+    let policy = SessionPolicy::of_request(req);
+    let session_id = match req.cookie("session-id") {
+        Some(cookie) if is_well_formed_session_id(cookie.value()) => cookie.value().to_string(),
+        _ => return Ok(None),
+    };
+    let key = session_key(&session_id);
+    let bytes: Option<Vec<u8>> = cmd("GET").arg(&[&key]).query_async(redis_conn).await?;
+    let mut info: SessionInfo = match bytes.map(|bytes| bincode::deserialize(&bytes)) {
+        Some(Ok(info)) => info,
+        Some(Err(_)) => {
+            cmd("DEL").arg(&[&key]).execute_async(redis_conn).await?;
+            return Ok(None);
+        }
+        None => return Ok(None),
+    };
+    info.last_seen_at = Utc::now();
+    if let Some(ip) = RequestInfo::from_http_request(req).ip {
+        info.ip = Some(ip);
+    }
+    if !renew_session(redis_conn, &session_id, &info, policy.lifetime_seconds).await? {
+        return Ok(None);
+    }
+    Ok(Some(info.session))
+}
+
+/// `Authorization: Bearer` token of the request, if any.
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Session found for a request, kept in its extensions.
+struct RequestSession(Option<Session>);
+
+/// Session of the request, from its bearer token or else its cookie. Looked up once per
+/// request, so middleware and the handler share the result.
+pub async fn request_session(
+    redis_conn: &mut RedisConn,
+    req: &HttpRequest,
+) -> Result<Option<Session>, Error> {
+    let cached = req.extensions().get::<RequestSession>().map(|cached| cached.0.clone());
+    if let Some(session) = cached {
+        return Ok(session);
     }
+    let session = match bearer_token(req) {
+        Some(token) => req
+            .app_data::<web::Data<JwtKeys>>()
+            .and_then(|keys| keys.verify_access_token(token).ok()),
+        None => extract_session(redis_conn, req).await?,
+    };
+    req.extensions_mut().insert(RequestSession(session.clone()));
+    Ok(session)
 }
@@ -0,0 +1,366 @@
+use crate::error::Error;
+use crate::model::Tuning;
+use crate::rating::{Elo, Glicko2, RatingSystemKind, SharedRatingSystem, TrueSkill};
+use crate::oidc::OidcProvider;
+use crate::session::{SessionPolicy, SESSION_ID_LENGTHS};
+use crate::token::JwtAlgorithm;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, ServerConfig};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Prefix of the environment variables, as in `SERVER_LISTEN_ADDR`.
+const ENV_PREFIX: &str = "SERVER";
+
+/// Unprefixed variables that platforms commonly set, and the key each one stands for. The
+/// prefixed variable wins when both are set.
+const ENV_ALIASES: &[(&str, &str)] = &[("DATABASE_URL", "database_url"), ("REDIS_URL", "redis_url")];
+
+/// Everything the server can be configured with. Each key comes from, in increasing
+/// priority, the defaults below, the TOML file given with `--config`, and the environment
+/// variable of the same name in upper case with the `SERVER_` prefix, as in
+/// `SERVER_LISTEN_ADDR`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub listen_addr: String,
+    /// PEM certificate chain and private key. Plain HTTP when unset.
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// Addresses of the proxies in front of the server, whose `X-Forwarded-For` is believed.
+    /// Only settable in the file.
+    pub trusted_proxies: Vec<IpAddr>,
+
+    pub database_url: String,
+    pub db_max_connections: u32,
+    pub db_min_connections: u32,
+    pub redis_url: String,
+    pub redis_pool_size: usize,
+    /// Apply pending migrations before serving.
+    pub run_migrations: bool,
+
+    /// Sessions expire after this long without a request.
+    pub session_lifetime_seconds: i64,
+    pub session_id_length: usize,
+    /// Largest page of a connection. The schema itself caps `first` at 100.
+    pub max_page_size: i32,
+    /// bcrypt cost of password hashes. Each step doubles the time a login takes.
+    pub password_hash_cost: u32,
+
+    pub rating_system: RatingSystemKind,
+    pub elo_k_factor: f64,
+    pub glicko2_tau: f64,
+    pub glicko2_initial_deviation: f64,
+    pub glicko2_initial_volatility: f64,
+    pub trueskill_initial_sigma: f64,
+    pub trueskill_beta: f64,
+    pub trueskill_tau: f64,
+    pub trueskill_draw_probability: f64,
+
+    pub jwt_algorithm: JwtAlgorithm,
+    /// HS256 secret.
+    pub jwt_secret: Option<String>,
+    /// Paths of the RS256 key pair in PEM.
+    pub jwt_private_key: Option<String>,
+    pub jwt_public_key: Option<String>,
+    /// Identity providers, as `[[oidc_providers]]` tables. Only settable in the file.
+    pub oidc_providers: Vec<OidcProvider>,
+    pub smtp_host: Option<String>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    /// Sender of account mail, as in `Battle <noreply@example.com>`.
+    pub mail_from: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let session = SessionPolicy::default();
+        let tuning = Tuning::default();
+        let glicko2 = Glicko2::default();
+        let trueskill = TrueSkill::default();
+        Config {
+            listen_addr: "0.0.0.0:8000".to_string(),
+            tls_cert: None,
+            tls_key: None,
+            trusted_proxies: Vec::new(),
+            database_url: String::new(),
+            db_max_connections: 10,
+            db_min_connections: 0,
+            redis_url: "redis://127.0.0.1:6379".to_string(),
+            redis_pool_size: 16,
+            run_migrations: false,
+            session_lifetime_seconds: session.lifetime_seconds,
+            session_id_length: session.id_length,
+            max_page_size: tuning.max_page_size,
+            password_hash_cost: tuning.password_hash_cost,
+            rating_system: RatingSystemKind::default(),
+            elo_k_factor: Elo::default().k_factor,
+            glicko2_tau: glicko2.tau,
+            glicko2_initial_deviation: glicko2.initial_deviation,
+            glicko2_initial_volatility: glicko2.initial_volatility,
+            trueskill_initial_sigma: trueskill.initial_sigma,
+            trueskill_beta: trueskill.beta,
+            trueskill_tau: trueskill.tau,
+            trueskill_draw_probability: trueskill.draw_probability,
+            jwt_algorithm: JwtAlgorithm::default(),
+            jwt_secret: None,
+            jwt_private_key: None,
+            jwt_public_key: None,
+            oidc_providers: Vec::new(),
+            smtp_host: None,
+            smtp_username: None,
+            smtp_password: None,
+            mail_from: None,
+        }
+    }
+}
+
+/// Layer the file, when given, and the environment over the defaults, and check the result.
+pub fn load(path: Option<&Path>) -> Result<Config, Error> {
+    load_from(path, std::env::vars())
+}
+
+/// `load` with the given environment variables instead of those of the process.
+fn load_from(
+    path: Option<&Path>,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<Config, Error> {
+    let vars: HashMap<String, String> = vars.into_iter().collect();
+    let mut layers = config::Config::default();
+    if let Some(path) = path {
+        layers
+            .merge(config::File::from(path).format(config::FileFormat::Toml))
+            .map_err(|err| Error::Config(err.to_string()))?;
+    }
+    let prefix = format!("{}_", ENV_PREFIX);
+    for (variable, key) in ENV_ALIASES {
+        let prefixed = format!("{}{}", prefix, key.to_uppercase());
+        if let (Some(value), None) = (vars.get(*variable), vars.get(&prefixed)) {
+            layers.set(key, value.as_str()).map_err(|err| Error::Config(err.to_string()))?;
+        }
+    }
+    for (variable, value) in vars.iter() {
+        // keys are snake case, so anything else with the prefix is not meant for us
+        let key = variable
+            .strip_prefix(&prefix)
+            .filter(|key| !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+        if let Some(key) = key {
+            layers
+                .set(&key.to_lowercase(), value.as_str())
+                .map_err(|err| Error::Config(err.to_string()))?;
+        }
+    }
+    let config: Config = layers.try_into().map_err(|err| Error::Config(err.to_string()))?;
+    config.validate()?;
+    Ok(config)
+}
+
+impl Config {
+    /// Every problem at once, so a broken deploy needs only one round of fixes.
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: &str| {
+            if !ok {
+                problems.push(problem.to_string());
+            }
+        };
+        check(self.listen_addr.parse::<SocketAddr>().is_ok(), "listen_addr must be an address and port, as in 0.0.0.0:8000");
+        check(self.tls_cert.is_some() == self.tls_key.is_some(), "tls_cert and tls_key must be set together");
+        check(!self.database_url.is_empty(), "database_url is required");
+        check(self.db_max_connections >= 1, "db_max_connections must be at least 1");
+        check(self.db_min_connections <= self.db_max_connections, "db_min_connections must not exceed db_max_connections");
+        check(self.redis_pool_size >= 1, "redis_pool_size must be at least 1");
+        check(self.session_lifetime_seconds >= 60, "session_lifetime_seconds must be at least 60");
+        check(SESSION_ID_LENGTHS.contains(&self.session_id_length), "session_id_length must be between 20 and 128");
+        check((1..=100).contains(&self.max_page_size), "max_page_size must be between 1 and 100");
+        check((4..=31).contains(&self.password_hash_cost), "password_hash_cost must be between 4 and 31");
+        check(self.elo_k_factor > 0.0, "elo_k_factor must be positive");
+        check(
+            self.glicko2_tau > 0.0 && self.glicko2_initial_deviation > 0.0 && self.glicko2_initial_volatility > 0.0,
+            "glicko2 parameters must be positive",
+        );
+        check(
+            self.trueskill_initial_sigma > 0.0 && self.trueskill_beta > 0.0 && self.trueskill_tau >= 0.0,
+            "trueskill_initial_sigma and trueskill_beta must be positive, trueskill_tau must not be negative",
+        );
+        check(
+            (0.0..1.0).contains(&self.trueskill_draw_probability),
+            "trueskill_draw_probability must be at least 0 and less than 1",
+        );
+        check(
+            self.jwt_algorithm != JwtAlgorithm::Hs256 || self.jwt_secret.is_some(),
+            "jwt_secret is required for hs256",
+        );
+        check(
+            self.jwt_algorithm != JwtAlgorithm::Rs256 || (self.jwt_private_key.is_some() && self.jwt_public_key.is_some()),
+            "jwt_private_key and jwt_public_key are required for rs256",
+        );
+        for (i, provider) in self.oidc_providers.iter().enumerate() {
+            check(
+                !provider.name.is_empty() && !self.oidc_providers[..i].iter().any(|other| other.name == provider.name),
+                "oidc_providers need distinct, non-empty names",
+            );
+            check(
+                [&provider.issuer, &provider.redirect_uri].iter().all(|url| url.starts_with("https://") || url.starts_with("http://")),
+                "issuer and redirect_uri of oidc_providers must be urls",
+            );
+            check(!provider.client_id.is_empty(), "oidc_providers need a client_id");
+        }
+        let smtp = [&self.smtp_host, &self.smtp_username, &self.smtp_password, &self.mail_from];
+        check(
+            smtp.iter().all(|value| value.is_some()) || smtp.iter().all(|value| value.is_none()),
+            "smtp_host, smtp_username, smtp_password and mail_from must be set together",
+        );
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Config(problems.join("; ")))
+        }
+    }
+    pub fn rating_system(&self) -> SharedRatingSystem {
+        match self.rating_system {
+            RatingSystemKind::Elo => Arc::new(Elo {
+                k_factor: self.elo_k_factor,
+            }),
+            RatingSystemKind::Glicko2 => Arc::new(Glicko2 {
+                tau: self.glicko2_tau,
+                initial_deviation: self.glicko2_initial_deviation,
+                initial_volatility: self.glicko2_initial_volatility,
+            }),
+            RatingSystemKind::TrueSkill => Arc::new(TrueSkill {
+                initial_sigma: self.trueskill_initial_sigma,
+                beta: self.trueskill_beta,
+                tau: self.trueskill_tau,
+                draw_probability: self.trueskill_draw_probability,
+            }),
+        }
+    }
+    pub fn session_policy(&self) -> SessionPolicy {
+        SessionPolicy {
+            lifetime_seconds: self.session_lifetime_seconds,
+            id_length: self.session_id_length,
+        }
+    }
+    pub fn tuning(&self) -> Tuning {
+        Tuning {
+            max_page_size: self.max_page_size,
+            password_hash_cost: self.password_hash_cost,
+        }
+    }
+    /// Server side TLS from `tls_cert` and `tls_key`. None when serving plain HTTP.
+    pub fn tls(&self) -> Result<Option<ServerConfig>, Error> {
+        let (cert_path, key_path) = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+            _ => return Ok(None),
+        };
+        let invalid = |what: &str| Error::Config(format!("{} is not valid PEM", what));
+        let chain = certs(&mut BufReader::new(File::open(cert_path)?)).map_err(|_| invalid("tls_cert"))?;
+        let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?)).map_err(|_| invalid("tls_key"))?;
+        if keys.is_empty() {
+            keys = rsa_private_keys(&mut BufReader::new(File::open(key_path)?)).map_err(|_| invalid("tls_key"))?;
+        }
+        let key = keys.into_iter().next().ok_or_else(|| invalid("tls_key"))?;
+        let mut tls = ServerConfig::new(NoClientAuth::new());
+        tls.set_single_cert(chain, key)
+            .map_err(|err| Error::Config(format!("tls_cert and tls_key do not match: {}", err)))?;
+        Ok(Some(tls))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rating::{Outcome, Rating, RatingSystem};
+
+    fn valid() -> Config {
+        Config {
+            database_url: "postgres://localhost/battle".to_string(),
+            jwt_secret: Some("secret".to_string()),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(valid().validate().is_ok());
+        assert!(Config::default().validate().is_err());
+
+        let config = Config {
+            listen_addr: "localhost".to_string(),
+            max_page_size: 0,
+            smtp_host: Some("smtp.example.com".to_string()),
+            ..valid()
+        };
+        let message = config.validate().unwrap_err().to_string();
+        assert!(message.contains("listen_addr"), "{}", message);
+        assert!(message.contains("max_page_size"), "{}", message);
+        assert!(message.contains("smtp_host"), "{}", message);
+        assert!(!message.contains("database_url"), "{}", message);
+
+        let config = Config {
+            jwt_secret: None,
+            ..valid()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join(format!("server-test-{}.toml", uuid::Uuid::new_v4()));
+        let provider = r#"
+[[oidc_providers]]
+name = "mock"
+issuer = "https://issuer.example"
+client_id = "client"
+client_secret = "secret"
+redirect_uri = "https://battle.example/auth/oidc/mock/callback"
+"#;
+        std::fs::write(&path, format!("jwt_secret = \"secret\"\n{}", provider)).unwrap();
+        let vars = |pairs: &[(&str, &str)]| {
+            pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect::<Vec<_>>()
+        };
+        let config = load_from(Some(&path), vars(&[("DATABASE_URL", "postgres://alias/battle")])).unwrap();
+        assert_eq!(config.database_url, "postgres://alias/battle");
+        assert_eq!(config.oidc_providers.len(), 1);
+        assert_eq!(config.oidc_providers[0].name, "mock");
+
+        // the prefixed variable wins over the alias, and over the file
+        let config = load_from(
+            Some(&path),
+            vars(&[
+                ("DATABASE_URL", "postgres://alias/battle"),
+                ("SERVER_DATABASE_URL", "postgres://prefixed/battle"),
+                ("SERVER_JWT_SECRET", "other"),
+                ("LISTEN_ADDR", "0.0.0.0:1"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.database_url, "postgres://prefixed/battle");
+        assert_eq!(config.jwt_secret.as_deref(), Some("other"));
+        assert_eq!(config.listen_addr, Config::default().listen_addr);
+
+        std::fs::write(&path, format!("jwt_secret = \"secret\"\n{}{}", provider, provider)).unwrap();
+        let message = load_from(Some(&path), vars(&[("DATABASE_URL", "postgres://alias/battle")]))
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("oidc_providers"), "{}", message);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rating_system() {
+        let config = Config {
+            rating_system: RatingSystemKind::Elo,
+            elo_k_factor: 16.0,
+            ..valid()
+        };
+        let (a, _) = config.rating_system().rate(&Rating::new(1000.0), &Rating::new(1000.0), Outcome::Win);
+        assert_eq!(a.rating, 1008.0);
+    }
+}
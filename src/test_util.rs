@@ -1,5 +1,13 @@
 use crate::error::Error;
+use crate::mailer::MemoryMailer;
 use crate::model::*;
+use crate::oidc::OidcProvider;
+use crate::rating::Elo;
+use crate::session::SessionPolicy;
+use crate::token::JwtKeys;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use std::sync::Arc;
 use testcontainers::{
     clients::Cli,
     images::{postgres::Postgres, redis::Redis},
@@ -27,6 +35,9 @@ pub struct TestNodes<'a> {
     pub pgpool: DbPool,
     pub redispool: RedisPool,
     pub schema: Schema,
+    pub jwt_keys: JwtKeys,
+    /// Mail the schema sent.
+    pub mailer: Arc<MemoryMailer>,
 }
 impl<'a> TestNodes<'a> {
     pub async fn new(docker: &'a Cli) -> TestNodes<'a> {
@@ -38,20 +49,132 @@ impl<'a> TestNodes<'a> {
         let redisurl = format!("redis://localhost:{}", redis_host_port);
 
         let dbpool = DbPoolOptions::new().connect(&dburl).await.unwrap();
-        let redispool = create_redispool(&redisurl).unwrap();
-
-        let mut migrator = sqlx::migrate!();
-        migrator
-            .migrations
-            .to_mut()
-            .retain(|migration| !migration.description.ends_with(".down"));
-        migrator.run(&dbpool).await.unwrap();
+        let redispool = create_redispool(&redisurl, 16).unwrap();
+        let redis_client = create_redis_client(&redisurl).unwrap();
+
+        crate::migration::run(&dbpool).await.unwrap();
+        let jwt_keys = JwtKeys::hs256(b"test secret");
+        let mailer = Arc::new(MemoryMailer::default());
         TestNodes {
             pg,
             redis,
             pgpool: dbpool.clone(),
             redispool: redispool.clone(),
-            schema: build_schema(dbpool, redispool).await.unwrap(),
+            schema: build_schema(
+                dbpool,
+                redispool,
+                redis_client,
+                Arc::new(Elo::default()),
+                jwt_keys.clone(),
+                mailer.clone(),
+                SessionPolicy::default(),
+                Tuning::default(),
+            )
+            .await
+            .unwrap(),
+            jwt_keys,
+            mailer,
+        }
+    }
+}
+
+/// Signing key of the mock issuer, and its public part as published in the JWKS.
+const MOCK_ISSUER_KEY: &str = include_str!("../tests/fixtures/oidc_rsa_key.pem");
+const MOCK_ISSUER_KEY_N: &str = "6qJVC7_ITSzlNUL78MHG23HzDIPUyUEwStuxzIzhWR3HE0HjMLB82RqqalSWvNghuq9twUZyGHIP0ExCcgEn3vByHjBIKM6QzAraLNStkN2QQA_R4cz1oA6hYxzoAVBXfRv-Ur5BnCkasoR0-7TQIg2MvqLt0ERpLBiXvtk84NrDXbMBZaIjSu8QVbbpxmzwkIOm7Uz0Kcn955uDlgQOZ6x4vFkP_9yFdHb6o1jUOyTGxYqSw_nT6Hri2KIebHb63ZKm1WC_jgBSg8cQU_k7YXZZI6c_a2_zuVt7sbGoPVt16431EZG2Y2CB2irs0v2qtnEUVt6rWkij6G5pNPLWYw";
+const MOCK_ISSUER_KEY_E: &str = "AQAB";
+
+/// OpenID Connect issuer that signs in whoever the code names. Codes look like
+/// `{subject}.{nonce}`, and the email of a subject is `{subject}@example.com`.
+pub struct MockIssuer {
+    pub issuer: String,
+}
+
+impl MockIssuer {
+    pub fn start() -> MockIssuer {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let data = issuer.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .data(data.clone())
+                .route("/.well-known/openid-configuration", web::get().to(mock_discovery))
+                .route("/jwks", web::get().to(mock_jwks))
+                .route("/token", web::post().to(mock_token))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_rt::spawn(server);
+        MockIssuer { issuer }
+    }
+    pub fn provider(&self, name: &str) -> OidcProvider {
+        OidcProvider {
+            name: name.to_string(),
+            issuer: self.issuer.clone(),
+            client_id: "battle-server".to_string(),
+            client_secret: "secret".to_string(),
+            redirect_uri: format!("http://localhost/auth/oidc/{}/callback", name),
         }
     }
 }
+
+async fn mock_discovery(issuer: web::Data<String>) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "issuer": issuer.as_str(),
+        "authorization_endpoint": format!("{}/authorize", issuer.as_str()),
+        "token_endpoint": format!("{}/token", issuer.as_str()),
+        "jwks_uri": format!("{}/jwks", issuer.as_str()),
+    }))
+}
+
+async fn mock_jwks() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "keys": [{
+            "kty": "RSA",
+            "kid": "test",
+            "use": "sig",
+            "alg": "RS256",
+            "n": MOCK_ISSUER_KEY_N,
+            "e": MOCK_ISSUER_KEY_E,
+        }],
+    }))
+}
+
+#[derive(serde::Deserialize)]
+struct MockTokenForm {
+    code: String,
+    client_id: String,
+}
+
+async fn mock_token(issuer: web::Data<String>, form: web::Form<MockTokenForm>) -> HttpResponse {
+    let (subject, nonce) = match form.code.split_once('.') {
+        Some(parts) => parts,
+        None => return HttpResponse::BadRequest().json(serde_json::json!({ "error": "invalid_grant" })),
+    };
+    let now = chrono::Utc::now().timestamp();
+    let claims = serde_json::json!({
+        "iss": issuer.as_str(),
+        "sub": subject,
+        "aud": form.client_id,
+        "iat": now,
+        "exp": now + 300,
+        "nonce": nonce,
+        "email": format!("{}@example.com", subject),
+        "email_verified": !subject.starts_with("unverified"),
+        "name": subject,
+    });
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some("test".to_string());
+    let id_token = encode(
+        &header,
+        &claims,
+        &EncodingKey::from_rsa_pem(MOCK_ISSUER_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    HttpResponse::Ok().json(serde_json::json!({
+        "access_token": "unused",
+        "token_type": "Bearer",
+        "id_token": id_token,
+    }))
+}
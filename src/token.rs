@@ -0,0 +1,217 @@
+use crate::error::Error;
+use crate::model::{DbPool, User, UserKind};
+use crate::oauth::Scope;
+use crate::permission::{load_permissions, Permission};
+use crate::session::Session;
+use async_graphql::SimpleObject;
+use deadpool_redis::{cmd, ConnectionWrapper as RedisConn};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+const ACCESS_TOKEN_LIFETIME_SECONDS: i64 = 60 * 15;
+const REFRESH_TOKEN_LIFETIME_SECONDS: i64 = 60 * 60 * 24 * 30;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JwtAlgorithm {
+    #[default]
+    Hs256,
+    Rs256,
+}
+
+/// Keys tokens are signed and verified with.
+#[derive(Clone)]
+pub struct JwtKeys {
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey<'static>,
+}
+
+impl JwtKeys {
+    pub fn hs256(secret: &[u8]) -> Self {
+        JwtKeys {
+            algorithm: Algorithm::HS256,
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret).into_static(),
+        }
+    }
+    pub fn rs256(private_pem: &[u8], public_pem: &[u8]) -> Result<Self, Error> {
+        Ok(JwtKeys {
+            algorithm: Algorithm::RS256,
+            encoding: EncodingKey::from_rsa_pem(private_pem)?,
+            decoding: DecodingKey::from_rsa_pem(public_pem)?.into_static(),
+        })
+    }
+    fn sign(&self, claims: &Claims) -> Result<String, Error> {
+        Ok(encode(&Header::new(self.algorithm), claims, &self.encoding)?)
+    }
+    fn verify(&self, token: &str, token_type: TokenType) -> Result<Claims, Error> {
+        let claims = decode::<Claims>(token, &self.decoding, &Validation::new(self.algorithm))?.claims;
+        if claims.typ != token_type {
+            return Err(Error::NotAuthorized);
+        }
+        Ok(claims)
+    }
+    /// Session an access token stands for.
+    pub fn verify_access_token(&self, token: &str) -> Result<Session, Error> {
+        let claims = self.verify(token, TokenType::Access)?;
+        Ok(Session {
+            user_id: claims.sub,
+            user_kind: claims.kind,
+            scopes: claims.grant.map(|grant| grant.scopes),
+            permissions: claims.perms,
+        })
+    }
+}
+
+/// What a player allowed a third-party app to do on their behalf.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Grant {
+    pub client_id: Uuid,
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum TokenType {
+    Access,
+    Refresh,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+struct Claims {
+    sub: Uuid,
+    kind: UserKind,
+    typ: TokenType,
+    jti: Uuid,
+    iat: i64,
+    exp: i64,
+    /// None for tokens the player got by logging in themselves.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    grant: Option<Grant>,
+    /// Permissions of the user when the token was issued.
+    #[serde(default)]
+    perms: Vec<Permission>,
+}
+
+#[derive(Clone, Debug, PartialEq, SimpleObject)]
+pub struct TokenPair {
+    /// Sent as `Authorization: Bearer <accessToken>`.
+    pub access_token: String,
+    /// Exchanged for a new pair with `refreshToken`. Each one can be used only once.
+    pub refresh_token: String,
+    /// Seconds until the access token expires.
+    pub expires_in: i32,
+}
+
+/// Redis key of a refresh token that has not been used yet. Holds the user id.
+fn refresh_token_key(jti: Uuid) -> String {
+    format!("refresh-token/{}", jti)
+}
+
+/// Set of the unused refresh token ids of a user, used to revoke them all at once.
+fn user_refresh_tokens_key(user_id: Uuid) -> String {
+    format!("user-refresh-tokens/{}", user_id)
+}
+
+fn new_claims(user: &User, perms: &[Permission], grant: Option<&Grant>, typ: TokenType, lifetime: i64) -> Claims {
+    let now = chrono::Utc::now().timestamp();
+    Claims {
+        sub: user.id,
+        kind: user.kind,
+        typ,
+        jti: Uuid::new_v4(),
+        iat: now,
+        exp: now + lifetime,
+        grant: grant.cloned(),
+        perms: perms.to_vec(),
+    }
+}
+
+pub async fn issue_tokens(
+    dbpool: &DbPool,
+    redis_conn: &mut RedisConn,
+    keys: &JwtKeys,
+    user: &User,
+    grant: Option<&Grant>,
+) -> Result<TokenPair, Error> {
+    let perms = load_permissions(dbpool, user.id).await?;
+    let access = new_claims(user, &perms, grant, TokenType::Access, ACCESS_TOKEN_LIFETIME_SECONDS);
+    let refresh = new_claims(user, &perms, grant, TokenType::Refresh, REFRESH_TOKEN_LIFETIME_SECONDS);
+    cmd("SET")
+        .arg(refresh_token_key(refresh.jti))
+        .arg(user.id.to_string())
+        .arg("EX")
+        .arg(REFRESH_TOKEN_LIFETIME_SECONDS)
+        .execute_async(redis_conn)
+        .await?;
+    let user_refresh_tokens_key = user_refresh_tokens_key(user.id);
+    cmd("SADD")
+        .arg(&[user_refresh_tokens_key.clone(), refresh.jti.to_string()])
+        .execute_async(redis_conn)
+        .await?;
+    cmd("EXPIRE")
+        .arg(&[user_refresh_tokens_key, REFRESH_TOKEN_LIFETIME_SECONDS.to_string()])
+        .execute_async(redis_conn)
+        .await?;
+    Ok(TokenPair {
+        access_token: keys.sign(&access)?,
+        refresh_token: keys.sign(&refresh)?,
+        expires_in: ACCESS_TOKEN_LIFETIME_SECONDS as i32,
+    })
+}
+
+/// An access token without a refresh token, with how many seconds it lasts. For clients
+/// that can ask for a new one on their own.
+pub async fn issue_access_token(
+    dbpool: &DbPool,
+    keys: &JwtKeys,
+    user: &User,
+    grant: &Grant,
+) -> Result<(String, i32), Error> {
+    let perms = load_permissions(dbpool, user.id).await?;
+    let access = new_claims(user, &perms, Some(grant), TokenType::Access, ACCESS_TOKEN_LIFETIME_SECONDS);
+    Ok((keys.sign(&access)?, ACCESS_TOKEN_LIFETIME_SECONDS as i32))
+}
+
+/// Use up a refresh token. Returns the id of the user it was issued to, and the grant it
+/// carries so the next pair keeps the same restrictions.
+pub async fn consume_refresh_token(
+    redis_conn: &mut RedisConn,
+    keys: &JwtKeys,
+    token: &str,
+) -> Result<(Uuid, Option<Grant>), Error> {
+    let claims = keys.verify(token, TokenType::Refresh)?;
+    // DEL reports whether the token was still there, so only one caller can use it
+    let removed: i64 = cmd("DEL")
+        .arg(&[refresh_token_key(claims.jti)])
+        .query_async(redis_conn)
+        .await?;
+    if removed == 0 {
+        return Err(Error::NotAuthorized);
+    }
+    cmd("SREM")
+        .arg(&[user_refresh_tokens_key(claims.sub), claims.jti.to_string()])
+        .execute_async(redis_conn)
+        .await?;
+    Ok((claims.sub, claims.grant))
+}
+
+/// Make every unused refresh token of the user worthless. Access tokens already out run
+/// until they expire.
+pub async fn revoke_refresh_tokens(redis_conn: &mut RedisConn, user_id: Uuid) -> Result<(), Error> {
+    let user_refresh_tokens_key = user_refresh_tokens_key(user_id);
+    let jtis: Vec<String> = cmd("SMEMBERS")
+        .arg(&[&user_refresh_tokens_key])
+        .query_async(redis_conn)
+        .await?;
+    let mut keys: Vec<String> = jtis
+        .iter()
+        .filter_map(|jti| Uuid::parse_str(jti).ok())
+        .map(refresh_token_key)
+        .collect();
+    keys.push(user_refresh_tokens_key);
+    cmd("DEL").arg(&keys).execute_async(redis_conn).await?;
+    Ok(())
+}
@@ -1,8 +1,51 @@
-use bcrypt::{hash, verify, BcryptError, DEFAULT_COST};
+use crate::error::Error;
+use bcrypt::{hash, verify, BcryptError};
+use deadpool_redis::{cmd, ConnectionWrapper as RedisConn};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{de::DeserializeOwned, Serialize};
 
-pub fn hash_password(plain: String) -> Result<String, BcryptError> {
-    hash(plain, DEFAULT_COST)
+pub fn hash_password(plain: String, cost: u32) -> Result<String, BcryptError> {
+    hash(plain, cost)
 }
 pub fn verify_password(plain: &str, hash: &str) -> Result<bool, BcryptError> {
     verify(plain, hash)
 }
+
+/// Random alphanumeric string, for secrets handed out to clients.
+pub fn random_string(length: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+/// Store a short-lived value, such as a one-time code, that expires on its own.
+pub async fn put_temporary<T: Serialize>(
+    redis_conn: &mut RedisConn,
+    key: &str,
+    value: &T,
+    lifetime_seconds: i64,
+) -> Result<(), Error> {
+    cmd("SET")
+        .arg(key)
+        .arg(bincode::serialize(value)?)
+        .arg("EX")
+        .arg(lifetime_seconds)
+        .execute_async(redis_conn)
+        .await?;
+    Ok(())
+}
+
+/// Read and remove a value stored with `put_temporary`, so it can be used only once.
+pub async fn take_temporary<T: DeserializeOwned>(
+    redis_conn: &mut RedisConn,
+    key: &str,
+) -> Result<Option<T>, Error> {
+    let bytes: Option<Vec<u8>> = cmd("GET").arg(&[key]).query_async(redis_conn).await?;
+    let removed: i64 = cmd("DEL").arg(&[key]).query_async(redis_conn).await?;
+    match bytes {
+        Some(bytes) if removed == 1 => Ok(Some(bincode::deserialize(&bytes)?)),
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,218 @@
+use crate::error::Error;
+use serde::Serialize;
+
+pub const PASSWORD_MIN_CHARS: usize = 8;
+/// bcrypt ignores everything past 72 bytes, so longer passwords would silently match
+/// anything sharing the prefix.
+pub const PASSWORD_MAX_BYTES: usize = 72;
+pub const NICKNAME_MIN_CHARS: usize = 2;
+pub const NICKNAME_MAX_CHARS: usize = 32;
+pub const REASON_MAX_CHARS: usize = 500;
+const EMAIL_MAX_BYTES: usize = 254;
+const EMAIL_LOCAL_MAX_BYTES: usize = 64;
+const REDIRECT_URI_MAX_BYTES: usize = 2000;
+
+/// Why one argument was rejected. Sent to clients in the `fields` error extension.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FieldError {
+    /// Argument name, as in the schema.
+    pub field: &'static str,
+    pub message: &'static str,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, message: &'static str) -> Self {
+        FieldError { field, message }
+    }
+}
+
+/// Collects the problems with every field, so clients can show them all at once.
+#[derive(Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn check(&mut self, field: &'static str, result: Result<(), &'static str>) -> &mut Self {
+        if let Err(message) = result {
+            self.errors.push(FieldError::new(field, message));
+        }
+        self
+    }
+    pub fn finish(&mut self) -> Result<(), Error> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Validation(std::mem::take(&mut self.errors)))
+        }
+    }
+}
+
+pub fn email(email: &str) -> Result<(), &'static str> {
+    if email.len() > EMAIL_MAX_BYTES {
+        return Err("is too long");
+    }
+    let (local, domain) = match email.rsplit_once('@') {
+        Some(parts) => parts,
+        None => return Err("is not an email address"),
+    };
+    let local_ok = !local.is_empty()
+        && local.len() <= EMAIL_LOCAL_MAX_BYTES
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..")
+        && local
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(c));
+    let domain_ok = domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if local_ok && domain_ok {
+        Ok(())
+    } else {
+        Err("is not an email address")
+    }
+}
+
+pub fn password(password: &str) -> Result<(), &'static str> {
+    if password.chars().count() < PASSWORD_MIN_CHARS {
+        return Err("must be at least 8 characters");
+    }
+    if password.len() > PASSWORD_MAX_BYTES {
+        return Err("must be at most 72 bytes");
+    }
+    let has_letter = password.chars().any(char::is_alphabetic);
+    let has_other = password.chars().any(|c| !c.is_alphabetic());
+    if !has_letter || !has_other {
+        return Err("must mix letters with digits or symbols");
+    }
+    Ok(())
+}
+
+pub fn nickname(nickname: &str) -> Result<(), &'static str> {
+    let length = nickname.chars().count();
+    if length < NICKNAME_MIN_CHARS || length > NICKNAME_MAX_CHARS {
+        return Err("must be 2 to 32 characters");
+    }
+    if !nickname.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.') {
+        return Err("may only contain letters, digits, '_', '-' and '.'");
+    }
+    if !nickname.starts_with(char::is_alphanumeric) {
+        return Err("must start with a letter or digit");
+    }
+    Ok(())
+}
+
+/// Why a staff member changed something by hand, kept for later audits.
+pub fn reason(reason: &str) -> Result<(), &'static str> {
+    if reason.trim().is_empty() {
+        return Err("must not be empty");
+    }
+    if reason.chars().count() > REASON_MAX_CHARS {
+        return Err("must be at most 500 characters");
+    }
+    Ok(())
+}
+
+/// Where an OAuth client sends players back to. Must be absolute and https, except on the
+/// loopback address where native apps listen (RFC 8252), and must not have a fragment.
+pub fn redirect_uri(uri: &str) -> Result<(), &'static str> {
+    if uri.len() > REDIRECT_URI_MAX_BYTES {
+        return Err("is too long");
+    }
+    if uri.chars().any(|c| c.is_whitespace() || c.is_control()) || uri.contains('#') {
+        return Err("must be an absolute url without a fragment");
+    }
+    let rest = match (uri.strip_prefix("https://"), uri.strip_prefix("http://")) {
+        (Some(rest), _) => rest,
+        (None, Some(rest)) if is_loopback(rest) => rest,
+        _ => return Err("must be an https url"),
+    };
+    let authority = rest.split(|c: char| c == '/' || c == '?').next().unwrap_or_default();
+    let host = authority.rsplit_once(':').map_or(authority, |(host, _)| host);
+    if host.is_empty() || authority.contains('@') {
+        return Err("must be an absolute url without a fragment");
+    }
+    Ok(())
+}
+
+fn is_loopback(rest: &str) -> bool {
+    ["localhost", "127.0.0.1", "[::1]"].iter().any(|host| {
+        rest.strip_prefix(host)
+            .map_or(false, |after| after.is_empty() || after.starts_with(|c: char| c == ':' || c == '/' || c == '?'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_email() {
+        for valid in ["a@example.com", "first.last+tag@mail.example.org"].iter() {
+            assert_eq!(email(valid), Ok(()), "{}", valid);
+        }
+        for invalid in ["a", "a@b", "@example.com", "a@@example.com", "a b@example.com", "a..b@example.com", "a@-example.com"].iter() {
+            assert!(email(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_password() {
+        assert_eq!(password("hunter22"), Ok(()));
+        assert!(password("b").is_err());
+        assert!(password("password").is_err());
+        assert!(password("12345678").is_err());
+        // 72 bytes is fine, 73 is not, however few characters they are
+        assert_eq!(password(&format!("a1{}", "x".repeat(70))), Ok(()));
+        assert!(password(&format!("a1{}", "é".repeat(36))).is_err());
+    }
+
+    #[test]
+    fn test_nickname() {
+        assert_eq!(nickname("carol"), Ok(()));
+        assert_eq!(nickname("Zoë_2.0"), Ok(()));
+        assert!(nickname("c").is_err());
+        assert!(nickname(&"c".repeat(33)).is_err());
+        assert!(nickname("-carol").is_err());
+        assert!(nickname("carol smith").is_err());
+        assert!(nickname("<script>").is_err());
+    }
+
+    #[test]
+    fn test_reason() {
+        assert_eq!(reason("rating was inflated by a bug"), Ok(()));
+        assert!(reason(" ").is_err());
+        assert!(reason(&"a".repeat(REASON_MAX_CHARS + 1)).is_err());
+    }
+
+    #[test]
+    fn test_redirect_uri() {
+        for valid in ["https://tool.example/cb", "https://tool.example:8443/cb?app=1", "http://localhost:8080/cb", "http://127.0.0.1/cb"].iter() {
+            assert_eq!(redirect_uri(valid), Ok(()), "{}", valid);
+        }
+        for invalid in ["/cb", "tool.example/cb", "http://tool.example/cb", "http://localhost.evil.example/cb", "https:///cb", "https://tool.example/cb#x", "https://user@tool.example/cb", "javascript:alert(1)"].iter() {
+            assert!(redirect_uri(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_validator_collects_every_field() {
+        let result = Validator::default()
+            .check("email", email("a"))
+            .check("password", password("hunter22"))
+            .check("nickname", nickname("c"))
+            .finish();
+        match result {
+            Err(Error::Validation(errors)) => assert_eq!(
+                errors.iter().map(|error| error.field).collect::<Vec<_>>(),
+                vec!["email", "nickname"]
+            ),
+            _ => panic!("expected validation errors"),
+        }
+    }
+}